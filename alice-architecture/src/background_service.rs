@@ -3,4 +3,10 @@
 pub trait BackgroundService: Send + Sync {
     /// 启动方法
     async fn run(&self);
+
+    /// 停止方法
+    ///
+    /// 通知服务不再接收新的任务，`run` 应在处理完进行中的任务后返回。停止是永久的，停止后再次调用
+    /// `run` 会立即返回，需要重新运行时应创建新的服务实例。
+    async fn stop(&self) {}
 }
@@ -14,4 +14,7 @@ pub struct Payload {
     /// Key: Client id
     /// Value: Resources to access.
     pub resource_access: HashMap<String, Value>,
+    /// 空格分隔的 scope
+    #[serde(default)]
+    pub scope: String,
 }
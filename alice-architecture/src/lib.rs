@@ -10,6 +10,9 @@ pub mod jwt_payload;
 #[cfg(feature = "web")]
 pub mod response;
 
+#[cfg(feature = "role-expression")]
+pub mod role_expression;
+
 #[cfg(feature = "event")]
 pub mod event_system;
 
@@ -1,10 +1,28 @@
 use std::collections::HashMap;
 
-#[async_trait::async_trait]
-pub trait MessageQueueConsumer: Send + Sync {
-    fn add_topic(self, topic: &str) -> Self;
-    fn add_topics(self, topics: Vec<String>) -> Self;
+use crate::background_service::BackgroundService;
+
+/// 消息队列消费者
+///
+/// 通过构建方法注册主题与对应的处理函数，构建完成后作为后台服务运行。
+pub trait MessageQueueConsumer: BackgroundService + Sized {
+    /// 消息处理函数
+    type Handler;
+
+    /// 订阅主题，并由 `handler` 处理该主题的消息
+    fn add_topic(self, topic: &str, handler: Self::Handler) -> Self;
+
+    fn add_topics(self, topics: impl IntoIterator<Item = (String, Self::Handler)>) -> Self {
+        topics.into_iter().fold(self, |consumer, (topic, handler)| {
+            consumer.add_topic(&topic, handler)
+        })
+    }
+
     fn add_option(self, option_key: &str, option_value: &str) -> Self;
-    fn add_options(self, options: HashMap<String, String>) -> Self;
-    async fn run(&self) -> ();
+
+    fn add_options(self, options: HashMap<String, String>) -> Self {
+        options.iter().fold(self, |consumer, (option_key, option_value)| {
+            consumer.add_option(option_key, option_value)
+        })
+    }
 }
@@ -1,8 +1,26 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
 use serde::Serialize;
 
 #[async_trait::async_trait]
 pub trait MessageQueueProducer: Send + Sync {
-    async fn send(&self, content: &str, topic: &str) -> anyhow::Result<()>;
+    async fn send(&self, content: &str, topic: &str) -> anyhow::Result<()> {
+        self.send_bytes(content.as_bytes(), topic).await
+    }
+
+    /// 发送二进制消息
+    async fn send_bytes(&self, content: &[u8], topic: &str) -> anyhow::Result<()> {
+        self.send_with_headers(content, topic, &HashMap::new()).await
+    }
+
+    /// 发送带消息头的二进制消息
+    async fn send_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()>;
 }
 
 #[async_trait::async_trait]
@@ -12,3 +30,46 @@ where
 {
     async fn send_object(&self, content: &T, topic: &str) -> anyhow::Result<()>;
 }
+
+/// 支持事务的生产者，同一事务中发送的消息要么全部提交，要么全部丢弃
+#[async_trait::async_trait]
+pub trait TransactionalMessageQueueProducer: MessageQueueProducer {
+    async fn begin_transaction(&self) -> anyhow::Result<()>;
+
+    async fn commit_transaction(&self) -> anyhow::Result<()>;
+
+    async fn abort_transaction(&self) -> anyhow::Result<()>;
+}
+
+/// 支持延迟投递的生产者，消息在指定的时刻之后才会被消费者收到，投递时保留原来的主题
+#[async_trait::async_trait]
+pub trait DelayedMessageQueueProducer: MessageQueueProducer {
+    /// 在 `delay` 之后投递消息
+    async fn send_delayed(
+        &self,
+        content: &[u8],
+        topic: &str,
+        delay: Duration,
+    ) -> anyhow::Result<()> {
+        self.send_at(content, topic, SystemTime::now() + delay).await
+    }
+
+    /// 在 `deliver_at` 时刻投递消息，该时刻已经过去时立即投递
+    async fn send_at(
+        &self,
+        content: &[u8],
+        topic: &str,
+        deliver_at: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.send_at_with_headers(content, topic, deliver_at, &HashMap::new()).await
+    }
+
+    /// 在 `deliver_at` 时刻投递带消息头的消息
+    async fn send_at_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        deliver_at: SystemTime,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()>;
+}
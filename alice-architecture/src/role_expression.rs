@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// 角色表达式解析错误
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid role expression `{expression}`: {reason}")]
+pub struct RoleExpressionError {
+    pub expression: String,
+    pub reason: String,
+}
+
+/// 角色表达式，例如 `fe:user || (device:hpc && !device:disabled)`
+///
+/// 角色的格式为 `client:role`，支持 `&&`、`||`、`!` 与括号，`!` 优先级最高，`||` 最低。
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum RoleExpression {
+    Role(String),
+    Not(Box<RoleExpression>),
+    All(Vec<RoleExpression>),
+    Any(Vec<RoleExpression>),
+}
+
+impl RoleExpression {
+    pub fn evaluate(&self, roles: &HashSet<String>) -> bool {
+        match self {
+            RoleExpression::Role(role) => roles.contains(role),
+            RoleExpression::Not(expression) => !expression.evaluate(roles),
+            RoleExpression::All(expressions) => expressions.iter().all(|e| e.evaluate(roles)),
+            RoleExpression::Any(expressions) => expressions.iter().any(|e| e.evaluate(roles)),
+        }
+    }
+}
+
+impl FromStr for RoleExpression {
+    type Err = RoleExpressionError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let error = |reason: &str| RoleExpressionError {
+            expression: expression.to_string(),
+            reason: reason.to_string(),
+        };
+        let tokens = tokenize(expression).map_err(|reason| error(&reason))?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let parsed = parser.parse_any().map_err(|reason| error(&reason))?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(parsed),
+            Some(token) => Err(error(&format!("unexpected {token:?}"))),
+        }
+    }
+}
+
+impl TryFrom<String> for RoleExpression {
+    type Error = RoleExpressionError;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Role(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '!' => tokens.push(Token::Not),
+            '&' if chars.next_if_eq(&'&').is_some() => tokens.push(Token::And),
+            '|' if chars.next_if_eq(&'|').is_some() => tokens.push(Token::Or),
+            c if is_role_char(c) => {
+                let mut role = String::from(c);
+                while let Some(c) = chars.next_if(|c| is_role_char(*c)) {
+                    role.push(c);
+                }
+                tokens.push(Token::Role(role));
+            }
+            c => return Err(format!("unexpected character `{c}`")),
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_role_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, ':' | '-' | '_' | '.' | '/')
+}
+
+/// Recursive descent parser, `||` binds looser than `&&`, which binds looser than `!`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next_if(&mut self, token: &Token) -> bool {
+        let matched = self.tokens.get(self.position) == Some(token);
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn parse_any(&mut self) -> Result<RoleExpression, String> {
+        let mut expressions = vec![self.parse_all()?];
+        while self.next_if(&Token::Or) {
+            expressions.push(self.parse_all()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.remove(0),
+            _ => RoleExpression::Any(expressions),
+        })
+    }
+
+    fn parse_all(&mut self) -> Result<RoleExpression, String> {
+        let mut expressions = vec![self.parse_unary()?];
+        while self.next_if(&Token::And) {
+            expressions.push(self.parse_unary()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.remove(0),
+            _ => RoleExpression::All(expressions),
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<RoleExpression, String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Not) => Ok(RoleExpression::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let expression = self.parse_any()?;
+                if self.next_if(&Token::Close) {
+                    Ok(expression)
+                } else {
+                    Err(String::from("missing `)`"))
+                }
+            }
+            Some(Token::Role(role)) => Ok(RoleExpression::Role(role)),
+            Some(token) => Err(format!("unexpected {token:?}")),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(roles: &[&str]) -> HashSet<String> {
+        roles.iter().map(ToString::to_string).collect()
+    }
+
+    fn role(role: &str) -> RoleExpression {
+        RoleExpression::Role(role.to_string())
+    }
+
+    #[test]
+    fn role_expressions_follow_precedence_and_parentheses() {
+        assert_eq!(
+            "fe:user || device:hpc && !device:disabled".parse::<RoleExpression>().unwrap(),
+            RoleExpression::Any(vec![
+                role("fe:user"),
+                RoleExpression::All(vec![
+                    role("device:hpc"),
+                    RoleExpression::Not(Box::new(role("device:disabled"))),
+                ]),
+            ])
+        );
+        let grouped =
+            "(fe:user || device:hpc) && !device:disabled".parse::<RoleExpression>().unwrap();
+        assert_eq!(
+            grouped,
+            RoleExpression::All(vec![
+                RoleExpression::Any(vec![role("fe:user"), role("device:hpc")]),
+                RoleExpression::Not(Box::new(role("device:disabled"))),
+            ])
+        );
+        assert!(grouped.evaluate(&roles(&["fe:user"])));
+        assert!(!grouped.evaluate(&roles(&["fe:user", "device:disabled"])));
+        assert!(!grouped.evaluate(&roles(&[])));
+        assert_eq!(
+            "!!fe:user".parse::<RoleExpression>().unwrap(),
+            RoleExpression::Not(Box::new(RoleExpression::Not(Box::new(role("fe:user")))))
+        );
+    }
+
+    #[test]
+    fn malformed_role_expressions_are_rejected() {
+        for expression in [
+            "",
+            "fe:user &&",
+            "(fe:user",
+            "fe:user)",
+            "fe:user & fe:admin",
+            "fe:user fe:admin",
+            "fe:user || || fe:admin",
+            "fe:user$",
+            "()",
+        ] {
+            let e = expression.parse::<RoleExpression>().unwrap_err();
+            assert_eq!(e.expression, expression);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::ResourceControlConfig;
+
+pub use alice_architecture::role_expression::{RoleExpression, RoleExpressionError};
+
+/// 规则匹配后的效果
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// 访问策略：按 HTTP 方法、路径与角色匹配规则
+///
+/// 任意一条匹配的规则为 [`Effect::Deny`] 时拒绝访问，否则有匹配的 [`Effect::Allow`] 规则时允许访问，
+/// 没有匹配的规则时拒绝访问。
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+}
+
+/// 一条访问规则
+#[derive(Deserialize, Clone, Debug)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub effect: Effect,
+
+    /// 规则适用的 HTTP 方法，为空时适用于所有方法
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    pub path: PathPattern,
+
+    /// 角色表达式，为空时适用于所有通过认证的请求
+    #[serde(default)]
+    pub roles: Option<RoleExpression>,
+}
+
+impl AccessRule {
+    pub fn allow(path: &str) -> Self {
+        Self {
+            effect: Effect::Allow,
+            methods: vec![],
+            path: PathPattern::from(path.to_string()),
+            roles: None,
+        }
+    }
+
+    pub fn deny(path: &str) -> Self {
+        Self {
+            effect: Effect::Deny,
+            ..Self::allow(path)
+        }
+    }
+
+    pub fn methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_string()).collect();
+        self
+    }
+
+    pub fn roles(mut self, roles: RoleExpression) -> Self {
+        self.roles = Some(roles);
+        self
+    }
+
+    pub fn matches(&self, method: &str, path: &str, roles: &HashSet<String>) -> bool {
+        (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+            && self.path.matches(path)
+            && self.roles.as_ref().is_none_or(|expression| expression.evaluate(roles))
+    }
+}
+
+impl AccessPolicy {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rule(mut self, rule: AccessRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// 拥有 `roles` 的请求是否可以访问，角色的格式为 `client:role`，见 [`token_roles`]
+    pub fn is_allowed(&self, method: &str, path: &str, roles: &HashSet<String>) -> bool {
+        let mut allowed = false;
+        for rule in self.rules.iter().filter(|rule| rule.matches(method, path, roles)) {
+            match rule.effect {
+                Effect::Deny => return false,
+                Effect::Allow => allowed = true,
+            }
+        }
+        allowed
+    }
+}
+
+/// 旧的配置中每个前缀转换为 `{prefix}/**` 的允许规则，前缀按路径段匹配，`/agent` 不再匹配 `/agentx`
+impl From<&ResourceControlConfig> for AccessPolicy {
+    fn from(config: &ResourceControlConfig) -> Self {
+        let rules = config
+            .0
+            .iter()
+            .flat_map(|(client_id, roles)| {
+                roles.iter().flat_map(move |(role, prefixes)| {
+                    prefixes.iter().map(move |prefix| {
+                        AccessRule::allow(&format!("{}/**", prefix.trim_end_matches('/')))
+                            .roles(RoleExpression::Role(format!("{client_id}:{role}")))
+                    })
+                })
+            })
+            .collect();
+        Self { rules }
+    }
+}
+
+impl From<ResourceControlConfig> for AccessPolicy {
+    fn from(config: ResourceControlConfig) -> Self {
+        Self::from(&config)
+    }
+}
+
+/// 令牌 `resource_access` 中的角色，格式为 `client:role`
+pub fn token_roles(resource_access: &HashMap<String, Value>) -> HashSet<String> {
+    resource_access
+        .iter()
+        .filter_map(|(client_id, access)| Some((client_id, access.get("roles")?.as_array()?)))
+        .flat_map(|(client_id, roles)| {
+            roles
+                .iter()
+                .filter_map(Value::as_str)
+                .map(move |role| format!("{client_id}:{role}"))
+        })
+        .collect()
+}
+
+/// 按 `/` 分段匹配的路径模式
+///
+/// `*` 与 `{name}` 匹配一段，`**` 匹配零段或多段，例如 `/agent/**` 匹配 `/agent` 与 `/agent/a/b`，
+/// 但不匹配 `/agentx`；`/file-storage/{id}/download` 匹配 `/file-storage/1/download`。
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "String")]
+pub struct PathPattern {
+    pattern: String,
+    segments: Vec<PathSegment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathSegment {
+    Literal(String),
+    Any,
+    Rest,
+}
+
+impl From<String> for PathPattern {
+    fn from(pattern: String) -> Self {
+        let segments = path_segments(&pattern)
+            .map(|segment| match segment {
+                "**" => PathSegment::Rest,
+                "*" => PathSegment::Any,
+                s if s.starts_with('{') && s.ends_with('}') => PathSegment::Any,
+                s => PathSegment::Literal(s.to_string()),
+            })
+            .collect();
+        Self { pattern, segments }
+    }
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl PathPattern {
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path_segments(path).collect::<Vec<_>>();
+        segments_match(&self.segments, &path)
+    }
+}
+
+/// Empty segments are ignored, so `//a/` and `/a` are the same path.
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn segments_match(pattern: &[PathSegment], path: &[&str]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, None) => true,
+        (Some((PathSegment::Rest, rest)), _) => {
+            segments_match(rest, path) || (!path.is_empty() && segments_match(pattern, &path[1..]))
+        }
+        (Some((PathSegment::Any, rest)), Some((_, path_rest))) => segments_match(rest, path_rest),
+        (Some((PathSegment::Literal(segment), rest)), Some((path_segment, path_rest))) => {
+            segment == path_segment && segments_match(rest, path_rest)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn roles(roles: &[&str]) -> HashSet<String> {
+        roles.iter().map(ToString::to_string).collect()
+    }
+
+    fn role(role: &str) -> RoleExpression {
+        RoleExpression::Role(role.to_string())
+    }
+
+    #[test]
+    fn path_patterns_match_whole_segments() {
+        let agent = PathPattern::from(String::from("/agent/**"));
+        assert!(agent.matches("/agent"));
+        assert!(agent.matches("/agent/"));
+        assert!(agent.matches("//agent/tasks/1"));
+        assert!(!agent.matches("/agentx"));
+        assert!(!agent.matches("/agentx/tasks"));
+        assert!(!agent.matches("/"));
+
+        let download = PathPattern::from(String::from("/file-storage/{id}/download"));
+        assert!(download.matches("/file-storage/1/download"));
+        assert!(!download.matches("/file-storage/download"));
+        assert!(!download.matches("/file-storage/1/2/download"));
+
+        let health = PathPattern::from(String::from("/**/health"));
+        assert!(health.matches("/health"));
+        assert!(health.matches("/a/b/health"));
+        assert!(!health.matches("/health/a"));
+        assert!(PathPattern::from(String::from("/**")).matches("/"));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let policy = AccessPolicy::new(vec![
+            AccessRule::allow("/api/**").roles(role("fe:user")),
+            AccessRule::deny("/api/admin/**"),
+            AccessRule::allow("/api/admin/**").roles(role("fe:admin")),
+            AccessRule::deny("/api/reports/**").methods(&["DELETE"]),
+        ]);
+        let user = roles(&["fe:user"]);
+        assert!(policy.is_allowed("GET", "/api/tasks", &user));
+        assert!(!policy.is_allowed("GET", "/api/admin/users", &roles(&["fe:user", "fe:admin"])));
+        assert!(policy.is_allowed("get", "/api/reports/1", &user));
+        assert!(!policy.is_allowed("delete", "/api/reports/1", &user));
+        // Nothing matches.
+        assert!(!policy.is_allowed("GET", "/other", &user));
+        assert!(!policy.is_allowed("GET", "/api/tasks", &roles(&["device:hpc"])));
+    }
+
+    #[test]
+    fn policies_deserialize_from_configuration() {
+        let policy: AccessPolicy = serde_json::from_value(json!({
+            "rules": [
+                { "path": "/agent/**", "roles": "device:hpc && !device:disabled" },
+                { "effect": "deny", "methods": ["POST"], "path": "/agent/{id}/stop" },
+            ]
+        }))
+        .unwrap();
+        let hpc = roles(&["device:hpc"]);
+        assert!(policy.is_allowed("GET", "/agent/1/stop", &hpc));
+        assert!(!policy.is_allowed("POST", "/agent/1/stop", &hpc));
+
+        let invalid = serde_json::from_value::<AccessPolicy>(json!({
+            "rules": [{ "path": "/agent/**", "roles": "device:hpc &&" }]
+        }));
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn legacy_resource_control_allows_prefixes_by_segment() {
+        let config = ResourceControlConfig(HashMap::from([
+            (
+                String::from("fe"),
+                HashMap::from([(String::from("user"), vec![String::from("/workflow-engine")])]),
+            ),
+            (
+                String::from("device"),
+                HashMap::from([(String::from("hpc"), vec![String::from("/agent/")])]),
+            ),
+        ]));
+        let policy = AccessPolicy::from(&config);
+        let user = roles(&["fe:user"]);
+        let hpc = roles(&["device:hpc"]);
+        assert!(policy.is_allowed("GET", "/workflow-engine", &user));
+        assert!(policy.is_allowed("POST", "/workflow-engine/SubmitTask", &user));
+        assert!(!policy.is_allowed("GET", "/workflow-engine/SubmitTask", &hpc));
+        assert!(policy.is_allowed("GET", "/agent/tasks", &hpc));
+        assert!(!policy.is_allowed("GET", "/agentx", &hpc));
+        assert!(!policy.is_allowed("GET", "/agent", &user));
+    }
+
+    #[test]
+    fn token_roles_are_prefixed_by_client() {
+        let resource_access = HashMap::from([
+            (String::from("fe"), json!({ "roles": ["user", "admin"] })),
+            (String::from("device"), json!({ "roles": ["hpc"] })),
+            (String::from("account"), json!({})),
+        ]);
+        assert_eq!(
+            token_roles(&resource_access),
+            roles(&["fe:user", "fe:admin", "device:hpc"])
+        );
+    }
+}
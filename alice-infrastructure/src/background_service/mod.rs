@@ -0,0 +1,228 @@
+pub mod supervisor;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use alice_architecture::background_service::BackgroundService;
+use tokio::task::JoinSet;
+
+pub use self::supervisor::*;
+
+/// 后台服务宿主
+///
+/// 启动所有注册的后台服务，收到停止信号后通知它们停止，并在期限内等待它们处理完进行中的任务。
+/// 服务停止后不能再次运行，见 [`BackgroundService::stop`]。
+pub struct BackgroundServiceHost {
+    services: Vec<Arc<dyn BackgroundService>>,
+    shutdown_timeout: Duration,
+}
+
+impl Default for BackgroundServiceHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BackgroundServiceHost {
+    pub fn new() -> Self {
+        Self {
+            services: vec![],
+            shutdown_timeout: Self::default_shutdown_timeout(),
+        }
+    }
+
+    fn default_shutdown_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    pub fn add_service(mut self, service: Arc<dyn BackgroundService>) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// 停止时等待服务退出的最长时间，超时后强制结束
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// 运行所有服务，直到收到 SIGTERM 或 Ctrl-C
+    pub async fn run(self) -> anyhow::Result<()> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// 运行所有服务，直到 `signal` 完成
+    pub async fn run_until(self, signal: impl Future<Output = ()>) -> anyhow::Result<()> {
+        let mut tasks = JoinSet::new();
+        for service in self.services.iter() {
+            let service = service.clone();
+            tasks.spawn(async move { service.run().await });
+        }
+        tracing::info!("{} background services started", self.services.len());
+
+        signal.await;
+        tracing::info!("Stopping background services");
+        for service in self.services.iter() {
+            service.stop().await;
+        }
+
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while let Some(result) = tasks.join_next().await {
+                if let Err(e) = result {
+                    tracing::error!("Background service exited abnormally: {e}");
+                }
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tasks.abort_all();
+            anyhow::bail!(
+                "Background services didn't stop within {:?}, aborted.",
+                self.shutdown_timeout
+            );
+        }
+        tracing::info!("Background services stopped");
+        Ok(())
+    }
+}
+
+/// 等待 SIGTERM 或 Ctrl-C
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Unable to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Unable to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    /// Runs until stopped, then takes `drain` to finish its in-flight work.
+    struct Worker {
+        drain: Duration,
+        runs: AtomicUsize,
+        finished: AtomicUsize,
+        shutdown: CancellationToken,
+    }
+
+    impl Worker {
+        fn new(drain: Duration) -> Arc<Self> {
+            Arc::new(Self {
+                drain,
+                runs: AtomicUsize::new(0),
+                finished: AtomicUsize::new(0),
+                shutdown: CancellationToken::new(),
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BackgroundService for Worker {
+        async fn run(&self) {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            self.shutdown.cancelled().await;
+            tokio::time::sleep(self.drain).await;
+            self.finished.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn stop(&self) {
+            self.shutdown.cancel();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn services_run_until_the_signal() {
+        let workers = [Worker::new(Duration::ZERO), Worker::new(Duration::ZERO)];
+        let host = workers.iter().fold(BackgroundServiceHost::new(), |host, worker| {
+            host.add_service(worker.clone())
+        });
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        let host = tokio::spawn(host.run_until(async {
+            stopped.await.ok();
+        }));
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        for worker in workers.iter() {
+            assert_eq!(worker.runs.load(Ordering::SeqCst), 1);
+            assert_eq!(worker.finished.load(Ordering::SeqCst), 0);
+        }
+        stop.send(()).unwrap();
+        host.await.unwrap().unwrap();
+        for worker in workers.iter() {
+            assert_eq!(worker.finished.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_flight_work_is_drained_before_the_deadline() {
+        let worker = Worker::new(Duration::from_secs(10));
+        let started = tokio::time::Instant::now();
+        BackgroundServiceHost::new()
+            .add_service(worker.clone())
+            .shutdown_timeout(Duration::from_secs(30))
+            .run_until(async {})
+            .await
+            .unwrap();
+        assert_eq!(worker.finished.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() >= Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn services_are_aborted_after_the_shutdown_timeout() {
+        let slow = Worker::new(Duration::from_secs(60));
+        let fast = Worker::new(Duration::ZERO);
+        let started = tokio::time::Instant::now();
+        let e = BackgroundServiceHost::new()
+            .add_service(slow.clone())
+            .add_service(fast.clone())
+            .shutdown_timeout(Duration::from_secs(5))
+            .run_until(async {})
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("didn't stop within 5s"), "{e}");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert_eq!(fast.finished.load(Ordering::SeqCst), 1);
+        assert_eq!(slow.finished.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopped_services_do_not_run_again() {
+        let worker = Worker::new(Duration::ZERO);
+        BackgroundServiceHost::new()
+            .add_service(worker.clone())
+            .run_until(async {})
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), worker.run()).await.unwrap();
+        assert_eq!(worker.finished.load(Ordering::SeqCst), 2);
+    }
+}
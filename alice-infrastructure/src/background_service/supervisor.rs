@@ -0,0 +1,422 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use alice_architecture::background_service::BackgroundService;
+use serde::Serialize;
+use tokio::{task::JoinSet, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+/// 后台服务的重启策略
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// 最大连续重启次数，`None` 表示不限制
+    pub max_restarts: Option<u32>,
+    /// 首次重启前的等待时间，之后每次翻倍
+    pub initial_backoff: Duration,
+    /// 重启等待时间的上限
+    pub max_backoff: Duration,
+    /// 服务持续运行超过该时长后，重启计数清零
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// 失败后不再重启
+    pub fn never() -> Self {
+        Self {
+            max_restarts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    pub fn max_restarts(mut self, max_restarts: u32) -> Self {
+        self.max_restarts = Some(max_restarts);
+        self
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn reset_after(mut self, reset_after: Duration) -> Self {
+        self.reset_after = reset_after;
+        self
+    }
+
+    fn allows(&self, restarts: u32) -> bool {
+        !matches!(self.max_restarts, Some(max) if restarts >= max)
+    }
+
+    fn backoff_of(&self, restarts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts))
+            .min(self.max_backoff)
+    }
+}
+
+/// 受监管的后台服务的状态
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ServiceState {
+    Starting,
+    Running,
+    Restarting { attempt: u32, reason: String },
+    Failed { reason: String },
+    Stopped,
+}
+
+/// 受监管服务的状态查询句柄，可用于健康检查
+#[derive(Debug, Clone, Default)]
+pub struct SupervisorHealth {
+    states: Arc<RwLock<HashMap<String, ServiceState>>>,
+}
+
+impl SupervisorHealth {
+    pub fn states(&self) -> HashMap<String, ServiceState> {
+        self.states.read().map(|states| states.clone()).unwrap_or_default()
+    }
+
+    pub fn state(&self, name: &str) -> Option<ServiceState> {
+        self.states.read().ok()?.get(name).cloned()
+    }
+
+    /// 没有服务处于失败状态
+    pub fn is_healthy(&self) -> bool {
+        self.states
+            .read()
+            .map(|states| {
+                !states.values().any(|state| matches!(state, ServiceState::Failed { .. }))
+            })
+            .unwrap_or(false)
+    }
+
+    fn set(&self, name: &str, state: ServiceState) {
+        if let Ok(mut states) = self.states.write() {
+            states.insert(name.to_string(), state);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SupervisedService {
+    name: String,
+    service: Arc<dyn BackgroundService>,
+    policy: RestartPolicy,
+}
+
+/// 后台服务监管者
+///
+/// 运行注册的后台服务，在服务崩溃或意外退出时按照其重启策略重新启动。
+/// 监管者自身也是后台服务，可以交给 [`BackgroundServiceHost`](super::BackgroundServiceHost) 运行。
+pub struct Supervisor {
+    services: Vec<SupervisedService>,
+    health: SupervisorHealth,
+    shutdown: CancellationToken,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            services: vec![],
+            health: SupervisorHealth::default(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    pub fn register(
+        mut self,
+        name: &str,
+        service: Arc<dyn BackgroundService>,
+        policy: RestartPolicy,
+    ) -> Self {
+        self.health.set(name, ServiceState::Starting);
+        self.services.push(SupervisedService {
+            name: name.to_string(),
+            service,
+            policy,
+        });
+        self
+    }
+
+    pub fn health(&self) -> SupervisorHealth {
+        self.health.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for Supervisor {
+    async fn run(&self) {
+        let mut tasks = JoinSet::new();
+        for service in self.services.iter() {
+            tasks.spawn(supervise(
+                service.clone(),
+                self.health.clone(),
+                self.shutdown.clone(),
+            ));
+        }
+        while tasks.join_next().await.is_some() {}
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+        for service in self.services.iter() {
+            service.service.stop().await;
+        }
+    }
+}
+
+async fn supervise(
+    supervised: SupervisedService,
+    health: SupervisorHealth,
+    shutdown: CancellationToken,
+) {
+    let SupervisedService {
+        name,
+        service,
+        policy,
+    } = supervised;
+    let mut restarts = 0;
+    loop {
+        health.set(&name, ServiceState::Running);
+        tracing::info!(service = %name, event = "started", "Background service started");
+
+        let started_at = Instant::now();
+        let running = service.clone();
+        let result = tokio::spawn(async move { running.run().await }).await;
+        if shutdown.is_cancelled() {
+            health.set(&name, ServiceState::Stopped);
+            tracing::info!(service = %name, event = "stopped", "Background service stopped");
+            return;
+        }
+
+        let reason = match result {
+            Ok(()) => "exited unexpectedly".to_string(),
+            Err(e) if e.is_panic() => format!("panicked: {}", panic_message(e.into_panic())),
+            Err(e) => e.to_string(),
+        };
+        if started_at.elapsed() >= policy.reset_after {
+            restarts = 0;
+        }
+        if !policy.allows(restarts) {
+            tracing::error!(
+                service = %name,
+                event = "failed",
+                restarts,
+                reason = %reason,
+                "Background service failed, giving up"
+            );
+            health.set(&name, ServiceState::Failed { reason });
+            return;
+        }
+
+        let backoff = policy.backoff_of(restarts);
+        restarts += 1;
+        tracing::warn!(
+            service = %name,
+            event = "restarting",
+            attempt = restarts,
+            backoff_ms = backoff.as_millis() as u64,
+            reason = %reason,
+            "Background service failed, restarting"
+        );
+        health.set(
+            &name,
+            ServiceState::Restarting {
+                attempt: restarts,
+                reason,
+            },
+        );
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                health.set(&name, ServiceState::Stopped);
+                tracing::info!(service = %name, event = "stopped", "Background service stopped");
+                return;
+            }
+            _ = tokio::time::sleep(backoff) => {}
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Fails after each scripted duration, panicking with `boom` when asked to, then runs until
+    /// stopped.
+    struct Scripted {
+        script: Vec<(Duration, bool)>,
+        starts: Mutex<Vec<Instant>>,
+        shutdown: CancellationToken,
+    }
+
+    impl Scripted {
+        fn new(script: &[(u64, bool)]) -> Arc<Self> {
+            Arc::new(Self {
+                script: script
+                    .iter()
+                    .map(|(secs, panics)| (Duration::from_secs(*secs), *panics))
+                    .collect(),
+                starts: Mutex::default(),
+                shutdown: CancellationToken::new(),
+            })
+        }
+
+        fn failing(times: usize) -> Arc<Self> {
+            Self::new(&vec![(0, false); times])
+        }
+
+        /// Seconds between consecutive starts.
+        fn gaps(&self) -> Vec<u64> {
+            let starts = self.starts.lock().unwrap();
+            starts.windows(2).map(|pair| (pair[1] - pair[0]).as_secs()).collect()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BackgroundService for Scripted {
+        async fn run(&self) {
+            let run = {
+                let mut starts = self.starts.lock().unwrap();
+                starts.push(Instant::now());
+                starts.len() - 1
+            };
+            match self.script.get(run) {
+                Some((duration, panics)) => {
+                    tokio::time::sleep(*duration).await;
+                    if *panics {
+                        panic!("boom");
+                    }
+                }
+                None => self.shutdown.cancelled().await,
+            }
+        }
+
+        async fn stop(&self) {
+            self.shutdown.cancel();
+        }
+    }
+
+    /// Supervises `service` until it has run for `secs` seconds, returning the supervisor.
+    async fn supervise(
+        service: Arc<Scripted>,
+        policy: RestartPolicy,
+        secs: u64,
+    ) -> Arc<Supervisor> {
+        let supervisor = Arc::new(Supervisor::new().register("worker", service, policy));
+        let running = supervisor.clone();
+        tokio::spawn(async move { running.run().await });
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        supervisor
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_the_limit() {
+        let service = Scripted::failing(4);
+        let policy =
+            RestartPolicy::default().backoff(Duration::from_secs(1), Duration::from_secs(4));
+        let supervisor = supervise(service.clone(), policy, 60).await;
+
+        assert_eq!(service.gaps(), [1, 2, 4, 4]);
+        assert_eq!(
+            supervisor.health().state("worker"),
+            Some(ServiceState::Running)
+        );
+        supervisor.stop().await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(
+            supervisor.health().state("worker"),
+            Some(ServiceState::Stopped)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_resets_after_running_long_enough() {
+        let service = Scripted::new(&[(0, false), (0, false), (20, false), (0, false)]);
+        let policy = RestartPolicy::default()
+            .backoff(Duration::from_secs(1), Duration::from_secs(60))
+            .reset_after(Duration::from_secs(10));
+        supervise(service.clone(), policy, 60).await;
+
+        assert_eq!(service.gaps(), [1, 2, 21, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_restarts() {
+        let service = Scripted::failing(5);
+        let policy = RestartPolicy::default()
+            .max_restarts(2)
+            .backoff(Duration::from_secs(1), Duration::from_secs(1));
+        let supervisor = Arc::new(Supervisor::new().register("worker", service.clone(), policy));
+
+        // The supervisor returns once its only service has failed.
+        tokio::time::timeout(Duration::from_secs(60), supervisor.run()).await.unwrap();
+        assert_eq!(service.starts.lock().unwrap().len(), 3);
+        assert_eq!(
+            supervisor.health().state("worker"),
+            Some(ServiceState::Failed {
+                reason: String::from("exited unexpectedly")
+            })
+        );
+        assert!(!supervisor.health().is_healthy());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_reports_restarts_and_panics() {
+        let service = Scripted::new(&[(1, true), (1, true)]);
+        let policy = RestartPolicy::default()
+            .max_restarts(1)
+            .backoff(Duration::from_secs(10), Duration::from_secs(10));
+        let supervisor = supervise(service, policy, 5).await;
+
+        let health = supervisor.health();
+        assert_eq!(
+            health.state("worker"),
+            Some(ServiceState::Restarting {
+                attempt: 1,
+                reason: String::from("panicked: boom")
+            })
+        );
+        assert!(health.is_healthy());
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(
+            health.state("worker"),
+            Some(ServiceState::Failed {
+                reason: String::from("panicked: boom")
+            })
+        );
+        assert!(!health.is_healthy());
+    }
+}
@@ -3,6 +3,8 @@ use std::collections::{HashMap, HashSet};
 use serde::Deserialize;
 use serde_json::Value;
 
+use crate::access_policy::{AccessPolicy, PathPattern};
+
 pub fn build_config() -> anyhow::Result<config::Config> {
     let args: Vec<String> = std::env::args().collect();
     let mut config = config::Config::builder().add_source(
@@ -50,6 +52,9 @@ pub struct CommonConfig {
     #[serde(default)]
     pub jwt: JwtValidationConfig,
 
+    #[serde(default)]
+    pub introspection: Option<IntrospectionConfig>,
+
     #[serde(default)]
     pub http_client: HttpClientConfig,
 }
@@ -73,6 +78,174 @@ pub struct JwtValidationConfig {
 
     #[serde(default = "JwtValidationConfig::default_iss")]
     pub iss: Option<HashSet<String>>,
+
+    /// 允许的签名算法，令牌使用的算法由匹配的 JWK 的 `alg` 决定，必须在该列表中
+    #[serde(default = "JwtValidationConfig::default_allowed_algorithms")]
+    pub allowed_algorithms: HashSet<String>,
+
+    /// 受信任的签发者及其公钥来源，只有这些签发者的令牌才会去获取公钥
+    #[serde(default)]
+    pub trusted_issuers: HashMap<String, TrustedIssuer>,
+
+    /// 同一个签发者两次刷新公钥的最短间隔（秒），防止未知的 `kid` 引发大量请求
+    #[serde(default = "JwtValidationConfig::default_key_refresh_interval")]
+    pub key_refresh_interval: u64,
+
+    /// 不需要令牌的路径，例如 `/health`、`/metrics/**`
+    #[serde(default)]
+    pub public_paths: Vec<PathPattern>,
+
+    /// 按名称配置的作用域，覆盖部分验证配置，通过 `JwtValidationMiddleware::scope` 使用
+    #[serde(default)]
+    pub scopes: HashMap<String, JwtScopeConfig>,
+
+    /// 从令牌的声明中读取用户与角色的方式，默认与 Keycloak 的令牌一致
+    #[serde(default)]
+    pub claims: ClaimsMappingConfig,
+}
+
+/// OAuth2 令牌内省（RFC 7662）配置，用于验证不是 JWT 的令牌
+#[derive(Deserialize, Clone, Debug)]
+pub struct IntrospectionConfig {
+    pub endpoint: String,
+
+    /// 调用内省接口的客户端凭据
+    pub client_id: String,
+
+    pub client_secret: String,
+
+    /// 有效令牌的结果缓存时间（秒），不超过令牌的 `exp`
+    #[serde(default = "IntrospectionConfig::default_cache_ttl")]
+    pub cache_ttl: u64,
+
+    /// 无效令牌的结果缓存时间（秒）
+    #[serde(default = "IntrospectionConfig::default_negative_cache_ttl")]
+    pub negative_cache_ttl: u64,
+
+    /// 最多缓存的结果数，超过时淘汰最先过期的结果
+    #[serde(default = "IntrospectionConfig::default_cache_capacity")]
+    pub cache_capacity: usize,
+
+    /// 内省结果的声明映射，默认用户名取 `username`
+    #[serde(default = "IntrospectionConfig::default_claims")]
+    pub claims: ClaimsMappingConfig,
+}
+
+impl IntrospectionConfig {
+    fn default_cache_ttl() -> u64 {
+        60
+    }
+
+    fn default_negative_cache_ttl() -> u64 {
+        10
+    }
+
+    fn default_cache_capacity() -> usize {
+        10_000
+    }
+
+    fn default_claims() -> ClaimsMappingConfig {
+        ClaimsMappingConfig {
+            username: String::from("username"),
+            ..Default::default()
+        }
+    }
+}
+
+/// 声明映射，声明的路径以 `.` 分隔，例如 `realm_access.roles`
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClaimsMappingConfig {
+    /// 用户 id 所在的声明，值必须为 uuid
+    #[serde(default = "ClaimsMappingConfig::default_subject")]
+    pub subject: String,
+
+    #[serde(default = "ClaimsMappingConfig::default_username")]
+    pub username: String,
+
+    /// 角色的来源，所有来源的角色合并在一起
+    #[serde(default = "ClaimsMappingConfig::default_roles")]
+    pub roles: Vec<RoleSource>,
+
+    /// 空格分隔的 scope 所在的声明
+    #[serde(default = "ClaimsMappingConfig::default_scope")]
+    pub scope: String,
+}
+
+/// 角色的来源，角色统一为 `client:role` 的格式
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "from", rename_all = "snake_case")]
+pub enum RoleSource {
+    /// Keycloak 的 `resource_access`，角色为 `client:role`
+    ResourceAccess,
+    /// Keycloak 的 `realm_access.roles`，角色为 `{client}:role`
+    RealmAccess {
+        #[serde(default = "RoleSource::default_realm_client")]
+        client: String,
+    },
+    /// 令牌的 scope，角色为 `{client}:scope`
+    Scope { client: String },
+    /// 任意声明中的字符串数组或空格分隔的字符串，角色为 `{client}:value`
+    Claim { path: String, client: String },
+}
+
+impl RoleSource {
+    fn default_realm_client() -> String {
+        String::from("realm")
+    }
+}
+
+impl ClaimsMappingConfig {
+    fn default_subject() -> String {
+        String::from("sub")
+    }
+
+    fn default_username() -> String {
+        String::from("preferred_username")
+    }
+
+    fn default_roles() -> Vec<RoleSource> {
+        vec![RoleSource::ResourceAccess]
+    }
+
+    fn default_scope() -> String {
+        String::from("scope")
+    }
+}
+
+impl Default for ClaimsMappingConfig {
+    fn default() -> Self {
+        Self {
+            subject: Self::default_subject(),
+            username: Self::default_username(),
+            roles: Self::default_roles(),
+            scope: Self::default_scope(),
+        }
+    }
+}
+
+/// 作用域的验证配置，没有设置的项使用外层的配置
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct JwtScopeConfig {
+    #[serde(default)]
+    pub aud: Option<HashSet<String>>,
+
+    #[serde(default)]
+    pub iss: Option<HashSet<String>>,
+
+    #[serde(default)]
+    pub public_paths: Option<Vec<PathPattern>>,
+}
+
+/// 签发者的公钥来源
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustedIssuer {
+    /// OpenID Connect discovery 地址，从中获取 `jwks_uri`
+    Discovery(String),
+    /// JWKS 地址
+    JwksUri(String),
+    /// 静态配置的 JWKS
+    Jwks(Value),
 }
 
 impl JwtValidationConfig {
@@ -99,6 +272,73 @@ impl JwtValidationConfig {
     fn default_iss() -> Option<HashSet<String>> {
         None
     }
+
+    fn default_key_refresh_interval() -> u64 {
+        30
+    }
+
+    /// 使用作用域覆盖后的配置，没有该作用域时返回原配置
+    pub fn for_scope(&self, scope: &str) -> Self {
+        let mut config = self.clone();
+        if let Some(scope) = self.scopes.get(scope) {
+            if scope.aud.is_some() {
+                config.aud = scope.aud.clone();
+            }
+            if scope.iss.is_some() {
+                config.iss = scope.iss.clone();
+            }
+            if let Some(public_paths) = &scope.public_paths {
+                config.public_paths = public_paths.clone();
+            }
+        }
+        config
+    }
+
+    pub fn is_public_path(&self, path: &str) -> bool {
+        self.public_paths.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// 签发者的公钥来源
+    ///
+    /// 没有配置在 `trusted_issuers` 中、但在 `iss` 中的签发者使用标准的 discovery 地址。
+    pub fn trusted_issuer(&self, iss: &str) -> Option<TrustedIssuer> {
+        if let Some(issuer) = self.trusted_issuers.get(iss) {
+            return Some(issuer.clone());
+        }
+        self.iss.as_ref().filter(|allowed| allowed.contains(iss)).map(|_| {
+            TrustedIssuer::Discovery(format!(
+                "{}/.well-known/openid-configuration",
+                iss.trim_end_matches('/')
+            ))
+        })
+    }
+
+    /// 所有受信任的签发者，包括只在作用域中受信任的签发者，见 [`JwtValidationConfig::trusted_issuer`]
+    ///
+    /// 所有作用域共用一个公钥存储，因此公钥存储需要能够获取每个作用域的签发者的公钥。
+    pub fn issuer_registry(&self) -> HashMap<String, TrustedIssuer> {
+        let scopes = self.scopes.keys().map(|scope| self.for_scope(scope));
+        std::iter::once(self.clone())
+            .chain(scopes)
+            .flat_map(|config| {
+                config
+                    .trusted_issuers
+                    .keys()
+                    .chain(config.iss.iter().flatten())
+                    .filter_map(|iss| Some((iss.clone(), config.trusted_issuer(iss)?)))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn default_allowed_algorithms() -> HashSet<String> {
+        [
+            "RS256", "RS384", "RS512", "PS256", "PS384", "PS512", "ES256", "ES384", "EdDSA",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
 }
 
 impl Default for JwtValidationConfig {
@@ -110,6 +350,12 @@ impl Default for JwtValidationConfig {
             validate_nbf: Self::default_validate_nbf(),
             aud: Self::default_aud(),
             iss: Self::default_iss(),
+            allowed_algorithms: Self::default_allowed_algorithms(),
+            trusted_issuers: HashMap::new(),
+            key_refresh_interval: Self::default_key_refresh_interval(),
+            public_paths: vec![],
+            scopes: HashMap::new(),
+            claims: ClaimsMappingConfig::default(),
         }
     }
 }
@@ -230,6 +476,10 @@ pub struct HostConfig {
     /// Config which controller path the client is able to access and what roles of this client can access.
     #[serde(default)]
     pub resources_config: ResourceControlConfig,
+
+    /// 访问策略，没有配置时由 `resources_config` 转换
+    #[serde(default)]
+    pub access_policy: Option<AccessPolicy>,
 }
 
 impl Default for HostConfig {
@@ -239,11 +489,19 @@ impl Default for HostConfig {
             bind_port: Self::default_port(),
             upload_file_path: Self::default_upload_path(),
             resources_config: ResourceControlConfig::default(),
+            access_policy: None,
         }
     }
 }
 
 impl HostConfig {
+    /// 生效的访问策略
+    pub fn access_policy(&self) -> AccessPolicy {
+        self.access_policy
+            .clone()
+            .unwrap_or_else(|| AccessPolicy::from(&self.resources_config))
+    }
+
     fn default_address() -> String {
         "0.0.0.0".to_string()
     }
@@ -267,6 +525,116 @@ pub struct MessageQueueConfig {
 
     #[serde(default)]
     pub consumer: HashMap<String, String>,
+
+    #[serde(default)]
+    pub internal: InternalQueueConfig,
+
+    /// 创建主题时的默认参数
+    #[serde(default)]
+    pub topic_defaults: TopicConfig,
+
+    /// 单独指定参数的主题，没有配置的主题使用 `topic_defaults`
+    #[serde(default)]
+    pub topic_configs: HashMap<String, TopicConfig>,
+}
+
+impl MessageQueueConfig {
+    /// 主题的创建参数
+    pub fn topic_config(&self, topic: &str) -> &TopicConfig {
+        self.topic_configs.get(topic).unwrap_or(&self.topic_defaults)
+    }
+}
+
+/// Kafka 主题的创建参数，启动时用于创建缺少的主题与检查已有的主题
+#[derive(Deserialize, Clone, Debug)]
+pub struct TopicConfig {
+    #[serde(default = "TopicConfig::default_partitions")]
+    pub partitions: i32,
+
+    #[serde(default = "TopicConfig::default_replication_factor")]
+    pub replication_factor: i32,
+
+    /// 消息保留的毫秒数，不设置时使用 broker 的默认值
+    #[serde(default)]
+    pub retention_ms: Option<i64>,
+
+    /// 其他主题配置，例如 `cleanup.policy`
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+}
+
+impl TopicConfig {
+    fn default_partitions() -> i32 {
+        1
+    }
+
+    fn default_replication_factor() -> i32 {
+        1
+    }
+
+    /// 需要设置与检查的主题配置，包括 `retention.ms`
+    pub fn settings(&self) -> HashMap<String, String> {
+        let mut settings = self.config.clone();
+        if let Some(retention_ms) = self.retention_ms {
+            settings.insert("retention.ms".to_string(), retention_ms.to_string());
+        }
+        settings
+    }
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            partitions: Self::default_partitions(),
+            replication_factor: Self::default_replication_factor(),
+            retention_ms: None,
+            config: HashMap::new(),
+        }
+    }
+}
+
+/// 内部消息队列配置
+#[derive(Default, Deserialize, Clone, Debug)]
+pub struct InternalQueueConfig {
+    /// 队列容量，不设置时不限制
+    #[serde(default)]
+    pub capacity: Option<usize>,
+
+    /// 队列已满时的处理方式
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
+
+    /// 写前日志的路径，设置后没有处理完的消息在重启后重新投递
+    #[serde(default)]
+    pub write_ahead_log: Option<String>,
+
+    /// 写前日志同步到磁盘的方式
+    #[serde(default)]
+    pub write_ahead_log_sync: WalSyncPolicy,
+}
+
+/// 写前日志同步到磁盘的方式
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSyncPolicy {
+    /// 每条消息写入后同步，进程或系统崩溃都不会丢失已经发送成功的消息
+    #[default]
+    Always,
+    /// 由操作系统决定何时写入磁盘，系统崩溃时可能丢失最近发送的消息
+    Never,
+}
+
+/// 有界队列已满时的处理方式
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// 等待队列有空位
+    #[default]
+    Block,
+    /// 丢弃最早的消息
+    DropOldest,
+    /// 返回错误
+    Error,
 }
 
 #[derive(Deserialize, Clone, Debug)]
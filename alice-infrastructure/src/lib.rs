@@ -1,5 +1,10 @@
+pub mod access_policy;
+
 pub mod config;
 
+#[cfg(feature = "background-service")]
+pub mod background_service;
+
 #[cfg(feature = "sea-orm-db")]
 pub mod data;
 
@@ -9,7 +14,7 @@ pub mod event_system;
 #[cfg(any(feature = "kafka-mq", feature = "flume-mq"))]
 pub mod message_queue;
 
-#[cfg(feature = "flume-mq")]
+#[cfg(any(feature = "kafka-mq", feature = "flume-mq"))]
 pub use message_queue::{ConsumerFn, ConsumerReturn};
 
 #[cfg(feature = "error")]
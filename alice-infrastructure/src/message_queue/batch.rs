@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::future::{ready, Future};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+
+use super::{decode_message, ConsumerReturn, DecodeError, Message};
+
+pub type BatchConsumerFn<SP> =
+    for<'async_fn> fn(messages: &'async_fn [Message], sp: Arc<SP>) -> ConsumerReturn;
+
+/// 类型擦除后的批处理函数
+pub type BatchHandler<SP> =
+    Arc<dyn for<'a> Fn(&'a [Message], Arc<SP>) -> ConsumerReturn<'a> + Send + Sync>;
+
+pub fn batch_consumer_fn_handler<SP>(handler: BatchConsumerFn<SP>) -> BatchHandler<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    Arc::new(handler)
+}
+
+/// 把以 `Vec<T>` 接收消息的批处理函数转换为 [`BatchHandler`]
+///
+/// 任何一条消息反序列化失败时，整批消息作为解码错误处理。
+pub fn typed_batch_handler<T, SP, F, Fut>(handler: F) -> BatchHandler<SP>
+where
+    T: DeserializeOwned + 'static,
+    SP: Send + Sync + 'static,
+    F: Fn(Vec<T>, Arc<SP>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + 'static,
+{
+    Arc::new(move |messages: &[Message], sp: Arc<SP>| -> ConsumerReturn {
+        let contents = messages
+            .iter()
+            .map(|message| {
+                decode_message::<T>(message).map_err(|e| DecodeError::new(&message.topic, e))
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match contents {
+            Ok(contents) => Box::pin(handler(contents, sp)),
+            Err(e) => Box::pin(ready(Err(e.into()))),
+        }
+    })
+}
+
+/// 批量消费的窗口：攒够 `max_size` 条消息，或者距离第一条消息超过 `max_wait` 时处理一批
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    pub max_size: usize,
+    pub max_wait: Duration,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 500,
+            max_wait: Duration::from_millis(200),
+        }
+    }
+}
+
+impl BatchOptions {
+    pub fn new(max_size: usize, max_wait: Duration) -> Self {
+        Self { max_size, max_wait }
+    }
+}
+
+/// 按主题积攒的消息
+pub(crate) struct Batches<T> {
+    options: BatchOptions,
+    batches: HashMap<String, (Instant, Vec<T>)>,
+}
+
+impl<T> Batches<T> {
+    pub fn new(options: BatchOptions) -> Self {
+        Self {
+            options,
+            batches: HashMap::new(),
+        }
+    }
+
+    /// 加入一条消息，该主题攒满时返回这一批
+    pub fn push(&mut self, topic: &str, item: T) -> Option<Vec<T>> {
+        let (_, items) = self
+            .batches
+            .entry(topic.to_string())
+            .or_insert_with(|| (Instant::now(), Vec::with_capacity(self.options.max_size)));
+        items.push(item);
+        if items.len() >= self.options.max_size {
+            return self.batches.remove(topic).map(|(_, items)| items);
+        }
+        None
+    }
+
+    /// 最早到期的一批的处理时间
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.batches
+            .values()
+            .map(|(started_at, _)| *started_at + self.options.max_wait)
+            .min()
+    }
+
+    /// 取出所有到期的批次
+    pub fn take_expired(&mut self) -> Vec<(String, Vec<T>)> {
+        let now = Instant::now();
+        let max_wait = self.options.max_wait;
+        let expired = self
+            .batches
+            .iter()
+            .filter(|(_, (started_at, _))| *started_at + max_wait <= now)
+            .map(|(topic, _)| topic.to_owned())
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter_map(|topic| self.batches.remove(&topic).map(|(_, items)| (topic, items)))
+            .collect()
+    }
+
+    /// 取出所有批次
+    pub fn take_all(&mut self) -> Vec<(String, Vec<T>)> {
+        self.batches.drain().map(|(topic, (_, items))| (topic, items)).collect()
+    }
+}
+
+/// 等到最早的一批到期；没有待处理的批次时一直等待
+pub(crate) async fn wait_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::Message;
+
+/// 消息编码格式的消息头
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// 消息的编码格式
+///
+/// 生产者发送对象时把编码格式写入 [`CONTENT_TYPE_HEADER`]，消费者按消息头选择解码方式，
+/// 没有该消息头的消息按 JSON 解码。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageCodec {
+    #[default]
+    Json,
+    #[cfg(feature = "codec-msgpack")]
+    MessagePack,
+    #[cfg(feature = "codec-cbor")]
+    Cbor,
+    /// Protobuf 不经过 serde，使用 [`send_protobuf`] 与 [`protobuf_handler`] 收发
+    #[cfg(feature = "codec-protobuf")]
+    Protobuf,
+}
+
+impl MessageCodec {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            #[cfg(feature = "codec-msgpack")]
+            Self::MessagePack => "application/msgpack",
+            #[cfg(feature = "codec-cbor")]
+            Self::Cbor => "application/cbor",
+            #[cfg(feature = "codec-protobuf")]
+            Self::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        // Parameters such as `; charset=utf-8` don't affect the codec.
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "application/json" => Some(Self::Json),
+            #[cfg(feature = "codec-msgpack")]
+            "application/msgpack" | "application/x-msgpack" => Some(Self::MessagePack),
+            #[cfg(feature = "codec-cbor")]
+            "application/cbor" => Some(Self::Cbor),
+            #[cfg(feature = "codec-protobuf")]
+            "application/x-protobuf" | "application/protobuf" => Some(Self::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// 按消息头选择编码格式，没有消息头时为 JSON
+    pub fn from_headers(headers: &HashMap<String, String>) -> anyhow::Result<Self> {
+        match headers.get(CONTENT_TYPE_HEADER) {
+            Some(content_type) => Self::from_content_type(content_type)
+                .ok_or_else(|| anyhow::anyhow!("Unsupported content type: {content_type}")),
+            None => Ok(Self::Json),
+        }
+    }
+
+    /// 含有编码格式的消息头
+    pub fn headers(&self) -> HashMap<String, String> {
+        HashMap::from([(
+            CONTENT_TYPE_HEADER.to_string(),
+            self.content_type().to_string(),
+        )])
+    }
+
+    pub fn encode<T>(&self, content: &T) -> anyhow::Result<Vec<u8>>
+    where
+        T: Serialize + ?Sized,
+    {
+        Ok(match self {
+            Self::Json => serde_json::to_vec(content)?,
+            #[cfg(feature = "codec-msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(content)?,
+            #[cfg(feature = "codec-cbor")]
+            Self::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(content, &mut buffer)?;
+                buffer
+            }
+            #[cfg(feature = "codec-protobuf")]
+            Self::Protobuf => anyhow::bail!("Protobuf messages are sent with send_protobuf"),
+        })
+    }
+
+    pub fn decode<T>(&self, payload: &[u8]) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(payload)?,
+            #[cfg(feature = "codec-msgpack")]
+            Self::MessagePack => rmp_serde::from_slice(payload)?,
+            #[cfg(feature = "codec-cbor")]
+            Self::Cbor => ciborium::from_reader(payload)?,
+            #[cfg(feature = "codec-protobuf")]
+            Self::Protobuf => anyhow::bail!("Protobuf messages are decoded with protobuf_handler"),
+        })
+    }
+}
+
+/// 按消息头解码消息
+pub fn decode_message<T>(message: &Message) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+{
+    MessageCodec::from_headers(&message.headers)?.decode(&message.payload)
+}
+
+/// 生产者的编码格式：默认格式，以及为部分主题单独指定的格式
+#[derive(Debug, Clone, Default)]
+pub struct MessageCodecs {
+    default: MessageCodec,
+    topics: HashMap<String, MessageCodec>,
+}
+
+impl MessageCodecs {
+    pub fn new(default: MessageCodec) -> Self {
+        Self {
+            default,
+            topics: HashMap::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, codec: MessageCodec) {
+        self.default = codec;
+    }
+
+    pub fn set_topic(&mut self, topic: &str, codec: MessageCodec) {
+        self.topics.insert(topic.to_string(), codec);
+    }
+
+    pub fn for_topic(&self, topic: &str) -> MessageCodec {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+}
+
+#[cfg(feature = "codec-protobuf")]
+pub use self::protobuf::*;
+
+#[cfg(feature = "codec-protobuf")]
+mod protobuf {
+    use std::future::{ready, Future};
+    use std::sync::Arc;
+
+    use super::{MessageCodec, CONTENT_TYPE_HEADER};
+    use crate::message_queue::{ConsumerReturn, DecodeError, Message, MessageHandler};
+    use alice_architecture::message_queue::producer::MessageQueueProducer;
+
+    /// 以 Protobuf 编码发送消息
+    pub async fn send_protobuf<T>(
+        producer: &dyn MessageQueueProducer,
+        content: &T,
+        topic: &str,
+    ) -> anyhow::Result<()>
+    where
+        T: prost::Message,
+    {
+        producer
+            .send_with_headers(
+                &content.encode_to_vec(),
+                topic,
+                &MessageCodec::Protobuf.headers(),
+            )
+            .await
+    }
+
+    /// 把以 Protobuf 消息 `T` 接收消息的处理函数转换为 [`MessageHandler`]
+    pub fn protobuf_handler<T, SP, F, Fut>(handler: F) -> MessageHandler<SP>
+    where
+        T: prost::Message + Default + 'static,
+        SP: Send + Sync + 'static,
+        F: Fn(T, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        Arc::new(move |message: &Message, sp: Arc<SP>| -> ConsumerReturn {
+            let content = match message.header(CONTENT_TYPE_HEADER) {
+                Some(content_type)
+                    if MessageCodec::from_content_type(content_type)
+                        != Some(MessageCodec::Protobuf) =>
+                {
+                    Err(anyhow::anyhow!(
+                        "Expected protobuf message, got {content_type}"
+                    ))
+                }
+                _ => T::decode(message.payload.as_slice()).map_err(anyhow::Error::from),
+            };
+            match content {
+                Ok(content) => Box::pin(handler(content, sp)),
+                Err(e) => Box::pin(ready(Err(DecodeError::new(&message.topic, e).into()))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u32,
+        items: Vec<String>,
+        note: Option<String>,
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 7,
+            items: vec![String::from("book"), String::from("pen")],
+            note: None,
+        }
+    }
+
+    fn codecs() -> Vec<MessageCodec> {
+        vec![
+            MessageCodec::Json,
+            #[cfg(feature = "codec-msgpack")]
+            MessageCodec::MessagePack,
+            #[cfg(feature = "codec-cbor")]
+            MessageCodec::Cbor,
+        ]
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        for codec in codecs() {
+            let message = Message {
+                topic: String::from("orders"),
+                payload: codec.encode(&order()).unwrap(),
+                headers: codec.headers(),
+            };
+            assert_eq!(
+                decode_message::<Order>(&message).unwrap(),
+                order(),
+                "{codec:?}"
+            );
+            assert_eq!(
+                MessageCodec::from_content_type(codec.content_type()),
+                Some(codec)
+            );
+        }
+    }
+
+    #[test]
+    fn content_type_selects_codec() {
+        assert_eq!(
+            MessageCodec::from_content_type("application/json; charset=utf-8"),
+            Some(MessageCodec::Json)
+        );
+        assert_eq!(MessageCodec::from_content_type("text/plain"), None);
+        assert_eq!(
+            MessageCodec::from_headers(&HashMap::new()).unwrap(),
+            MessageCodec::Json
+        );
+        let headers = HashMap::from([(CONTENT_TYPE_HEADER.to_string(), "text/plain".to_string())]);
+        assert!(MessageCodec::from_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn topics_can_override_default_codec() {
+        let mut codecs = MessageCodecs::default();
+        codecs.set_topic("orders", MessageCodec::Json);
+        assert_eq!(codecs.for_topic("payments"), MessageCodec::Json);
+        #[cfg(feature = "codec-msgpack")]
+        {
+            codecs.set_default(MessageCodec::MessagePack);
+            assert_eq!(codecs.for_topic("payments"), MessageCodec::MessagePack);
+            assert_eq!(codecs.for_topic("orders"), MessageCodec::Json);
+        }
+    }
+
+    #[cfg(feature = "codec-protobuf")]
+    #[tokio::test]
+    async fn protobuf_handler_decodes_protobuf_messages() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::message_queue::DecodeError;
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Ping {
+            #[prost(uint32, tag = "1")]
+            n: u32,
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handler = protobuf_handler(|ping: Ping, received: Arc<Mutex<Vec<u32>>>| async move {
+            received.lock().unwrap().push(ping.n);
+            Ok(())
+        });
+        let message = Message {
+            topic: String::from("pings"),
+            payload: prost::Message::encode_to_vec(&Ping { n: 7 }),
+            headers: MessageCodec::Protobuf.headers(),
+        };
+        handler(&message, received.clone()).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), [7]);
+
+        let json = Message {
+            headers: MessageCodec::Json.headers(),
+            ..message
+        };
+        let e = handler(&json, received.clone()).await.unwrap_err();
+        assert!(e.is::<DecodeError>());
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use super::topic_matches;
+
+/// 令牌桶限流参数：每秒补充 `per_second` 个令牌，最多积攒 `burst` 个
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// 主题当前的控制状态
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TopicStatus {
+    pub paused: bool,
+    pub rate_limit: Option<RateLimit>,
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst.max(1)),
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long to wait for the next one.
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        let burst = f64::from(self.limit.burst.max(1));
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(burst);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else if self.limit.per_second > 0.0 {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        } else {
+            // No token will ever be added, check again later in case the limit is changed.
+            Some(Duration::from_secs(1))
+        }
+    }
+}
+
+#[derive(Default)]
+struct TopicControl {
+    paused: bool,
+    bucket: Option<TokenBucket>,
+}
+
+struct ControlState {
+    topics: Mutex<HashMap<String, TopicControl>>,
+    changes: watch::Sender<()>,
+}
+
+/// 消费者的运行时控制：按主题暂停、恢复消费，以及按主题限流
+///
+/// 通过消费者的 `control` 方法获取，可以克隆后交给管理接口使用。主题可以使用通配符，
+/// 匹配同一个模式的主题共用一个令牌桶。暂停的主题的消息留在队列中，恢复后按顺序处理。
+#[derive(Clone)]
+pub struct ConsumerControl {
+    state: Arc<ControlState>,
+}
+
+impl Default for ConsumerControl {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(ControlState {
+                topics: Mutex::default(),
+                changes: watch::channel(()).0,
+            }),
+        }
+    }
+}
+
+impl ConsumerControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self, topic: &str) {
+        self.topics().entry(topic.to_string()).or_default().paused = true;
+        self.state.changes.send_replace(());
+    }
+
+    pub fn resume(&self, topic: &str) {
+        if let Some(control) = self.topics().get_mut(topic) {
+            control.paused = false;
+        }
+        self.state.changes.send_replace(());
+    }
+
+    /// 设置主题的限流，`None` 时取消限流
+    pub fn set_rate_limit(&self, topic: &str, rate_limit: Option<RateLimit>) {
+        self.topics().entry(topic.to_string()).or_default().bucket =
+            rate_limit.map(TokenBucket::new);
+        self.state.changes.send_replace(());
+    }
+
+    pub fn is_paused(&self, topic: &str) -> bool {
+        self.topics()
+            .iter()
+            .any(|(pattern, control)| control.paused && topic_matches(pattern, topic))
+    }
+
+    /// 设置过暂停或限流的主题的状态
+    pub fn status(&self) -> HashMap<String, TopicStatus> {
+        self.topics()
+            .iter()
+            .map(|(topic, control)| {
+                let status = TopicStatus {
+                    paused: control.paused,
+                    rate_limit: control.bucket.as_ref().map(|bucket| bucket.limit),
+                };
+                (topic.clone(), status)
+            })
+            .collect()
+    }
+
+    /// Notifies the consumer loops when a topic is paused, resumed or its limit changes.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<()> {
+        self.state.changes.subscribe()
+    }
+
+    /// Waits until the rate limit of the topic allows handling one more message.
+    pub(crate) async fn throttle(&self, topic: &str) {
+        loop {
+            let wait = self
+                .topics()
+                .iter_mut()
+                .filter(|(pattern, _)| topic_matches(pattern, topic))
+                .filter_map(|(_, control)| control.bucket.as_mut())
+                .find_map(TokenBucket::take);
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    fn topics(&self) -> MutexGuard<'_, HashMap<String, TopicControl>> {
+        self.state.topics.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take_all(bucket: &mut TokenBucket) -> usize {
+        std::iter::from_fn(|| bucket.take().is_none().then_some(())).count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_up_to_burst() {
+        let mut bucket = TokenBucket::new(RateLimit::new(2.0, 3));
+        assert_eq!(take_all(&mut bucket), 3);
+        assert_eq!(bucket.take(), Some(Duration::from_millis(500)));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(take_all(&mut bucket), 1);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(take_all(&mut bucket), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_without_rate_only_allows_burst() {
+        let mut bucket = TokenBucket::new(RateLimit::new(0.0, 0));
+        assert_eq!(take_all(&mut bucket), 1);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.take(), Some(Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_waits_for_matching_limit() {
+        let control = ConsumerControl::new();
+        control.set_rate_limit("orders.*", Some(RateLimit::new(1.0, 1)));
+
+        let started_at = Instant::now();
+        control.throttle("orders.created").await;
+        control.throttle("orders.paid").await;
+        assert_eq!(started_at.elapsed(), Duration::from_secs(1));
+        // Other topics aren't limited.
+        control.throttle("payments.created").await;
+        assert_eq!(started_at.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn pausing_patterns_pauses_matching_topics() {
+        let control = ConsumerControl::new();
+        control.pause("orders.#");
+        assert!(control.is_paused("orders.created"));
+        assert!(!control.is_paused("payments.created"));
+
+        control.resume("orders.#");
+        assert!(!control.is_paused("orders.created"));
+        assert_eq!(
+            control.status()["orders.#"],
+            TopicStatus {
+                paused: false,
+                rate_limit: None
+            }
+        );
+    }
+}
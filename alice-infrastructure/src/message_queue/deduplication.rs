@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{ConsumerContext, ConsumerReturn, Message, MessageHandler};
+
+/// 消息的唯一 id，生产者在发送时没有设置该消息头的情况下自动生成
+pub const MESSAGE_ID_HEADER: &str = "message-id";
+
+tokio::task_local! {
+    static DEDUPLICATION_STORE: Option<Arc<dyn DeduplicationStore>>;
+}
+
+/// 记录已经处理过的消息，用于跳过至少一次投递产生的重复消息
+#[async_trait::async_trait]
+pub trait DeduplicationStore: Send + Sync {
+    /// 没有记录时记录该主题上的消息并返回 `true`，已经有记录时返回 `false`
+    ///
+    /// 检查与记录必须是原子的，同时到达的重复消息只有一个能记录成功。
+    async fn insert(&self, topic: &str, message_id: &str) -> anyhow::Result<bool>;
+
+    /// 删除记录，使重新投递的消息能够再次处理
+    async fn remove(&self, topic: &str, message_id: &str) -> anyhow::Result<()>;
+}
+
+pub(crate) fn new_message_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Makes the store of the consumer available to the idempotent handlers called in `future`.
+pub(crate) fn scope<F: Future>(
+    store: Option<Arc<dyn DeduplicationStore>>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    DEDUPLICATION_STORE.scope(store, future)
+}
+
+/// 幂等地执行处理函数：处理前记录消息 id，已经有记录时跳过，处理失败时删除记录
+///
+/// 由 `#[message_consumer(idempotent)]` 生成的代码调用。消费者没有设置 [`DeduplicationStore`]，
+/// 或者消息没有 [`MESSAGE_ID_HEADER`] 时直接执行处理函数。
+///
+/// 消息 id 在处理前记录，同时到达的重复消息只处理一次。进程在处理过程中退出，或者处理失败后没能删除记录时，
+/// 记录仍然保留，重新投递的消息在记录过期前被跳过，这段时间内消息最多处理一次而不是至少一次。
+pub async fn deduplicate<F>(handler: F) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let store = DEDUPLICATION_STORE.try_with(Clone::clone).ok().flatten();
+    let (Some(store), Some(context)) = (store, ConsumerContext::current()) else {
+        return handler.await;
+    };
+    let Some(message_id) = context.header(MESSAGE_ID_HEADER) else {
+        return handler.await;
+    };
+    if !store.insert(&context.topic, message_id).await? {
+        tracing::debug!(
+            "Skipping duplicate message {message_id} from {}",
+            context.topic
+        );
+        return Ok(());
+    }
+    let result = handler.await;
+    if result.is_err() {
+        if let Err(e) = store.remove(&context.topic, message_id).await {
+            tracing::error!(
+                "Unable to remove message {message_id} from {} after failing: {e}",
+                context.topic
+            );
+        }
+    }
+    result
+}
+
+/// 把处理函数包装为幂等的处理函数，见 [`deduplicate`]
+pub fn idempotent_handler<SP>(handler: MessageHandler<SP>) -> MessageHandler<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    Arc::new(move |message: &Message, sp: Arc<SP>| -> ConsumerReturn {
+        Box::pin(deduplicate(handler(message, sp)))
+    })
+}
+
+/// 内存中的去重记录，超过容量时淘汰最久没有访问的记录，超过 `ttl` 的记录视为不存在
+///
+/// 记录只在当前进程中有效，多个实例或者需要在重启后去重时使用 [`SeaOrmDeduplicationStore`]。
+pub struct MemoryDeduplicationStore {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<LruEntries>,
+}
+
+/// Processed messages with their insertion time and last access, and the keys ordered by access.
+#[derive(Default)]
+struct LruEntries {
+    entries: HashMap<(String, String), (Instant, u64)>,
+    accesses: BTreeMap<u64, (String, String)>,
+    next_access: u64,
+}
+
+impl LruEntries {
+    fn touch(&mut self, key: &(String, String)) {
+        let access = self.next_access;
+        self.next_access += 1;
+        if let Some((_, last_access)) = self.entries.get_mut(key) {
+            self.accesses.remove(last_access);
+            *last_access = access;
+            self.accesses.insert(access, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &(String, String)) {
+        if let Some((_, last_access)) = self.entries.remove(key) {
+            self.accesses.remove(&last_access);
+        }
+    }
+}
+
+impl MemoryDeduplicationStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DeduplicationStore for MemoryDeduplicationStore {
+    async fn insert(&self, topic: &str, message_id: &str) -> anyhow::Result<bool> {
+        let key = (topic.to_string(), message_id.to_string());
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((inserted_at, _)) = entries.entries.get(&key) {
+            if inserted_at.elapsed() < self.ttl {
+                entries.touch(&key);
+                return Ok(false);
+            }
+        }
+        entries.remove(&key);
+        while entries.entries.len() >= self.capacity.max(1) {
+            let Some((_, oldest)) = entries.accesses.pop_first() else {
+                break;
+            };
+            entries.entries.remove(&oldest);
+        }
+        let access = entries.next_access;
+        entries.next_access += 1;
+        entries.accesses.insert(access, key.clone());
+        entries.entries.insert(key, (Instant::now(), access));
+        Ok(true)
+    }
+
+    async fn remove(&self, topic: &str, message_id: &str) -> anyhow::Result<()> {
+        let key = (topic.to_string(), message_id.to_string());
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).remove(&key);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sea-orm-db")]
+pub use self::sea_orm_store::*;
+
+#[cfg(feature = "sea-orm-db")]
+mod sea_orm_store {
+    use std::time::Duration;
+
+    use sea_orm::{ConnectionTrait, DatabaseConnection, Statement};
+
+    use super::DeduplicationStore;
+
+    /// 保存在数据库表中的去重记录，多个实例共享，重启后仍然有效
+    ///
+    /// 表通过 [`SeaOrmDeduplicationStore::create_table`] 创建，SQL 为 PostgreSQL 语法。
+    /// 超过 `ttl` 的记录视为不存在，通过 [`SeaOrmDeduplicationStore::purge`] 删除。
+    pub struct SeaOrmDeduplicationStore {
+        connection: DatabaseConnection,
+        table: String,
+        ttl: Duration,
+    }
+
+    impl SeaOrmDeduplicationStore {
+        pub fn new(connection: DatabaseConnection, ttl: Duration) -> Self {
+            Self {
+                connection,
+                table: String::from("processed_messages"),
+                ttl,
+            }
+        }
+
+        /// 记录所在的表，默认为 `processed_messages`
+        pub fn table(mut self, table: &str) -> Self {
+            self.table = table.to_string();
+            self
+        }
+
+        pub async fn create_table(&self) -> anyhow::Result<()> {
+            let sql = format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                    topic TEXT NOT NULL, \
+                    message_id TEXT NOT NULL, \
+                    processed_at TIMESTAMPTZ NOT NULL DEFAULT now(), \
+                    PRIMARY KEY (topic, message_id))",
+                self.table
+            );
+            self.execute(&sql, vec![]).await
+        }
+
+        /// 删除超过 `ttl` 的记录
+        pub async fn purge(&self) -> anyhow::Result<()> {
+            let sql = format!(
+                "DELETE FROM {} WHERE processed_at < now() - make_interval(secs => $1)",
+                self.table
+            );
+            self.execute(&sql, vec![self.ttl.as_secs_f64().into()]).await
+        }
+
+        async fn execute(&self, sql: &str, values: Vec<sea_orm::Value>) -> anyhow::Result<()> {
+            let backend = self.connection.get_database_backend();
+            self.connection
+                .execute(Statement::from_sql_and_values(backend, sql, values))
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DeduplicationStore for SeaOrmDeduplicationStore {
+        async fn insert(&self, topic: &str, message_id: &str) -> anyhow::Result<bool> {
+            // The primary key makes the check and the insert atomic, an expired record is
+            // replaced as if it didn't exist.
+            let sql = format!(
+                "INSERT INTO {} AS existing (topic, message_id, processed_at) \
+                    VALUES ($1, $2, now()) \
+                    ON CONFLICT (topic, message_id) DO UPDATE SET processed_at = now() \
+                    WHERE existing.processed_at < now() - make_interval(secs => $3) \
+                    RETURNING 1",
+                self.table
+            );
+            let statement = Statement::from_sql_and_values(
+                self.connection.get_database_backend(),
+                sql,
+                vec![
+                    topic.into(),
+                    message_id.into(),
+                    self.ttl.as_secs_f64().into(),
+                ],
+            );
+            Ok(self.connection.query_one(statement).await?.is_some())
+        }
+
+        async fn remove(&self, topic: &str, message_id: &str) -> anyhow::Result<()> {
+            let sql = format!(
+                "DELETE FROM {} WHERE topic = $1 AND message_id = $2",
+                self.table
+            );
+            self.execute(&sql, vec![topic.into(), message_id.into()]).await
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::{BTreeMap, HashSet};
+        use std::sync::{Arc, Mutex};
+
+        use sea_orm::{
+            Database, DatabaseBackend, DbErr, ProxyDatabaseTrait, ProxyExecResult, ProxyRow, Value,
+        };
+
+        use super::*;
+
+        /// Keeps the records in memory like the primary key of the table, ignoring the ttl.
+        #[derive(Debug, Default)]
+        struct Table(Arc<Mutex<HashSet<String>>>);
+
+        fn key(statement: &Statement) -> String {
+            format!("{:?}", &statement.values.as_ref().unwrap().0[..2])
+        }
+
+        impl ProxyDatabaseTrait for Table {
+            fn query(&self, statement: Statement) -> Result<Vec<ProxyRow>, DbErr> {
+                assert!(statement.sql.starts_with("INSERT INTO handled AS existing"));
+                assert!(statement.sql.contains("ON CONFLICT (topic, message_id)"));
+                if !self.0.lock().unwrap().insert(key(&statement)) {
+                    return Ok(Vec::new());
+                }
+                let row = BTreeMap::from([(String::from("?column?"), Value::Int(Some(1)))]);
+                Ok(vec![ProxyRow::new(row)])
+            }
+
+            fn execute(&self, statement: Statement) -> Result<ProxyExecResult, DbErr> {
+                assert!(statement.sql.starts_with("DELETE FROM handled WHERE"));
+                let removed = self.0.lock().unwrap().remove(&key(&statement));
+                Ok(ProxyExecResult {
+                    last_insert_id: 0,
+                    rows_affected: u64::from(removed),
+                })
+            }
+        }
+
+        #[tokio::test]
+        async fn records_are_inserted_only_if_absent() {
+            let table = Table::default();
+            let records = table.0.clone();
+            let proxy: Box<dyn ProxyDatabaseTrait> = Box::new(table);
+            let connection =
+                Database::connect_proxy(DatabaseBackend::Postgres, Arc::new(Mutex::new(proxy)))
+                    .await
+                    .unwrap();
+            let store =
+                SeaOrmDeduplicationStore::new(connection, Duration::from_secs(60)).table("handled");
+
+            assert!(store.insert("orders", "1").await.unwrap());
+            assert!(!store.insert("orders", "1").await.unwrap());
+            assert!(store.insert("payments", "1").await.unwrap());
+            store.remove("orders", "1").await.unwrap();
+            assert_eq!(records.lock().unwrap().len(), 1);
+            assert!(store.insert("orders", "1").await.unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_entries() {
+        let store = MemoryDeduplicationStore::new(2, Duration::from_secs(60));
+        assert!(store.insert("orders", "1").await.unwrap());
+        assert!(store.insert("orders", "2").await.unwrap());
+        // Seeing "1" again makes "2" the least recently used one.
+        assert!(!store.insert("orders", "1").await.unwrap());
+        assert!(store.insert("orders", "3").await.unwrap());
+
+        assert!(!store.insert("orders", "1").await.unwrap());
+        assert!(store.insert("orders", "2").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_and_removed_entries_are_forgotten() {
+        let store = MemoryDeduplicationStore::new(10, Duration::from_millis(20));
+        assert!(store.insert("orders", "1").await.unwrap());
+        assert!(!store.insert("orders", "1").await.unwrap());
+        assert!(store.insert("payments", "1").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(store.insert("orders", "1").await.unwrap());
+        store.remove("orders", "1").await.unwrap();
+        assert!(store.insert("orders", "1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn idempotent_handlers_skip_processed_messages() {
+        let store: Arc<dyn DeduplicationStore> =
+            Arc::new(MemoryDeduplicationStore::new(10, Duration::from_secs(60)));
+        let handler = idempotent_handler(Arc::new(
+            |_: &Message, calls: Arc<AtomicUsize>| -> ConsumerReturn {
+                Box::pin(async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                })
+            },
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let message = |id: Option<&str>| Message {
+            topic: String::from("orders"),
+            payload: Vec::new(),
+            headers: id
+                .map(|id| HashMap::from([(MESSAGE_ID_HEADER.to_string(), id.to_string())]))
+                .unwrap_or_default(),
+        };
+        for message in [
+            message(Some("1")),
+            message(Some("1")),
+            message(Some("2")),
+            message(None),
+            message(None),
+        ] {
+            let handled = ConsumerContext::scope(&message, handler(&message, calls.clone()));
+            scope(Some(store.clone()), handled).await.unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    async fn handle_twice(handler: MessageHandler<AtomicUsize>) -> (usize, [bool; 2]) {
+        let store: Arc<dyn DeduplicationStore> =
+            Arc::new(MemoryDeduplicationStore::new(10, Duration::from_secs(60)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let message = Message {
+            topic: String::from("orders"),
+            payload: Vec::new(),
+            headers: HashMap::from([(MESSAGE_ID_HEADER.to_string(), String::from("1"))]),
+        };
+        let handle = || {
+            let handled = ConsumerContext::scope(&message, handler(&message, calls.clone()));
+            scope(Some(store.clone()), handled)
+        };
+        let (first, second) = tokio::join!(handle(), handle());
+        (
+            calls.load(Ordering::SeqCst),
+            [first.is_ok(), second.is_ok()],
+        )
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicates_are_handled_once() {
+        let handler = idempotent_handler(Arc::new(
+            |_: &Message, calls: Arc<AtomicUsize>| -> ConsumerReturn {
+                Box::pin(async move {
+                    // Lets the duplicate start while the message is being handled.
+                    tokio::task::yield_now().await;
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                })
+            },
+        ));
+        assert_eq!(handle_twice(handler).await, (1, [true, true]));
+    }
+
+    #[tokio::test]
+    async fn failed_messages_are_handled_again() {
+        let handler = idempotent_handler(Arc::new(
+            |_: &Message, calls: Arc<AtomicUsize>| -> ConsumerReturn {
+                Box::pin(async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err(anyhow::anyhow!("unavailable")),
+                        _ => Ok(()),
+                    }
+                })
+            },
+        ));
+        assert_eq!(handle_twice(handler).await, (2, [false, true]));
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::future::{ready, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use alice_architecture::message_queue::producer::MessageQueueProducer;
+use serde::de::DeserializeOwned;
+use tokio::runtime::Handle;
+use tracing::Instrument;
+
+use super::metrics::metrics;
+use super::{
+    decode_message, deduplication, most_specific_pattern, BatchHandler, ConsumerContext,
+    DeduplicationStore,
+};
+
+pub type ConsumerReturn<'async_fn> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'async_fn>>;
+pub type ConsumerFn<SP> = for<'async_fn> fn(content: &'async_fn str, sp: Arc<SP>) -> ConsumerReturn;
+
+/// 消费者收到的消息
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub headers: HashMap<String, String>,
+}
+
+impl Message {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+}
+
+/// 类型擦除后的消息处理函数
+pub type MessageHandler<SP> =
+    Arc<dyn for<'a> Fn(&'a Message, Arc<SP>) -> ConsumerReturn<'a> + Send + Sync>;
+
+/// 把以字符串接收消息的处理函数转换为 [`MessageHandler`]，非 UTF-8 的消息作为解码错误处理
+pub fn consumer_fn_handler<SP>(handler: ConsumerFn<SP>) -> MessageHandler<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    Arc::new(move |message: &Message, sp: Arc<SP>| -> ConsumerReturn {
+        match std::str::from_utf8(&message.payload) {
+            Ok(content) => handler(content, sp),
+            Err(e) => Box::pin(ready(Err(DecodeError::new(&message.topic, e).into()))),
+        }
+    })
+}
+
+/// 把以 `T` 接收消息的处理函数转换为 [`MessageHandler`]
+///
+/// 按消息头中的编码格式反序列化，失败时作为解码错误处理。
+pub fn typed_handler<T, SP, F, Fut>(handler: F) -> MessageHandler<SP>
+where
+    T: DeserializeOwned + 'static,
+    SP: Send + Sync + 'static,
+    F: Fn(T, Arc<SP>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + 'static,
+{
+    Arc::new(move |message: &Message, sp: Arc<SP>| -> ConsumerReturn {
+        match decode_message::<T>(message) {
+            Ok(content) => Box::pin(handler(content, sp)),
+            Err(e) => Box::pin(ready(Err(DecodeError::new(&message.topic, e).into()))),
+        }
+    })
+}
+
+/// 消息无法解码为处理函数需要的类型，重试没有意义，直接进入死信队列
+#[derive(Debug, thiserror::Error)]
+#[error("Unable to decode message from {topic}: {source}")]
+pub struct DecodeError {
+    pub topic: String,
+    #[source]
+    pub source: anyhow::Error,
+}
+
+impl DecodeError {
+    pub fn new(topic: &str, source: impl Into<anyhow::Error>) -> Self {
+        Self {
+            topic: topic.to_string(),
+            source: source.into(),
+        }
+    }
+}
+
+/// 处理失败时的重试策略
+#[derive(Debug, Clone, Default)]
+pub struct RetryPolicy {
+    /// 最大重试次数，默认不重试
+    pub max_retries: u32,
+    /// 每次重试前的等待时间
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, backoff: Duration) -> Self {
+        Self {
+            max_retries,
+            backoff,
+        }
+    }
+}
+
+/// 重试后仍然失败的消息被发送到 `{topic}{topic_suffix}`
+#[derive(Clone)]
+pub struct DeadLetterQueue {
+    pub producer: Arc<dyn MessageQueueProducer>,
+    pub topic_suffix: String,
+}
+
+impl DeadLetterQueue {
+    pub fn new(producer: Arc<dyn MessageQueueProducer>) -> Self {
+        Self {
+            producer,
+            topic_suffix: String::from(".dlq"),
+        }
+    }
+
+    pub fn topic_suffix(mut self, topic_suffix: &str) -> Self {
+        self.topic_suffix = topic_suffix.to_string();
+        self
+    }
+}
+
+/// 消费者共用的消息分发逻辑：按主题查找处理函数，失败时重试，最终失败的消息进入死信队列
+pub(crate) struct MessageDispatcher<SP> {
+    name: &'static str,
+    service_provider: Arc<SP>,
+    handlers: HashMap<String, MessageHandler<SP>>,
+    batch_handlers: HashMap<String, BatchHandler<SP>>,
+    retry_policy: RetryPolicy,
+    dead_letter_queue: Option<DeadLetterQueue>,
+    deduplication_store: Option<Arc<dyn DeduplicationStore>>,
+}
+
+impl<SP> MessageDispatcher<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    pub fn new(name: &'static str, service_provider: Arc<SP>) -> Self {
+        Self {
+            name,
+            service_provider,
+            handlers: HashMap::new(),
+            batch_handlers: HashMap::new(),
+            retry_policy: RetryPolicy::default(),
+            dead_letter_queue: None,
+            deduplication_store: None,
+        }
+    }
+
+    #[cfg_attr(not(feature = "kafka-mq"), allow(dead_code))]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn insert(&mut self, topic: &str, handler: MessageHandler<SP>) {
+        self.handlers.insert(topic.to_string(), handler);
+    }
+
+    pub fn insert_batch(&mut self, topic: &str, handler: BatchHandler<SP>) {
+        self.batch_handlers.insert(topic.to_string(), handler);
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn set_dead_letter_queue(&mut self, dead_letter_queue: DeadLetterQueue) {
+        self.dead_letter_queue = Some(dead_letter_queue);
+    }
+
+    pub fn set_deduplication_store(&mut self, deduplication_store: Arc<dyn DeduplicationStore>) {
+        self.deduplication_store = Some(deduplication_store);
+    }
+
+    #[cfg_attr(not(feature = "kafka-mq"), allow(dead_code))]
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.handlers.keys().chain(self.batch_handlers.keys())
+    }
+
+    /// 交给订阅了该主题的处理函数处理，没有完全相同的主题时交给最具体的匹配模式，见 [`most_specific_pattern`]
+    pub fn dispatch(&self, message: &Message) {
+        match find_handler(&self.handlers, &message.topic) {
+            Some(handler) => self.handle(handler, message),
+            None => {
+                metrics().error(self.name, &message.topic, "no_handler");
+                tracing::error!("No such service: {}", message.topic);
+            }
+        }
+    }
+
+    /// 交给指定的处理函数处理，在处理完成前阻塞当前消费者
+    pub fn handle(&self, handler: &MessageHandler<SP>, message: &Message) {
+        let future = async {
+            let sp = &self.service_provider;
+            let result =
+                self.call_with_retry(&message.topic, 1, || handler(message, sp.clone())).await;
+            if let Err(e) = result {
+                tracing::error!("Handling message from {} failed: {e}", message.topic);
+                self.send_to_dead_letter_queue(message).await;
+            }
+        };
+        let future = deduplication::scope(self.deduplication_store.clone(), future);
+        self.block_on(ConsumerContext::scope(message, future));
+    }
+
+    /// 把同一主题的一批消息交给订阅了该主题的批处理函数处理
+    ///
+    /// 处理成功或者失败后全部进入死信队列时返回 `true`；没有处理函数、或者处理失败且没有进入死信队列时
+    /// 返回 `false`，这时调用者不应提交这批消息。
+    pub fn dispatch_batch(&self, topic: &str, messages: &[Message]) -> bool {
+        let handler = match find_handler(&self.batch_handlers, topic) {
+            Some(handler) => handler,
+            None => {
+                metrics().error(self.name, topic, "no_handler");
+                tracing::error!("No such service: {topic}");
+                return false;
+            }
+        };
+        self.block_on(async {
+            let sp = &self.service_provider;
+            let count = messages.len() as u64;
+            let result = self.call_with_retry(topic, count, || handler(messages, sp.clone())).await;
+            let Err(e) = result else {
+                return true;
+            };
+            tracing::error!(
+                "Handling {} messages from {topic} failed: {e}",
+                messages.len()
+            );
+            let mut dead_lettered = true;
+            for message in messages {
+                dead_lettered &= self.send_to_dead_letter_queue(message).await;
+            }
+            dead_lettered
+        })
+    }
+
+    fn block_on<T>(&self, future: impl Future<Output = T>) -> T {
+        tokio::task::block_in_place(|| {
+            Handle::current().block_on(
+                future.instrument(tracing::trace_span!("message_queue", consumer = self.name)),
+            )
+        })
+    }
+
+    /// Calls the handler until it succeeds or runs out of retries, and records the metrics.
+    async fn call_with_retry<'a>(
+        &self,
+        topic: &str,
+        count: u64,
+        call: impl Fn() -> ConsumerReturn<'a>,
+    ) -> anyhow::Result<()> {
+        let metrics = metrics();
+        metrics.consumed(self.name, topic, count);
+        let started_at = Instant::now();
+        let result = self.retry(topic, call).await;
+        metrics.handled(self.name, topic, started_at.elapsed(), result.is_ok());
+        if let Err(e) = &result {
+            let error_type = if e.is::<DecodeError>() {
+                "decode"
+            } else {
+                "handler"
+            };
+            metrics.error(self.name, topic, error_type);
+        }
+        result
+    }
+
+    async fn retry<'a>(
+        &self,
+        topic: &str,
+        call: impl Fn() -> ConsumerReturn<'a>,
+    ) -> anyhow::Result<()> {
+        let mut retries = 0;
+        loop {
+            let e = match call().await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if e.is::<DecodeError>() || retries >= self.retry_policy.max_retries {
+                return Err(e);
+            }
+            retries += 1;
+            tracing::warn!("Handling message from {topic} failed, retry {retries}: {e}");
+            tokio::time::sleep(self.retry_policy.backoff).await;
+        }
+    }
+
+    /// Returns whether the message was sent to the dead letter queue.
+    async fn send_to_dead_letter_queue(&self, message: &Message) -> bool {
+        let dead_letter_queue = match &self.dead_letter_queue {
+            Some(d) => d,
+            None => return false,
+        };
+        let topic = format!("{}{}", message.topic, dead_letter_queue.topic_suffix);
+        let result = dead_letter_queue
+            .producer
+            .send_with_headers(&message.payload, &topic, &message.headers)
+            .await;
+        if let Err(e) = &result {
+            tracing::error!("Unable to send message to dead letter queue {topic}: {e}");
+        }
+        result.is_ok()
+    }
+}
+
+fn find_handler<'a, H>(handlers: &'a HashMap<String, H>, topic: &str) -> Option<&'a H> {
+    handlers.get(topic).or_else(|| {
+        let pattern = most_specific_pattern(handlers.keys().map(String::as_str), topic)?;
+        handlers.get(pattern)
+    })
+}
@@ -1,39 +1,115 @@
 use std::collections::HashMap;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Arc;
+use std::future::{poll_fn, Future};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockWriteGuard};
+use std::time::SystemTime;
 
 use alice_architecture::background_service::BackgroundService;
+use alice_architecture::message_queue::consumer::MessageQueueConsumer;
 use alice_architecture::message_queue::producer::{
-    MessageQueueProducer, MessageQueueProducerTemplate,
+    DelayedMessageQueueProducer, MessageQueueProducer, MessageQueueProducerTemplate,
 };
-use tokio::runtime::Handle;
-use tracing::Instrument;
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::time::DelayQueue;
 
-pub type ConsumerReturn<'async_fn> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'async_fn>>;
-pub type ConsumerFn<SP> = for<'async_fn> fn(content: &'async_fn str, sp: Arc<SP>) -> ConsumerReturn;
+use crate::config::{InternalQueueConfig, OverflowPolicy};
+
+use super::metrics::metrics;
+use super::write_ahead_log::{Receipt, ReplayedMessages, WriteAheadLog};
+use super::{
+    batch_consumer_fn_handler, consumer_fn_handler, new_message_id, topic_matches,
+    typed_batch_handler, typed_handler, wait_deadline, BatchConsumerFn, BatchOptions, Batches,
+    ConsumerControl, ConsumerFn, DeadLetterQueue, DeduplicationStore, Message, MessageCodec,
+    MessageCodecs, MessageDispatcher, MessageHandler, RetryPolicy, MESSAGE_ID_HEADER,
+};
 
 #[derive(Debug, Clone)]
 pub struct InternalMessage {
     pub target: String,
-    pub body: String,
+    pub body: Vec<u8>,
+    pub headers: HashMap<String, String>,
+    /// 启用写前日志时的回执，消息处理完成后确认
+    pub receipt: Option<Receipt>,
 }
 
-pub struct InternalMessageQueueProducer {
-    receiver: flume::Receiver<InternalMessage>,
+impl InternalMessage {
+    pub fn new(target: &str, body: Vec<u8>, headers: HashMap<String, String>) -> Self {
+        Self {
+            target: target.to_string(),
+            body,
+            headers,
+            receipt: None,
+        }
+    }
+
+    /// 确认消息已经处理完成，不再需要在重启后重新投递
+    pub fn ack(&self) {
+        if let Some(receipt) = &self.receipt {
+            receipt.ack();
+        }
+    }
+}
+
+impl From<InternalMessage> for Message {
+    fn from(message: InternalMessage) -> Self {
+        Self {
+            topic: message.target,
+            payload: message.body,
+            headers: message.headers,
+        }
+    }
+}
+
+/// 有界队列已满，并且溢出策略为 [`OverflowPolicy::Error`]
+#[derive(Debug, thiserror::Error)]
+#[error("Internal message queue is full, capacity: {capacity}")]
+pub struct QueueFullError {
+    pub capacity: usize,
+}
+
+/// 默认订阅组，订阅所有主题，在第一次调用 [`InternalMessageQueueProducer::get_receiver`] 时创建
+pub const DEFAULT_GROUP: &str = "default";
+
+/// 订阅组：组内的消费者共用一个通道，分担该组收到的消息
+struct SubscriberGroup {
+    patterns: Vec<String>,
     sender: flume::Sender<InternalMessage>,
+    receiver: flume::Receiver<InternalMessage>,
+}
+
+impl SubscriberGroup {
+    fn matches(&self, topic: &str) -> bool {
+        self.patterns.iter().any(|pattern| topic_matches(pattern, topic))
+    }
+}
+
+/// A delayed message and the time it becomes due.
+type ScheduledMessage = (Instant, InternalMessage);
+
+/// 内部消息队列
+///
+/// 每个订阅组都会收到匹配其订阅模式的所有消息，组内的多个消费者分担消息。
+pub struct InternalMessageQueueProducer {
+    groups: Arc<RwLock<HashMap<String, SubscriberGroup>>>,
+    capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    write_ahead_log: Option<Arc<WriteAheadLog>>,
+    /// Messages from the write ahead log, delivered once their groups subscribe.
+    replayed: Mutex<ReplayedMessages>,
+    codecs: MessageCodecs,
+    scheduler: OnceLock<flume::Sender<ScheduledMessage>>,
 }
 
 #[async_trait::async_trait]
 impl MessageQueueProducer for InternalMessageQueueProducer {
-    async fn send(&self, content: &str, topic:&str) -> anyhow::Result<()> {
-        Ok(self
-            .sender
-            .send_async(InternalMessage {
-                target: topic.to_string(),
-                body: content.to_string(),
-            })
-            .await?)
+    async fn send_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.enqueue(topic, content.to_vec(), headers.clone()).await
     }
 }
 
@@ -43,13 +119,32 @@ where
     T: serde::Serialize + Send + Sync,
 {
     async fn send_object(&self, content: &T, topic: &str) -> anyhow::Result<()> {
-        Ok(self
-            .sender
-            .send_async(InternalMessage {
-                target: topic.to_string(),
-                body: serde_json::to_string(content)?,
-            })
-            .await?)
+        let codec = self.codecs.for_topic(topic);
+        self.enqueue(topic, codec.encode(content)?, codec.headers()).await
+    }
+}
+
+/// 延迟消息保存在时间轮中，到期后才进入订阅组的通道
+///
+/// 延迟消息在到期前只保存在内存中，不写入写前日志，进程退出时没有到期的消息会丢失。
+#[async_trait::async_trait]
+impl DelayedMessageQueueProducer for InternalMessageQueueProducer {
+    async fn send_at_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        deliver_at: SystemTime,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let delay = match deliver_at.duration_since(SystemTime::now()) {
+            Ok(delay) if !delay.is_zero() => delay,
+            _ => return self.enqueue(topic, content.to_vec(), headers.clone()).await,
+        };
+        let message = InternalMessage::new(topic, content.to_vec(), headers.clone());
+        self.scheduler
+            .get_or_init(|| self.spawn_scheduler())
+            .send((Instant::now() + delay, message))
+            .map_err(|_| anyhow::anyhow!("Internal message queue scheduler stopped"))
     }
 }
 
@@ -61,15 +156,281 @@ impl Default for InternalMessageQueueProducer {
 
 impl InternalMessageQueueProducer {
     pub fn new() -> Self {
-        let (sender, receiver): (
-            flume::Sender<InternalMessage>,
-            flume::Receiver<InternalMessage>,
-        ) = flume::unbounded();
-        Self { sender, receiver }
+        Self::with_capacity(None, OverflowPolicy::default(), None)
+    }
+
+    /// 有界队列，每个订阅组的通道最多容纳 `capacity` 条消息，已满时按 `overflow_policy` 处理
+    pub fn bounded(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Self::with_capacity(Some(capacity), overflow_policy, None)
+    }
+
+    /// 按配置创建队列
+    ///
+    /// 配置了写前日志时，上次没有处理完的消息在其订阅组创建时重新投递到该组；
+    /// 一个组重新投递的消息多于容量时，只有该组的通道容量扩大到能够容纳这些消息。
+    pub fn with_config(config: &InternalQueueConfig) -> anyhow::Result<Self> {
+        let write_ahead_log = config
+            .write_ahead_log
+            .as_ref()
+            .map(|path| WriteAheadLog::open(path, config.write_ahead_log_sync))
+            .transpose()?;
+        Ok(Self::with_capacity(
+            config.capacity,
+            config.overflow_policy,
+            write_ahead_log,
+        ))
     }
 
+    fn with_capacity(
+        capacity: Option<usize>,
+        overflow_policy: OverflowPolicy,
+        write_ahead_log: Option<(Arc<WriteAheadLog>, ReplayedMessages)>,
+    ) -> Self {
+        let groups: Arc<RwLock<HashMap<String, SubscriberGroup>>> = Arc::default();
+        let weak_groups = Arc::downgrade(&groups);
+        metrics().register_queue_depth(move || {
+            let groups = weak_groups.upgrade()?;
+            let groups = groups.read().unwrap_or_else(|e| e.into_inner());
+            Some(
+                groups
+                    .iter()
+                    .map(|(name, group)| (name.clone(), group.receiver.len()))
+                    .collect(),
+            )
+        });
+        let (write_ahead_log, replayed) = write_ahead_log.unzip();
+        Self {
+            groups,
+            capacity,
+            overflow_policy,
+            write_ahead_log,
+            replayed: Mutex::new(replayed.unwrap_or_default()),
+            codecs: MessageCodecs::default(),
+            scheduler: OnceLock::new(),
+        }
+    }
+
+    /// `send_object` 默认使用的编码格式，默认为 JSON
+    pub fn codec(mut self, codec: MessageCodec) -> Self {
+        self.codecs.set_default(codec);
+        self
+    }
+
+    /// 为主题单独指定 `send_object` 使用的编码格式
+    pub fn topic_codec(mut self, topic: &str, codec: MessageCodec) -> Self {
+        self.codecs.set_topic(topic, codec);
+        self
+    }
+
+    /// 默认订阅组的接收端，收到所有主题的消息
+    ///
+    /// 默认订阅组在第一次调用时创建，此前发送的消息不会进入该组。
     pub fn get_receiver(&self) -> flume::Receiver<InternalMessage> {
-        self.receiver.clone()
+        self.subscribe(DEFAULT_GROUP, "#")
+    }
+
+    /// 为订阅组增加订阅模式，返回该组的接收端
+    ///
+    /// 同一个组多次订阅时返回同一个接收端，组内的消费者分担消息；不同的组各自收到所有匹配的消息。
+    /// 模式的写法见 [`topic_matches`]。
+    pub fn subscribe(&self, group: &str, pattern: &str) -> flume::Receiver<InternalMessage> {
+        let mut groups = self.write_groups();
+        let subscriber_group =
+            groups.entry(group.to_string()).or_insert_with(|| self.new_group(group));
+        if !subscriber_group.patterns.iter().any(|p| p == pattern) {
+            subscriber_group.patterns.push(pattern.to_string());
+        }
+        subscriber_group.receiver.clone()
+    }
+
+    /// 移除订阅组，该组不再收到新的消息
+    ///
+    /// 该组没有消费者时，已经在组内的消息被丢弃，不再在重启后重新投递；
+    /// 重启后还没有重新订阅的组，其等待重新投递的消息同样被丢弃。
+    pub fn unsubscribe(&self, group: &str) {
+        let dropped = match self.write_groups().remove(group) {
+            // The group holds one receiver itself, any other belongs to a consumer.
+            Some(subscriber_group) if subscriber_group.receiver.receiver_count() == 1 => {
+                subscriber_group.receiver.drain().collect()
+            }
+            Some(_) => Vec::new(),
+            None => self
+                .replayed
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(group)
+                .unwrap_or_default(),
+        };
+        dropped.iter().for_each(InternalMessage::ack);
+        if !dropped.is_empty() {
+            tracing::warn!(
+                "Dropping {} messages of unsubscribed group {group}",
+                dropped.len()
+            );
+        }
+    }
+
+    /// Creates the channel of a group, with the messages replayed for it already queued.
+    fn new_group(&self, group: &str) -> SubscriberGroup {
+        let replayed = self
+            .replayed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(group)
+            .unwrap_or_default();
+        let (sender, receiver) = match self.capacity {
+            Some(capacity) if capacity < replayed.len() => {
+                tracing::warn!(
+                    "Replaying {} messages into group {group} with capacity {capacity}",
+                    replayed.len()
+                );
+                flume::bounded(replayed.len())
+            }
+            Some(capacity) => flume::bounded(capacity),
+            None => flume::unbounded(),
+        };
+        for message in replayed {
+            // The channel has room for all of them and the group holds a receiver.
+            let _ = sender.try_send(message);
+        }
+        SubscriberGroup {
+            patterns: Vec::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Starts the task that holds the delayed messages until they are due.
+    ///
+    /// The task enqueues through its own handle of the queue, so it keeps delivering the scheduled
+    /// messages after the producer is dropped and stops once none is left.
+    fn spawn_scheduler(&self) -> flume::Sender<ScheduledMessage> {
+        let (sender, receiver) = flume::unbounded::<ScheduledMessage>();
+        let producer = Self {
+            groups: self.groups.clone(),
+            capacity: self.capacity,
+            overflow_policy: self.overflow_policy,
+            write_ahead_log: self.write_ahead_log.clone(),
+            replayed: Mutex::default(),
+            codecs: self.codecs.clone(),
+            scheduler: OnceLock::new(),
+        };
+        tokio::spawn(async move {
+            let mut delay_queue = DelayQueue::new();
+            let mut disconnected = false;
+            loop {
+                let message = tokio::select! {
+                    scheduled = receiver.recv_async(), if !disconnected => {
+                        match scheduled {
+                            Ok((deliver_at, message)) => {
+                                delay_queue.insert_at(message, deliver_at);
+                            }
+                            Err(_) => disconnected = true,
+                        }
+                        continue;
+                    }
+                    Some(expired) = poll_fn(|cx| delay_queue.poll_expired(cx)),
+                        if !delay_queue.is_empty() => expired.into_inner(),
+                    else => break,
+                };
+                let topic = message.target.clone();
+                if let Err(e) = producer.enqueue(&topic, message.body, message.headers).await {
+                    tracing::error!("Unable to deliver delayed message to {topic}: {e}");
+                }
+            }
+        });
+        sender
+    }
+
+    async fn enqueue(
+        &self,
+        topic: &str,
+        body: Vec<u8>,
+        mut headers: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        headers.entry(MESSAGE_ID_HEADER.to_string()).or_insert_with(new_message_id);
+        let channels = self
+            .groups
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|(_, group)| group.matches(topic))
+            .map(|(name, group)| (name.clone(), group.sender.clone(), group.receiver.clone()))
+            .collect::<Vec<_>>();
+        let messages = match &self.write_ahead_log {
+            Some(write_ahead_log) => {
+                let groups = channels.iter().map(|(name, ..)| name.as_str()).collect::<Vec<_>>();
+                write_ahead_log.append(topic, body, headers, &groups)?
+            }
+            None => vec![InternalMessage::new(topic, body, headers); channels.len()],
+        };
+
+        let mut result = Ok(());
+        for ((_, sender, receiver), message) in channels.iter().zip(messages) {
+            if let Err(e) = self.deliver(sender, receiver, message).await {
+                let error_type = if e.is::<QueueFullError>() {
+                    "queue_full"
+                } else {
+                    "send"
+                };
+                metrics().error("internal", topic, error_type);
+                result = Err(e);
+            }
+        }
+        if result.is_ok() {
+            metrics().produced("internal", topic);
+        }
+        result
+    }
+
+    async fn deliver(
+        &self,
+        sender: &flume::Sender<InternalMessage>,
+        receiver: &flume::Receiver<InternalMessage>,
+        mut message: InternalMessage,
+    ) -> anyhow::Result<()> {
+        match self.overflow_policy {
+            OverflowPolicy::Block => {
+                if let Err(e) = sender.send_async(message).await {
+                    e.0.ack();
+                    anyhow::bail!("Internal message queue disconnected");
+                }
+            }
+            OverflowPolicy::Error => match sender.try_send(message) {
+                Ok(()) => {}
+                Err(e) => {
+                    let message = e.into_inner();
+                    message.ack();
+                    return Err(QueueFullError {
+                        capacity: sender.capacity().unwrap_or_default(),
+                    }
+                    .into());
+                }
+            },
+            OverflowPolicy::DropOldest => loop {
+                message = match sender.try_send(message) {
+                    Ok(()) => break,
+                    Err(flume::TrySendError::Full(message)) => message,
+                    Err(flume::TrySendError::Disconnected(message)) => {
+                        message.ack();
+                        anyhow::bail!("Internal message queue disconnected");
+                    }
+                };
+                if let Ok(oldest) = receiver.try_recv() {
+                    metrics().error("internal", &oldest.target, "dropped");
+                    tracing::warn!(
+                        "Internal message queue is full, dropping message to {}",
+                        oldest.target
+                    );
+                    oldest.ack();
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn write_groups(&self) -> RwLockWriteGuard<'_, HashMap<String, SubscriberGroup>> {
+        self.groups.write().unwrap_or_else(|e| e.into_inner())
     }
 }
 
@@ -78,8 +439,9 @@ where
     SP: Send + Sync + 'static,
 {
     receiver: flume::Receiver<InternalMessage>,
-    service_provider: Arc<SP>,
-    fn_mapper: HashMap<String, ConsumerFn<SP>>,
+    dispatcher: MessageDispatcher<SP>,
+    control: ConsumerControl,
+    shutdown: CancellationToken,
 }
 
 #[async_trait::async_trait]
@@ -88,47 +450,521 @@ where
     SP: Send + Sync + 'static,
 {
     async fn run(&self) {
+        let mut changes = self.control.subscribe();
+        // A message of a paused topic, nothing more is received until its topic is resumed.
+        let mut held = None;
         loop {
-            match self.receiver.recv_async().await {
-                Ok(message) => {
-                    tracing::debug!("message received: {message:#?}");
-                    match self.fn_mapper.get(&message.target) {
-                        Some(x) => {
-                            let sp = self.service_provider.clone();
-                            let x = *x;
-                            tokio::task::block_in_place(move || {
-                                Handle::current().block_on(
-                                    async move {
-                                        if let Err(e) = x(message.body.as_str(), sp.clone()).await {
-                                            tracing::error!("{e}")
-                                        }
-                                    }
-                                    .instrument(tracing::trace_span!("internal_message_queue")),
-                                )
-                            });
-                        }
-                        None => tracing::error!("No such service: {}", message.target),
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = changes.changed() => {
+                    if let Some(message) = release_held(&mut held, &self.control) {
+                        self.handle(message).await;
                     }
                 }
-                Err(e) => tracing::error!("{e}"),
+                message = self.receiver.recv_async(), if held.is_none() => match message {
+                    Ok(message) if self.control.is_paused(&message.target) => held = Some(message),
+                    Ok(message) => self.handle(message).await,
+                    Err(e) => {
+                        tracing::error!("{e}");
+                        break;
+                    }
+                },
             }
         }
+        // Messages already queued would be lost after exiting, so handle them before stopping.
+        let queued = self.receiver.try_iter().collect::<Vec<_>>();
+        for message in held.into_iter().chain(queued) {
+            self.handle(message).await;
+        }
+        tracing::info!("Internal message queue consumer stopped");
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl<SP> MessageQueueConsumer for InternalMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    type Handler = ConsumerFn<SP>;
+
+    fn add_topic(mut self, topic: &str, handler: Self::Handler) -> Self {
+        self.dispatcher.insert(topic, consumer_fn_handler(handler));
+        self
+    }
+
+    /// The internal message queue has no options, they are ignored.
+    fn add_option(self, _option_key: &str, _option_value: &str) -> Self {
+        self
     }
 }
 
 impl<SP> InternalMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    pub fn new(receiver: flume::Receiver<InternalMessage>, service_provider: Arc<SP>) -> Self {
+        Self {
+            receiver,
+            dispatcher: MessageDispatcher::new("internal_message_queue", service_provider),
+            control: ConsumerControl::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 订阅主题，消息反序列化为 `T` 后交给 `handler` 处理
+    pub fn register<T, F, Fut>(mut self, topic: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(T, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.dispatcher.insert(topic, typed_handler(handler));
+        self
+    }
+
+    /// 订阅主题，由 `handler` 直接处理原始消息
+    pub fn add_handler(mut self, topic: &str, handler: MessageHandler<SP>) -> Self {
+        self.dispatcher.insert(topic, handler);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.dispatcher.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn dead_letter_queue(mut self, dead_letter_queue: DeadLetterQueue) -> Self {
+        self.dispatcher.set_dead_letter_queue(dead_letter_queue);
+        self
+    }
+
+    /// 幂等的处理函数通过 `store` 跳过已经处理过的消息
+    pub fn deduplication_store(mut self, store: Arc<dyn DeduplicationStore>) -> Self {
+        self.dispatcher.set_deduplication_store(store);
+        self
+    }
+
+    /// 暂停与限流的控制句柄
+    ///
+    /// 收到暂停主题的消息后消费者停止接收，直到该主题恢复，其余消息留在有界队列中形成背压；
+    /// 同一订阅组中其他主题的消息也随之等待，需要单独暂停的主题应使用单独的订阅组。
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
+
+    async fn handle(&self, message: InternalMessage) {
+        tracing::debug!("message received: {message:#?}");
+        self.control.throttle(&message.target).await;
+        let receipt = message.receipt.clone();
+        self.dispatcher.dispatch(&message.into());
+        if let Some(receipt) = receipt {
+            receipt.ack();
+        }
+    }
+}
+
+/// 批量消费者：同一主题的消息按 [`BatchOptions`] 攒成一批后交给批处理函数
+pub struct InternalBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    receiver: flume::Receiver<InternalMessage>,
+    dispatcher: MessageDispatcher<SP>,
+    options: BatchOptions,
+    control: ConsumerControl,
+    shutdown: CancellationToken,
+}
+
+#[async_trait::async_trait]
+impl<SP> BackgroundService for InternalBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    async fn run(&self) {
+        let mut batches = Batches::new(self.options.clone());
+        let mut changes = self.control.subscribe();
+        let mut held = None;
+        loop {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = wait_deadline(batches.next_deadline()) => {
+                    for (topic, messages) in batches.take_expired() {
+                        self.dispatch_batch(&topic, messages);
+                    }
+                }
+                _ = changes.changed() => {
+                    if let Some(message) = release_held(&mut held, &self.control) {
+                        self.push(&mut batches, message).await;
+                    }
+                }
+                message = self.receiver.recv_async(), if held.is_none() => match message {
+                    Ok(message) if self.control.is_paused(&message.target) => held = Some(message),
+                    Ok(message) => self.push(&mut batches, message).await,
+                    Err(e) => {
+                        tracing::error!("{e}");
+                        break;
+                    }
+                },
+            }
+        }
+        let queued = self.receiver.try_iter().collect::<Vec<_>>();
+        for message in held.into_iter().chain(queued) {
+            self.push(&mut batches, message).await;
+        }
+        for (topic, messages) in batches.take_all() {
+            self.dispatch_batch(&topic, messages);
+        }
+        tracing::info!("Internal batch message queue consumer stopped");
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl<SP> MessageQueueConsumer for InternalBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    type Handler = BatchConsumerFn<SP>;
+
+    fn add_topic(mut self, topic: &str, handler: Self::Handler) -> Self {
+        self.dispatcher.insert_batch(topic, batch_consumer_fn_handler(handler));
+        self
+    }
+
+    /// The internal message queue has no options, they are ignored.
+    fn add_option(self, _option_key: &str, _option_value: &str) -> Self {
+        self
+    }
+}
+
+impl<SP> InternalBatchMessageQueueConsumer<SP>
 where
     SP: Send + Sync + 'static,
 {
     pub fn new(
         receiver: flume::Receiver<InternalMessage>,
         service_provider: Arc<SP>,
-        fn_mapper: HashMap<String, ConsumerFn<SP>>,
+        options: BatchOptions,
     ) -> Self {
         Self {
             receiver,
-            service_provider,
-            fn_mapper,
+            dispatcher: MessageDispatcher::new("internal_batch_message_queue", service_provider),
+            options,
+            control: ConsumerControl::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 订阅主题，一批消息反序列化为 `Vec<T>` 后交给 `handler` 处理
+    pub fn register<T, F, Fut>(mut self, topic: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Vec<T>, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.dispatcher.insert_batch(topic, typed_batch_handler(handler));
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.dispatcher.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn dead_letter_queue(mut self, dead_letter_queue: DeadLetterQueue) -> Self {
+        self.dispatcher.set_dead_letter_queue(dead_letter_queue);
+        self
+    }
+
+    /// 暂停与限流的控制句柄，限流按消息计数
+    ///
+    /// 暂停的行为与 [`InternalMessageQueueConsumer::control`] 相同。
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
+
+    async fn push(&self, batches: &mut Batches<InternalMessage>, message: InternalMessage) {
+        let topic = message.target.clone();
+        self.control.throttle(&topic).await;
+        if let Some(messages) = batches.push(&topic, message) {
+            self.dispatch_batch(&topic, messages);
+        }
+    }
+
+    fn dispatch_batch(&self, topic: &str, messages: Vec<InternalMessage>) {
+        let receipts = messages.iter().filter_map(|m| m.receipt.clone()).collect::<Vec<_>>();
+        let messages = messages.into_iter().map(Message::from).collect::<Vec<_>>();
+        // Like the single message consumer, messages are acked even if they weren't handled, they
+        // only live in memory and the write-ahead log.
+        self.dispatcher.dispatch_batch(topic, &messages);
+        for receipt in receipts {
+            receipt.ack();
+        }
+    }
+}
+
+/// Takes out the held message once its topic is no longer paused.
+fn release_held(
+    held: &mut Option<InternalMessage>,
+    control: &ConsumerControl,
+) -> Option<InternalMessage> {
+    held.take_if(|message| !control.is_paused(&message.target))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::message_queue::ConsumerReturn;
+
+    #[derive(Default)]
+    struct Received(Mutex<Vec<String>>);
+
+    impl Received {
+        fn contents(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn record(content: &str, sp: Arc<Received>) -> ConsumerReturn<'_> {
+        sp.0.lock().unwrap().push(content.to_string());
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_topic_routes_messages_to_handler() {
+        let producer = InternalMessageQueueProducer::new();
+        let received = Arc::new(Received::default());
+        let consumer = InternalMessageQueueConsumer::new(producer.get_receiver(), received.clone())
+            .add_topics([("order.created".to_string(), record as ConsumerFn<Received>)])
+            .add_option("group.id", "ignored");
+        producer.send("created", "order.created").await.unwrap();
+        producer.send("deleted", "order.deleted").await.unwrap();
+
+        // Queued messages are handled before the stopped consumer exits.
+        consumer.stop().await;
+        consumer.run().await;
+        assert_eq!(received.contents(), ["created"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paused_topics_apply_backpressure() {
+        let producer = Arc::new(InternalMessageQueueProducer::bounded(
+            1,
+            OverflowPolicy::Block,
+        ));
+        let received = Arc::new(Received::default());
+        let consumer = Arc::new(
+            InternalMessageQueueConsumer::new(producer.subscribe("workers", "#"), received.clone())
+                .add_topics([("orders".to_string(), record as ConsumerFn<Received>)]),
+        );
+        let control = consumer.control();
+        control.pause("orders");
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+
+        // The consumer holds the first message and leaves the second in the queue.
+        producer.send("first", "orders").await.unwrap();
+        producer.send("second", "orders").await.unwrap();
+        let third = tokio::spawn({
+            let producer = producer.clone();
+            async move { producer.send("third", "orders").await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!third.is_finished());
+        assert!(received.contents().is_empty());
+
+        control.resume("orders");
+        third.await.unwrap().unwrap();
+        consumer.stop().await;
+        running.await.unwrap();
+        assert_eq!(received.contents(), ["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn every_group_receives_matching_messages() {
+        let producer = InternalMessageQueueProducer::new();
+        let orders = producer.subscribe("orders", "order.*");
+        let audit = producer.subscribe("audit", "#");
+        // Subscribing again shares the channel of the group.
+        let audit_worker = producer.subscribe("audit", "payment.#");
+        producer.send("created", "order.created").await.unwrap();
+        producer.send("paid", "payment.card.paid").await.unwrap();
+
+        assert_eq!(
+            orders.drain().map(|m| m.target).collect::<Vec<_>>(),
+            ["order.created"]
+        );
+        assert_eq!(audit.len(), 2);
+        assert_eq!(audit_worker.recv().unwrap().target, "order.created");
+        assert_eq!(audit.recv().unwrap().target, "payment.card.paid");
+    }
+
+    #[tokio::test]
+    async fn replays_write_ahead_log_into_subscribed_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = InternalQueueConfig {
+            capacity: Some(1),
+            write_ahead_log: Some(dir.path().join("queue.wal").display().to_string()),
+            ..Default::default()
+        };
+        {
+            let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+            let workers = producer.subscribe("workers", "orders.#");
+            producer.send("first", "orders.created").await.unwrap();
+            workers.recv().unwrap();
+            // Not acked before the restart.
         }
+
+        let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+        assert!(producer.get_receiver().is_empty());
+        let workers = producer.subscribe("workers", "orders.#");
+        let message = workers.recv().unwrap();
+        assert_eq!(message.body, b"first");
+        message.ack();
+        drop(producer);
+
+        let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+        assert!(producer.subscribe("workers", "orders.#").is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_enlarges_only_its_own_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = InternalQueueConfig {
+            capacity: Some(1),
+            overflow_policy: OverflowPolicy::Error,
+            write_ahead_log: Some(dir.path().join("queue.wal").display().to_string()),
+            ..Default::default()
+        };
+        {
+            let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+            let workers = producer.subscribe("workers", "orders.#");
+            for body in ["first", "second"] {
+                producer.send(body, "orders.created").await.unwrap();
+                workers.recv().unwrap();
+            }
+        }
+
+        let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+        assert_eq!(producer.get_receiver().capacity(), Some(1));
+        let workers = producer.subscribe("workers", "orders.#");
+        assert_eq!(workers.capacity(), Some(2));
+        assert_eq!(workers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn write_ahead_log_of_custom_groups_compacts_to_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.wal");
+        let config = InternalQueueConfig {
+            write_ahead_log: Some(path.display().to_string()),
+            ..Default::default()
+        };
+        {
+            let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+            let orders = producer.subscribe("orders", "orders.#");
+            let audit = producer.subscribe("audit", "#");
+            producer.send("first", "orders.created").await.unwrap();
+            producer.send("second", "payments.created").await.unwrap();
+            for message in orders.drain().chain(audit.drain()) {
+                message.ack();
+            }
+        }
+
+        // Opening the log rewrites it with only the pending messages.
+        let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert!(producer.subscribe("orders", "orders.#").is_empty());
+        assert!(producer.subscribe("audit", "#").is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_an_idle_group_drops_its_replayed_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = InternalQueueConfig {
+            write_ahead_log: Some(dir.path().join("queue.wal").display().to_string()),
+            ..Default::default()
+        };
+        {
+            let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+            let _receiver = producer.get_receiver();
+            producer.send("first", "orders.created").await.unwrap();
+        }
+        {
+            let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+            producer.unsubscribe(DEFAULT_GROUP);
+        }
+        let producer = InternalMessageQueueProducer::with_config(&config).unwrap();
+        assert!(producer.get_receiver().is_empty());
+    }
+
+    #[tokio::test]
+    async fn custom_groups_alone_do_not_block_bounded_sends() {
+        let producer = InternalMessageQueueProducer::bounded(1, OverflowPolicy::Block);
+        let orders = producer.subscribe("orders", "order.*");
+        for body in ["first", "second", "third"] {
+            tokio::time::timeout(Duration::from_secs(5), producer.send(body, "order.created"))
+                .await
+                .expect("send blocked on a group without consumers")
+                .unwrap();
+            assert_eq!(orders.recv().unwrap().body, body.as_bytes());
+        }
+    }
+
+    async fn next_body(receiver: &flume::Receiver<InternalMessage>) -> String {
+        let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv_async())
+            .await
+            .expect("delayed message wasn't delivered")
+            .unwrap();
+        String::from_utf8(message.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn delayed_messages_are_delivered_when_due() {
+        let producer = InternalMessageQueueProducer::new();
+        let receiver = producer.get_receiver();
+        producer
+            .send_delayed(b"late", "orders", Duration::from_millis(300))
+            .await
+            .unwrap();
+        producer
+            .send_delayed(b"early", "orders", Duration::from_millis(100))
+            .await
+            .unwrap();
+        // A time in the past is delivered at once.
+        producer
+            .send_at(b"now", "orders", SystemTime::now() - Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(next_body(&receiver).await, "now");
+        assert_eq!(next_body(&receiver).await, "early");
+        assert_eq!(next_body(&receiver).await, "late");
+    }
+
+    #[tokio::test]
+    async fn delayed_messages_outlive_the_producer() {
+        let producer = InternalMessageQueueProducer::new();
+        let receiver = producer.get_receiver();
+        producer
+            .send_delayed(b"later", "orders", Duration::from_millis(50))
+            .await
+            .unwrap();
+        drop(producer);
+
+        assert_eq!(next_body(&receiver).await, "later");
     }
 }
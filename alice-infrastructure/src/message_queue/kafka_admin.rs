@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, ResourceSpecifier, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::ClientConfig;
+
+use crate::config::{CommonConfig, MessageQueueConfig, TopicConfig};
+
+use super::is_topic_pattern;
+
+/// Kafka 管理操作的错误
+#[derive(Debug, thiserror::Error)]
+pub enum KafkaAdminError {
+    #[error("Unable to create kafka admin client: {0}")]
+    Create(#[source] KafkaError),
+    #[error("Kafka admin operation failed: {0}")]
+    Operation(#[source] KafkaError),
+    #[error("Unable to create topic {topic}: {code}")]
+    CreateTopic {
+        topic: String,
+        code: RDKafkaErrorCode,
+    },
+    #[error("Unable to describe topic {topic}: {code}")]
+    DescribeTopic {
+        topic: String,
+        code: RDKafkaErrorCode,
+    },
+    #[error("Existing topics don't match the configuration: {}", display_mismatches(.0))]
+    Misconfigured(Vec<TopicMismatch>),
+}
+
+/// 已有主题与配置不一致的一项参数
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicMismatch {
+    pub topic: String,
+    pub setting: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for TopicMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} is {}, expected {}",
+            self.topic, self.setting, self.actual, self.expected
+        )
+    }
+}
+
+fn display_mismatches(mismatches: &[TopicMismatch]) -> String {
+    mismatches.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+}
+
+/// Kafka 管理客户端，启动时按 [`MessageQueueConfig`] 创建缺少的主题并检查已有的主题
+pub struct KafkaAdmin {
+    client: Arc<AdminClient<DefaultClientContext>>,
+    timeout: Duration,
+}
+
+impl KafkaAdmin {
+    pub fn new(client_options: &HashMap<String, String>) -> Result<Self, KafkaAdminError> {
+        let mut kafka_config = ClientConfig::new();
+        for (option_key, option_value) in client_options.iter() {
+            kafka_config.set(option_key.as_str(), option_value.as_str());
+        }
+        kafka_config.set_log_level(RDKafkaLogLevel::Debug);
+        let client = kafka_config.create().map_err(KafkaAdminError::Create)?;
+        Ok(Self {
+            client: Arc::new(client),
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// 管理操作的超时时间，默认 30 秒
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 创建 `config.topics` 中缺少的主题，并检查已有主题的分区数、副本数与主题配置
+    ///
+    /// 已有主题与配置不一致时不会修改主题，而是返回 [`KafkaAdminError::Misconfigured`]。
+    /// 带通配符的主题只用于内部消息队列，会被跳过。
+    pub async fn provision(&self, config: &MessageQueueConfig) -> Result<(), KafkaAdminError> {
+        let topics = config
+            .topics
+            .iter()
+            .filter(|topic| !is_topic_pattern(topic))
+            .map(|topic| (topic.as_str(), config.topic_config(topic)))
+            .collect::<Vec<_>>();
+        let existing = self.fetch_topics().await?;
+
+        let (present, missing): (Vec<_>, Vec<_>) =
+            topics.into_iter().partition(|(topic, _)| existing.contains_key(*topic));
+        self.create_topics(&missing).await?;
+
+        let mut mismatches = Vec::new();
+        for (topic, topic_config) in &present {
+            let (partitions, replication_factor) = existing[*topic];
+            if partitions != topic_config.partitions {
+                mismatches.push(mismatch(
+                    topic,
+                    "partitions",
+                    topic_config.partitions,
+                    partitions,
+                ));
+            }
+            if replication_factor != topic_config.replication_factor {
+                mismatches.push(mismatch(
+                    topic,
+                    "replication_factor",
+                    topic_config.replication_factor,
+                    replication_factor,
+                ));
+            }
+        }
+        mismatches.extend(self.check_settings(&present).await?);
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(KafkaAdminError::Misconfigured(mismatches))
+        }
+    }
+
+    /// The partition count and replication factor of every topic in the cluster.
+    async fn fetch_topics(&self) -> Result<HashMap<String, (i32, i32)>, KafkaAdminError> {
+        let client = self.client.clone();
+        let timeout = self.timeout;
+        // Fetching metadata blocks, run it off the async workers.
+        let metadata =
+            tokio::task::spawn_blocking(move || client.inner().fetch_metadata(None, timeout))
+                .await
+                .map_err(|_| KafkaAdminError::Operation(KafkaError::Canceled))
+                .and_then(|result| result.map_err(KafkaAdminError::Operation))?;
+        Ok(metadata
+            .topics()
+            .iter()
+            .map(|topic| {
+                let replication_factor =
+                    topic.partitions().first().map(|p| p.replicas().len()).unwrap_or_default();
+                (
+                    topic.name().to_string(),
+                    (topic.partitions().len() as i32, replication_factor as i32),
+                )
+            })
+            .collect())
+    }
+
+    async fn create_topics(&self, topics: &[(&str, &TopicConfig)]) -> Result<(), KafkaAdminError> {
+        if topics.is_empty() {
+            return Ok(());
+        }
+        let settings = topics.iter().map(|(_, config)| config.settings()).collect::<Vec<_>>();
+        let new_topics = topics
+            .iter()
+            .zip(&settings)
+            .map(|((topic, config), settings)| {
+                settings.iter().fold(
+                    NewTopic::new(
+                        topic,
+                        config.partitions,
+                        TopicReplication::Fixed(config.replication_factor),
+                    ),
+                    |new_topic, (key, value)| new_topic.set(key, value),
+                )
+            })
+            .collect::<Vec<_>>();
+        let results = self
+            .client
+            .create_topics(&new_topics, &self.options())
+            .await
+            .map_err(KafkaAdminError::Operation)?;
+        for result in results {
+            match result {
+                Ok(topic) => tracing::info!("Kafka topic {topic} created"),
+                // Another instance created it at the same time.
+                Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((topic, code)) => return Err(KafkaAdminError::CreateTopic { topic, code }),
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_settings(
+        &self,
+        topics: &[(&str, &TopicConfig)],
+    ) -> Result<Vec<TopicMismatch>, KafkaAdminError> {
+        let topics = topics
+            .iter()
+            .map(|(topic, config)| (*topic, config.settings()))
+            .filter(|(_, settings)| !settings.is_empty())
+            .collect::<Vec<_>>();
+        if topics.is_empty() {
+            return Ok(Vec::new());
+        }
+        let specifiers = topics
+            .iter()
+            .map(|(topic, _)| ResourceSpecifier::Topic(topic))
+            .collect::<Vec<_>>();
+        let results = self
+            .client
+            .describe_configs(&specifiers, &self.options())
+            .await
+            .map_err(KafkaAdminError::Operation)?;
+
+        let mut mismatches = Vec::new();
+        for ((topic, settings), result) in topics.iter().zip(results) {
+            let resource = result.map_err(|code| KafkaAdminError::DescribeTopic {
+                topic: topic.to_string(),
+                code,
+            })?;
+            for (key, expected) in settings {
+                let actual = resource.get(key).and_then(|entry| entry.value.clone());
+                if actual.as_ref() != Some(expected) {
+                    mismatches.push(mismatch(
+                        topic,
+                        key,
+                        expected,
+                        actual.unwrap_or_else(|| String::from("unset")),
+                    ));
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    fn options(&self) -> AdminOptions {
+        AdminOptions::new()
+            .operation_timeout(Some(self.timeout))
+            .request_timeout(Some(self.timeout))
+    }
+}
+
+fn mismatch(
+    topic: &str,
+    setting: &str,
+    expected: impl ToString,
+    actual: impl ToString,
+) -> TopicMismatch {
+    TopicMismatch {
+        topic: topic.to_string(),
+        setting: setting.to_string(),
+        expected: expected.to_string(),
+        actual: actual.to_string(),
+    }
+}
+
+/// 启动时按配置创建主题，管理客户端使用 `mq.producer` 中的连接配置
+pub async fn provision_topics(config: &CommonConfig) -> Result<(), KafkaAdminError> {
+    KafkaAdmin::new(&config.mq.producer)?.provision(&config.mq).await
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::ClientContext;
+
+    use super::*;
+
+    fn admin(cluster: &MockCluster<'_, impl ClientContext>) -> KafkaAdmin {
+        let client_options =
+            HashMap::from([("bootstrap.servers".to_string(), cluster.bootstrap_servers())]);
+        KafkaAdmin::new(&client_options).unwrap().timeout(Duration::from_secs(10))
+    }
+
+    fn config(topics: &[&str], partitions: i32) -> MessageQueueConfig {
+        MessageQueueConfig {
+            topics: topics.iter().map(ToString::to_string).collect(),
+            topic_defaults: TopicConfig {
+                partitions,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_topics_and_patterns_are_accepted() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 3, 1).unwrap();
+        // The pattern would fail to be created if it weren't skipped.
+        admin(&cluster).provision(&config(&["orders", "orders.*"], 3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mismatched_topics_are_reported() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 3, 1).unwrap();
+        let mut config = config(&["orders"], 2);
+        config.topic_defaults.replication_factor = 2;
+
+        let Err(KafkaAdminError::Misconfigured(mismatches)) =
+            admin(&cluster).provision(&config).await
+        else {
+            panic!("mismatches weren't reported");
+        };
+        assert_eq!(
+            mismatches,
+            [
+                mismatch("orders", "partitions", 2, 3),
+                mismatch("orders", "replication_factor", 2, 1),
+            ]
+        );
+    }
+}
@@ -1,46 +1,120 @@
 use alice_architecture::{
     background_service::BackgroundService,
-    message_queue::producer::{MessageQueueProducer, MessageQueueProducerTemplate},
+    message_queue::{
+        consumer::MessageQueueConsumer,
+        producer::{
+            DelayedMessageQueueProducer, MessageQueueProducer, MessageQueueProducerTemplate,
+            TransactionalMessageQueueProducer,
+        },
+    },
 };
-use futures_util::StreamExt;
+use futures_util::{Future, StreamExt};
 use rdkafka::{
     config::RDKafkaLogLevel,
-    consumer::Consumer,
-    message::OwnedHeaders,
-    producer::{FutureProducer, FutureRecord},
-    ClientConfig, Message,
+    consumer::{CommitMode, Consumer, ConsumerContext, ConsumerGroupMetadata, StreamConsumer},
+    error::{KafkaError, KafkaResult, RDKafkaErrorCode},
+    message::{BorrowedMessage, Header, Headers, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord, Producer},
+    statistics::Statistics,
+    ClientConfig, ClientContext, Message as _, Offset, TopicPartitionList,
 };
+use serde::de::DeserializeOwned;
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::runtime::Handle;
-use tracing::Instrument;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
-use crate::ConsumerFn;
+use super::metrics::metrics;
+use super::{
+    batch_consumer_fn_handler, consumer_fn_handler, is_topic_pattern, new_message_id, topic_regex,
+    typed_batch_handler, typed_handler, wait_deadline, BatchConsumerFn, BatchOptions, Batches,
+    ConsumerControl, ConsumerFn, DeadLetterQueue, DeduplicationStore, Message, MessageCodec,
+    MessageCodecs, MessageDispatcher, MessageHandler, RetryPolicy, MESSAGE_ID_HEADER,
+};
+
+/// Kafka 生产者的错误，保留 rdkafka 的原始错误
+#[derive(Debug, thiserror::Error)]
+pub enum KafkaProducerError {
+    #[error("Unable to create kafka producer: {0}")]
+    Create(#[source] KafkaError),
+    #[error("Unable to send message to {topic}: {source}")]
+    Send {
+        topic: String,
+        #[source]
+        source: KafkaError,
+    },
+    #[error("Kafka transaction failed: {0}")]
+    Transaction(#[source] KafkaError),
+    #[error("Unable to flush kafka producer: {0}")]
+    Flush(#[source] KafkaError),
+    #[error("Kafka producer is not transactional, set `transactional.id` to enable transactions")]
+    NotTransactional,
+}
+
+/// 延迟消息默认发送到的主题
+pub const DEFAULT_DELAY_TOPIC: &str = "alice.delayed";
+
+/// 延迟消息的投递时刻，自 UNIX 纪元起的毫秒数
+pub const DELIVER_AT_HEADER: &str = "x-deliver-at";
+
+/// 延迟消息原来的主题
+pub const ORIGINAL_TOPIC_HEADER: &str = "x-original-topic";
 
+/// Kafka 生产者
+///
+/// 配置了 `transactional.id` 时为事务模式，创建时完成事务初始化，
+/// 在 `begin_transaction` 与 `commit_transaction` 之间发送的消息要么全部可见，要么全部丢弃。
+/// 释放时在 `flush_timeout` 内阻塞等待缓冲区中的消息发送完成，异步代码中优先通过
+/// [`KafkaMessageQueueProducer::close`] 等待，避免阻塞异步运行时的线程。
 pub struct KafkaMessageQueueProducer {
     producer: Arc<FutureProducer>,
+    transactional: bool,
+    queue_timeout: Duration,
+    transaction_timeout: Duration,
+    flush_timeout: Duration,
+    codecs: MessageCodecs,
+    delay_topic: String,
 }
 
 #[async_trait::async_trait]
 impl MessageQueueProducer for KafkaMessageQueueProducer {
-    async fn send(&self, content: &str, topic: &str) -> anyhow::Result<()> {
-        match self
-            .producer
+    async fn send_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut owned_headers =
+            headers.iter().fold(OwnedHeaders::new(), |owned_headers, (key, value)| {
+                owned_headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            });
+        if !headers.contains_key(MESSAGE_ID_HEADER) {
+            owned_headers = owned_headers.insert(Header {
+                key: MESSAGE_ID_HEADER,
+                value: Some(&new_message_id()),
+            });
+        }
+        self.producer
             .send(
-                FutureRecord::to(topic)
-                    .payload(content)
-                    .key("")
-                    .headers(OwnedHeaders::new()),
-                Duration::from_secs(0),
+                FutureRecord::to(topic).payload(content).key("").headers(owned_headers),
+                self.queue_timeout,
             )
             .await
-        {
-            Ok(_) => {}
-            Err(_) => anyhow::bail!("Send Error"),
-        };
+            .map_err(|(source, _)| {
+                metrics().error("kafka", topic, "send");
+                KafkaProducerError::Send {
+                    topic: topic.to_string(),
+                    source,
+                }
+            })?;
+        metrics().produced("kafka", topic);
         Ok(())
     }
 }
@@ -51,19 +125,194 @@ where
     T: serde::Serialize + Send + Sync,
 {
     async fn send_object(&self, content: &T, topic: &str) -> anyhow::Result<()> {
-        self.send(serde_json::to_string(content)?.as_str(), topic).await
+        let codec = self.codecs.for_topic(topic);
+        self.send_with_headers(&codec.encode(content)?, topic, &codec.headers()).await
+    }
+}
+
+/// 延迟消息先发送到延迟主题，由 [`KafkaDelayRelay`] 在到期后转发到原来的主题
+#[async_trait::async_trait]
+impl DelayedMessageQueueProducer for KafkaMessageQueueProducer {
+    async fn send_at_with_headers(
+        &self,
+        content: &[u8],
+        topic: &str,
+        deliver_at: SystemTime,
+        headers: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        if deliver_at <= SystemTime::now() {
+            return self.send_with_headers(content, topic, headers).await;
+        }
+        let mut headers = headers.clone();
+        headers.insert(
+            DELIVER_AT_HEADER.to_string(),
+            deliver_at.duration_since(UNIX_EPOCH)?.as_millis().to_string(),
+        );
+        headers.insert(ORIGINAL_TOPIC_HEADER.to_string(), topic.to_string());
+        self.send_with_headers(content, &self.delay_topic, &headers).await
+    }
+}
+
+#[async_trait::async_trait]
+impl TransactionalMessageQueueProducer for KafkaMessageQueueProducer {
+    async fn begin_transaction(&self) -> anyhow::Result<()> {
+        self.transaction(|producer, _| producer.begin_transaction()).await
+    }
+
+    async fn commit_transaction(&self) -> anyhow::Result<()> {
+        self.transaction(|producer, timeout| producer.commit_transaction(timeout)).await
+    }
+
+    async fn abort_transaction(&self) -> anyhow::Result<()> {
+        self.transaction(|producer, timeout| producer.abort_transaction(timeout)).await
     }
 }
 
 impl KafkaMessageQueueProducer {
-    pub fn new(client_options: &HashMap<String, String>) -> Self {
+    pub fn new(client_options: &HashMap<String, String>) -> Result<Self, KafkaProducerError> {
         let mut kafka_config = ClientConfig::new();
         for (option_key, option_value) in client_options.iter() {
             kafka_config.set(option_key.as_str(), option_value.as_str());
         }
         kafka_config.set_log_level(RDKafkaLogLevel::Debug);
-        Self {
-            producer: Arc::new(kafka_config.create().unwrap()),
+        let producer: FutureProducer = kafka_config.create().map_err(KafkaProducerError::Create)?;
+        let transactional = client_options.contains_key("transactional.id");
+        let this = Self {
+            producer: Arc::new(producer),
+            transactional,
+            queue_timeout: Duration::from_secs(5),
+            transaction_timeout: Duration::from_secs(30),
+            flush_timeout: Duration::from_secs(10),
+            codecs: MessageCodecs::default(),
+            delay_topic: DEFAULT_DELAY_TOPIC.to_string(),
+        };
+        if transactional {
+            this.producer
+                .init_transactions(this.transaction_timeout)
+                .map_err(KafkaProducerError::Transaction)?;
+        }
+        Ok(this)
+    }
+
+    /// 幂等生产者：消息不会因为重试而重复写入同一分区
+    pub fn idempotent(
+        client_options: &HashMap<String, String>,
+    ) -> Result<Self, KafkaProducerError> {
+        let mut client_options = client_options.clone();
+        client_options.insert("enable.idempotence".to_string(), "true".to_string());
+        Self::new(&client_options)
+    }
+
+    /// 事务生产者，`transactional_id` 在同一个应用的多个实例之间需要保持唯一且稳定
+    pub fn transactional(
+        client_options: &HashMap<String, String>,
+        transactional_id: &str,
+    ) -> Result<Self, KafkaProducerError> {
+        let mut client_options = client_options.clone();
+        client_options.insert("transactional.id".to_string(), transactional_id.to_string());
+        Self::new(&client_options)
+    }
+
+    /// 本地发送队列已满时等待的最长时间，默认 5 秒
+    pub fn queue_timeout(mut self, queue_timeout: Duration) -> Self {
+        self.queue_timeout = queue_timeout;
+        self
+    }
+
+    /// 提交、回滚事务的超时时间，默认 30 秒
+    pub fn transaction_timeout(mut self, transaction_timeout: Duration) -> Self {
+        self.transaction_timeout = transaction_timeout;
+        self
+    }
+
+    /// `flush` 与 `close` 等待消息发送完成的最长时间，默认 10 秒
+    pub fn flush_timeout(mut self, flush_timeout: Duration) -> Self {
+        self.flush_timeout = flush_timeout;
+        self
+    }
+
+    /// `send_object` 默认使用的编码格式，默认为 JSON
+    pub fn codec(mut self, codec: MessageCodec) -> Self {
+        self.codecs.set_default(codec);
+        self
+    }
+
+    /// 为主题单独指定 `send_object` 使用的编码格式
+    pub fn topic_codec(mut self, topic: &str, codec: MessageCodec) -> Self {
+        self.codecs.set_topic(topic, codec);
+        self
+    }
+
+    /// 延迟消息发送到的主题，默认为 [`DEFAULT_DELAY_TOPIC`]，需要与 [`KafkaDelayRelay`] 一致
+    pub fn delay_topic(mut self, delay_topic: &str) -> Self {
+        self.delay_topic = delay_topic.to_string();
+        self
+    }
+
+    /// 把消费者的 offset 加入当前事务，用于消费-处理-生产的恰好一次语义
+    pub async fn send_offsets_to_transaction(
+        &self,
+        offsets: TopicPartitionList,
+        group_metadata: ConsumerGroupMetadata,
+    ) -> anyhow::Result<()> {
+        self.transaction(move |producer, timeout| {
+            producer.send_offsets_to_transaction(&offsets, &group_metadata, timeout)
+        })
+        .await
+    }
+
+    /// 等待缓冲区中的消息发送完成
+    pub async fn flush(&self) -> Result<(), KafkaProducerError> {
+        let producer = self.producer.clone();
+        let timeout = self.flush_timeout;
+        // Flushing blocks, run it off the async workers.
+        tokio::task::spawn_blocking(move || producer.flush(timeout))
+            .await
+            .map_err(|_| KafkaProducerError::Flush(KafkaError::Canceled))?
+            .map_err(KafkaProducerError::Flush)
+    }
+
+    /// 等待缓冲区中的消息发送完成后释放生产者
+    pub async fn close(self) -> Result<(), KafkaProducerError> {
+        self.flush().await
+    }
+
+    /// The transactional API of rdkafka is blocking, run it off the async workers.
+    async fn transaction<F>(&self, operation: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&FutureProducer, Duration) -> KafkaResult<()> + Send + 'static,
+    {
+        if !self.transactional {
+            return Err(KafkaProducerError::NotTransactional.into());
+        }
+        let producer = self.producer.clone();
+        let timeout = self.transaction_timeout;
+        tokio::task::spawn_blocking(move || operation(&producer, timeout))
+            .await?
+            .map_err(KafkaProducerError::Transaction)?;
+        Ok(())
+    }
+}
+
+/// Flushes the buffered messages for at most `flush_timeout`, blocking the thread that drops the
+/// producer. On a multi-threaded runtime the other tasks are moved off the blocked worker.
+impl Drop for KafkaMessageQueueProducer {
+    fn drop(&mut self) {
+        if self.producer.in_flight_count() == 0 {
+            return;
+        }
+        let flush = || self.producer.flush(self.flush_timeout);
+        let result = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(flush)
+            }
+            _ => flush(),
+        };
+        if let Err(e) = result {
+            tracing::warn!(
+                "Kafka producer dropped with {} messages not delivered: {e}",
+                self.producer.in_flight_count()
+            );
         }
     }
 }
@@ -72,10 +321,10 @@ pub struct KafkaMultiTopicMessageQueueConsumer<SP>
 where
     SP: Send + Sync + 'static,
 {
-    topics: HashSet<String>,
     client_options: HashMap<String, String>,
-    service_provider: Arc<SP>,
-    fn_mapper: HashMap<String, ConsumerFn<SP>>,
+    dispatcher: MessageDispatcher<SP>,
+    control: ConsumerControl,
+    shutdown: CancellationToken,
 }
 
 #[async_trait::async_trait]
@@ -84,49 +333,44 @@ where
     SP: Send + Sync + 'static,
 {
     async fn run(&self) {
-        let mut kafka_config = ClientConfig::new();
-        for (option_key, option_value) in self.client_options.iter() {
-            kafka_config.set(option_key.as_str(), option_value.as_str());
-        }
-        kafka_config.set_log_level(RDKafkaLogLevel::Debug);
-        let stream_consumer: rdkafka::consumer::StreamConsumer = kafka_config.create().unwrap();
-        stream_consumer
-            .subscribe(
-                self.topics.iter().map(|topic| topic.as_str()).collect::<Vec<&str>>().as_slice(),
-            )
-            .unwrap();
+        let topics = self.dispatcher.topics().map(|topic| topic.as_str()).collect::<Vec<_>>();
+        let stream_consumer =
+            match create_stream_consumer(&self.client_options, &topics, self.dispatcher.name()) {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!("{e}");
+                    return;
+                }
+            };
         let mut stream = stream_consumer.stream();
+        let mut changes = self.control.subscribe();
+        let mut paused = PausedPartitions::default();
         tracing::info!("Kafka consumer starting");
         loop {
-            match stream.next().await {
+            let message = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = changes.changed() => {
+                    paused.release(&stream_consumer, &self.control);
+                    continue;
+                }
+                message = stream.next() => message,
+            };
+            match message {
                 Some(Ok(borrowed_message)) => {
-                    let topic = borrowed_message.topic();
-                    let message =
-                        borrowed_message.payload_view::<str>().and_then(Result::ok).unwrap_or("{}");
-                    tracing::debug!("Message: {message}");
-                    match self.fn_mapper.get(topic) {
-                        Some(x) => {
-                            let sp = self.service_provider.clone();
-                            let x = *x;
-
-                            tokio::task::block_in_place(move || {
-                                Handle::current().block_on(
-                                    async move {
-                                        if let Err(e) = x(message, sp.clone()).await {
-                                            tracing::error!("{e}")
-                                        }
-                                    }
-                                    .instrument(
-                                        tracing::trace_span!("kafka_multi_topic_message_queue"),
-                                    ),
-                                )
-                            });
-                        }
-                        None => tracing::error!("No such service: {topic}"),
+                    if paused.hold(&stream_consumer, &self.control, &borrowed_message) {
+                        continue;
+                    }
+                    if !throttle(&self.control, &self.shutdown, borrowed_message.topic()).await {
+                        break;
                     }
+                    let message = to_message(&borrowed_message);
+                    tracing::debug!("Message: {}", String::from_utf8_lossy(&message.payload));
+                    self.dispatcher.dispatch(&message);
+                    store_offset(&stream_consumer, &borrowed_message);
                 }
                 Some(Err(kafka_error)) => match kafka_error {
-                    rdkafka::error::KafkaError::PartitionEOF(partition) => {
+                    KafkaError::PartitionEOF(partition) => {
                         tracing::info!("at end of partition {partition:?}");
                     }
                     _ => tracing::error!("errors from kafka, {kafka_error}"),
@@ -134,6 +378,206 @@ where
                 None => {}
             }
         }
+        commit_on_shutdown(&stream_consumer);
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+fn create_stream_consumer(
+    client_options: &HashMap<String, String>,
+    topics: &[&str],
+    name: &'static str,
+) -> anyhow::Result<MetricsStreamConsumer> {
+    let mut kafka_config = ClientConfig::new();
+    // Statistics are needed for the consumer lag metrics.
+    kafka_config.set("statistics.interval.ms", "10000");
+    for (option_key, option_value) in client_options.iter() {
+        kafka_config.set(option_key.as_str(), option_value.as_str());
+    }
+    // Offsets are stored once their messages are handled, never for the held messages of paused
+    // partitions, so that neither auto commit nor the commit on shutdown skips them.
+    kafka_config.set("enable.auto.offset.store", "false");
+    kafka_config.set_log_level(RDKafkaLogLevel::Debug);
+    let stream_consumer: MetricsStreamConsumer = kafka_config
+        .create_with_context(ConsumerMetricsContext { name })
+        .map_err(|e| anyhow::anyhow!("Unable to create kafka consumer: {e}"))?;
+    // Kafka subscribes to patterns as regular expressions.
+    let subscriptions = topics
+        .iter()
+        .map(|topic| match is_topic_pattern(topic) {
+            true => topic_regex(topic),
+            false => topic.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let subscriptions = subscriptions.iter().map(String::as_str).collect::<Vec<_>>();
+    stream_consumer
+        .subscribe(&subscriptions)
+        .map_err(|e| anyhow::anyhow!("Unable to subscribe kafka topics: {e}"))?;
+    Ok(stream_consumer)
+}
+
+fn to_message(borrowed_message: &BorrowedMessage) -> Message {
+    Message {
+        topic: borrowed_message.topic().to_string(),
+        payload: borrowed_message.payload().unwrap_or_default().to_vec(),
+        headers: borrowed_message
+            .headers()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .filter_map(|header| {
+                        let value = std::str::from_utf8(header.value?).ok()?;
+                        Some((header.key.to_string(), value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Reports the consumer lag of every partition from the statistics of librdkafka.
+struct ConsumerMetricsContext {
+    name: &'static str,
+}
+
+impl ClientContext for ConsumerMetricsContext {
+    fn stats(&self, statistics: Statistics) {
+        for (topic_name, topic) in statistics.topics {
+            for (partition, stats) in topic.partitions {
+                // Partition -1 is the internal unassigned partition, and -1 lag means unknown.
+                if partition >= 0 && stats.consumer_lag >= 0 {
+                    metrics().consumer_lag(self.name, &topic_name, partition, stats.consumer_lag);
+                }
+            }
+        }
+    }
+}
+
+impl ConsumerContext for ConsumerMetricsContext {}
+
+type MetricsStreamConsumer = StreamConsumer<ConsumerMetricsContext>;
+
+/// Marks the message as handled, its offset is committed by auto commit or on shutdown.
+fn store_offset(consumer: &MetricsStreamConsumer, borrowed_message: &BorrowedMessage<'_>) {
+    if let Err(e) = consumer.store_offset_from_message(borrowed_message) {
+        tracing::error!("Unable to store kafka consumer offset: {e}");
+    }
+}
+
+/// Pauses the partition and rewinds to the message, so that it is fetched again after resuming.
+fn pause_at_message(
+    consumer: &MetricsStreamConsumer,
+    borrowed_message: &BorrowedMessage<'_>,
+) -> KafkaResult<()> {
+    let (topic, partition) = (borrowed_message.topic(), borrowed_message.partition());
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+    consumer.pause(&partitions)?;
+    consumer.seek(
+        topic,
+        partition,
+        Offset::Offset(borrowed_message.offset()),
+        Duration::from_secs(5),
+    )
+}
+
+fn resume_partition(
+    consumer: &MetricsStreamConsumer,
+    topic: &str,
+    partition: i32,
+) -> KafkaResult<()> {
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+    consumer.resume(&partitions)
+}
+
+/// Partitions paused because their topics are paused through [`ConsumerControl`].
+#[derive(Default)]
+struct PausedPartitions(HashSet<(String, i32)>);
+
+impl PausedPartitions {
+    /// Returns whether the message is left for later, pausing its partition if its topic is paused.
+    fn hold(
+        &mut self,
+        consumer: &MetricsStreamConsumer,
+        control: &ConsumerControl,
+        borrowed_message: &BorrowedMessage<'_>,
+    ) -> bool {
+        let key = (
+            borrowed_message.topic().to_string(),
+            borrowed_message.partition(),
+        );
+        // Messages fetched before pausing are fetched again after resuming.
+        if self.0.contains(&key) {
+            return true;
+        }
+        if !control.is_paused(&key.0) {
+            return false;
+        }
+        match pause_at_message(consumer, borrowed_message) {
+            Ok(()) => {
+                tracing::info!("Kafka partition {} of {} paused", key.1, key.0);
+                self.0.insert(key);
+                true
+            }
+            Err(e) => {
+                tracing::error!("Unable to pause partition {} of {}: {e}", key.1, key.0);
+                false
+            }
+        }
+    }
+
+    /// Resumes the partitions whose topics are no longer paused.
+    fn release(&mut self, consumer: &MetricsStreamConsumer, control: &ConsumerControl) {
+        self.0.retain(|(topic, partition)| {
+            if control.is_paused(topic) {
+                return true;
+            }
+            if let Err(e) = resume_partition(consumer, topic, *partition) {
+                tracing::error!("Unable to resume partition {partition} of {topic}: {e}");
+            }
+            false
+        });
+    }
+}
+
+/// Waits until the rate limit of the topic allows one more message, returns `false` if the consumer is
+/// stopped first. The message is left unhandled then, and consumed again after restart.
+async fn throttle(control: &ConsumerControl, shutdown: &CancellationToken, topic: &str) -> bool {
+    tokio::select! {
+        biased;
+        _ = shutdown.cancelled() => false,
+        _ = control.throttle(topic) => true,
+    }
+}
+
+/// Commits the offsets of the handled messages, so that they won't be consumed again after restart.
+fn commit_on_shutdown(consumer: &MetricsStreamConsumer) {
+    match consumer.commit_consumer_state(CommitMode::Sync) {
+        Ok(()) => tracing::info!("Kafka consumer offsets committed"),
+        Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+        Err(e) => tracing::error!("Unable to commit kafka consumer offsets: {e}"),
+    }
+    tracing::info!("Kafka consumer stopped");
+}
+
+impl<SP> MessageQueueConsumer for KafkaMultiTopicMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    type Handler = ConsumerFn<SP>;
+
+    fn add_topic(mut self, topic: &str, handler: Self::Handler) -> Self {
+        self.dispatcher.insert(topic, consumer_fn_handler(handler));
+        self
+    }
+
+    fn add_option(mut self, option_key: &str, option_value: &str) -> Self {
+        self.client_options.insert(option_key.to_string(), option_value.to_string());
+        self
     }
 }
 
@@ -141,32 +585,64 @@ impl<SP> KafkaMultiTopicMessageQueueConsumer<SP>
 where
     SP: Send + Sync + 'static,
 {
-    pub fn new(
-        topics: Vec<String>,
-        client_options: HashMap<String, String>,
-        service_provider: Arc<SP>,
-        fn_mapper: HashMap<String, ConsumerFn<SP>>,
-    ) -> Self {
-        let mut new_topics = HashSet::new();
-        for topic in topics {
-            new_topics.insert(topic.to_string());
-        }
+    pub fn new(service_provider: Arc<SP>) -> Self {
         Self {
-            topics: new_topics,
-            client_options,
-            service_provider,
-            fn_mapper,
+            client_options: HashMap::new(),
+            dispatcher: MessageDispatcher::new("kafka_multi_topic_message_queue", service_provider),
+            control: ConsumerControl::new(),
+            shutdown: CancellationToken::new(),
         }
     }
+
+    /// 订阅主题，消息反序列化为 `T` 后交给 `handler` 处理
+    pub fn register<T, F, Fut>(mut self, topic: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(T, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.dispatcher.insert(topic, typed_handler(handler));
+        self
+    }
+
+    /// 订阅主题，由 `handler` 直接处理原始消息
+    pub fn add_handler(mut self, topic: &str, handler: MessageHandler<SP>) -> Self {
+        self.dispatcher.insert(topic, handler);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.dispatcher.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn dead_letter_queue(mut self, dead_letter_queue: DeadLetterQueue) -> Self {
+        self.dispatcher.set_dead_letter_queue(dead_letter_queue);
+        self
+    }
+
+    /// 幂等的处理函数通过 `store` 跳过已经处理过的消息
+    pub fn deduplication_store(mut self, store: Arc<dyn DeduplicationStore>) -> Self {
+        self.dispatcher.set_deduplication_store(store);
+        self
+    }
+
+    /// 暂停与限流的控制句柄，暂停的主题的分区被暂停，恢复后从第一条没有处理的消息继续
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
 }
+
 pub struct KafkaSingleTopicMessageQueueConsumer<SP>
 where
     SP: Send + Sync + 'static,
 {
     topics: HashSet<String>,
     client_options: HashMap<String, String>,
-    service_provider: Arc<SP>,
-    fn_mapper: Vec<ConsumerFn<SP>>,
+    dispatcher: MessageDispatcher<SP>,
+    fn_mapper: Vec<MessageHandler<SP>>,
+    control: ConsumerControl,
+    shutdown: CancellationToken,
 }
 
 #[async_trait::async_trait]
@@ -175,44 +651,45 @@ where
     SP: Send + Sync + 'static,
 {
     async fn run(&self) {
-        let mut kafka_config = ClientConfig::new();
-        for (option_key, option_value) in self.client_options.iter() {
-            kafka_config.set(option_key.as_str(), option_value.as_str());
-        }
-        kafka_config.set_log_level(RDKafkaLogLevel::Debug);
-        let stream_consumer: rdkafka::consumer::StreamConsumer = kafka_config.create().unwrap();
-        stream_consumer
-            .subscribe(
-                self.topics.iter().map(|topic| topic.as_str()).collect::<Vec<&str>>().as_slice(),
-            )
-            .unwrap();
+        let topics = self.topics.iter().map(|topic| topic.as_str()).collect::<Vec<_>>();
+        let stream_consumer =
+            match create_stream_consumer(&self.client_options, &topics, self.dispatcher.name()) {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!("{e}");
+                    return;
+                }
+            };
         let mut stream = stream_consumer.stream();
+        let mut changes = self.control.subscribe();
+        let mut paused = PausedPartitions::default();
         loop {
-            match stream.next().await {
+            let message = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = changes.changed() => {
+                    paused.release(&stream_consumer, &self.control);
+                    continue;
+                }
+                message = stream.next() => message,
+            };
+            match message {
                 Some(Ok(borrowed_message)) => {
-                    let message =
-                        borrowed_message.payload_view::<str>().and_then(Result::ok).unwrap_or("{}");
-                    tracing::debug!("Message: {message}");
-                    for x in &self.fn_mapper {
-                        let sp = self.service_provider.clone();
-                        let x = *x;
-                        let message = message.to_owned();
-                        tokio::task::block_in_place(move || {
-                            Handle::current().block_on(
-                                async move {
-                                    if let Err(e) = x(&message, sp.clone()).await {
-                                        tracing::error!("{e}")
-                                    }
-                                }
-                                .instrument(tracing::trace_span!(
-                                    "kafka_single_topic_message_queue"
-                                )),
-                            )
-                        });
+                    if paused.hold(&stream_consumer, &self.control, &borrowed_message) {
+                        continue;
                     }
+                    if !throttle(&self.control, &self.shutdown, borrowed_message.topic()).await {
+                        break;
+                    }
+                    let message = to_message(&borrowed_message);
+                    tracing::debug!("Message: {}", String::from_utf8_lossy(&message.payload));
+                    for handler in &self.fn_mapper {
+                        self.dispatcher.handle(handler, &message);
+                    }
+                    store_offset(&stream_consumer, &borrowed_message);
                 }
                 Some(Err(kafka_error)) => match kafka_error {
-                    rdkafka::error::KafkaError::PartitionEOF(partition) => {
+                    KafkaError::PartitionEOF(partition) => {
                         tracing::info!("at end of partition {partition:?}");
                     }
                     _ => tracing::error!("errors from kafka, {kafka_error}"),
@@ -220,6 +697,30 @@ where
                 None => {}
             }
         }
+        commit_on_shutdown(&stream_consumer);
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Every registered handler receives the messages of all subscribed topics.
+impl<SP> MessageQueueConsumer for KafkaSingleTopicMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    type Handler = ConsumerFn<SP>;
+
+    fn add_topic(mut self, topic: &str, handler: Self::Handler) -> Self {
+        self.topics.insert(topic.to_string());
+        self.fn_mapper.push(consumer_fn_handler(handler));
+        self
+    }
+
+    fn add_option(mut self, option_key: &str, option_value: &str) -> Self {
+        self.client_options.insert(option_key.to_string(), option_value.to_string());
+        self
     }
 }
 
@@ -227,21 +728,700 @@ impl<SP> KafkaSingleTopicMessageQueueConsumer<SP>
 where
     SP: Send + Sync + 'static,
 {
+    pub fn new(service_provider: Arc<SP>) -> Self {
+        Self {
+            topics: HashSet::new(),
+            client_options: HashMap::new(),
+            dispatcher: MessageDispatcher::new(
+                "kafka_single_topic_message_queue",
+                service_provider,
+            ),
+            fn_mapper: vec![],
+            control: ConsumerControl::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 订阅主题，消息反序列化为 `T` 后交给 `handler` 处理
+    pub fn register<T, F, Fut>(mut self, topic: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(T, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.topics.insert(topic.to_string());
+        self.fn_mapper.push(typed_handler(handler));
+        self
+    }
+
+    /// 订阅主题，由 `handler` 直接处理原始消息
+    pub fn add_handler(mut self, topic: &str, handler: MessageHandler<SP>) -> Self {
+        self.topics.insert(topic.to_string());
+        self.fn_mapper.push(handler);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.dispatcher.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn dead_letter_queue(mut self, dead_letter_queue: DeadLetterQueue) -> Self {
+        self.dispatcher.set_dead_letter_queue(dead_letter_queue);
+        self
+    }
+
+    /// 幂等的处理函数通过 `store` 跳过已经处理过的消息
+    pub fn deduplication_store(mut self, store: Arc<dyn DeduplicationStore>) -> Self {
+        self.dispatcher.set_deduplication_store(store);
+        self
+    }
+
+    /// 暂停与限流的控制句柄，暂停的主题的分区被暂停，恢复后从第一条没有处理的消息继续
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
+}
+
+/// 批量消费者：同一主题的消息按 [`BatchOptions`] 攒成一批后交给批处理函数
+///
+/// 自动提交被关闭，每批消息处理完成后才提交其 offset；没有处理函数的消息不提交，重启后重新消费。
+pub struct KafkaBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    client_options: HashMap<String, String>,
+    dispatcher: MessageDispatcher<SP>,
+    options: BatchOptions,
+    control: ConsumerControl,
+    shutdown: CancellationToken,
+}
+
+/// A consumed message with its partition and offset.
+type BatchItem = (Message, i32, i64);
+
+#[async_trait::async_trait]
+impl<SP> BackgroundService for KafkaBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    async fn run(&self) {
+        let mut client_options = self.client_options.clone();
+        // Offsets are committed per batch.
+        client_options.insert("enable.auto.commit".to_string(), "false".to_string());
+        let topics = self.dispatcher.topics().map(|topic| topic.as_str()).collect::<Vec<_>>();
+        let stream_consumer =
+            match create_stream_consumer(&client_options, &topics, self.dispatcher.name()) {
+                Ok(c) => c,
+                Err(e) => {
+                    tracing::error!("{e}");
+                    return;
+                }
+            };
+        let mut stream = stream_consumer.stream();
+        let mut batches = Batches::<BatchItem>::new(self.options.clone());
+        let mut changes = self.control.subscribe();
+        let mut paused = PausedPartitions::default();
+        tracing::info!("Kafka batch consumer starting");
+        loop {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = wait_deadline(batches.next_deadline()) => {
+                    for (topic, items) in batches.take_expired() {
+                        self.handle_batch(&stream_consumer, &topic, items);
+                    }
+                }
+                _ = changes.changed() => paused.release(&stream_consumer, &self.control),
+                message = stream.next() => match message {
+                    Some(Ok(borrowed_message))
+                        if paused.hold(&stream_consumer, &self.control, &borrowed_message) => {}
+                    Some(Ok(borrowed_message)) => {
+                        if !throttle(&self.control, &self.shutdown, borrowed_message.topic()).await {
+                            break;
+                        }
+                        let item = (
+                            to_message(&borrowed_message),
+                            borrowed_message.partition(),
+                            borrowed_message.offset(),
+                        );
+                        if let Some(items) = batches.push(borrowed_message.topic(), item) {
+                            self.handle_batch(&stream_consumer, borrowed_message.topic(), items);
+                        }
+                    }
+                    Some(Err(kafka_error)) => match kafka_error {
+                        KafkaError::PartitionEOF(partition) => {
+                            tracing::info!("at end of partition {partition:?}");
+                        }
+                        _ => tracing::error!("errors from kafka, {kafka_error}"),
+                    },
+                    None => {}
+                },
+            }
+        }
+        for (topic, items) in batches.take_all() {
+            self.handle_batch(&stream_consumer, &topic, items);
+        }
+        commit_on_shutdown(&stream_consumer);
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl<SP> MessageQueueConsumer for KafkaBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    type Handler = BatchConsumerFn<SP>;
+
+    fn add_topic(mut self, topic: &str, handler: Self::Handler) -> Self {
+        self.dispatcher.insert_batch(topic, batch_consumer_fn_handler(handler));
+        self
+    }
+
+    fn add_option(mut self, option_key: &str, option_value: &str) -> Self {
+        self.client_options.insert(option_key.to_string(), option_value.to_string());
+        self
+    }
+}
+
+impl<SP> KafkaBatchMessageQueueConsumer<SP>
+where
+    SP: Send + Sync + 'static,
+{
+    pub fn new(service_provider: Arc<SP>, options: BatchOptions) -> Self {
+        Self {
+            client_options: HashMap::new(),
+            dispatcher: MessageDispatcher::new("kafka_batch_message_queue", service_provider),
+            options,
+            control: ConsumerControl::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 订阅主题，一批消息反序列化为 `Vec<T>` 后交给 `handler` 处理
+    pub fn register<T, F, Fut>(mut self, topic: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Vec<T>, Arc<SP>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + 'static,
+    {
+        self.dispatcher.insert_batch(topic, typed_batch_handler(handler));
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.dispatcher.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn dead_letter_queue(mut self, dead_letter_queue: DeadLetterQueue) -> Self {
+        self.dispatcher.set_dead_letter_queue(dead_letter_queue);
+        self
+    }
+
+    /// 暂停与限流的控制句柄，暂停的主题的分区被暂停，恢复后从第一条没有处理的消息继续
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
+
+    /// Handles a batch, then commits the offsets after its last message of every partition.
+    ///
+    /// A batch that is neither handled nor dead-lettered is left uncommitted and its partitions are
+    /// rewound to it, so that it is consumed again instead of being lost.
+    fn handle_batch(&self, consumer: &MetricsStreamConsumer, topic: &str, items: Vec<BatchItem>) {
+        // The first offset and the offset after the last message of every partition.
+        let mut ranges = HashMap::<i32, (i64, i64)>::new();
+        let messages = items
+            .into_iter()
+            .map(|(message, partition, offset)| {
+                let range = ranges.entry(partition).or_insert((offset, offset + 1));
+                *range = (range.0.min(offset), range.1.max(offset + 1));
+                message
+            })
+            .collect::<Vec<_>>();
+        if !self.dispatcher.dispatch_batch(topic, &messages) {
+            for (partition, (first_offset, _)) in ranges {
+                let offset = Offset::Offset(first_offset);
+                if let Err(e) = consumer.seek(topic, partition, offset, Duration::from_secs(5)) {
+                    tracing::error!("Unable to rewind partition {partition} of {topic}: {e}");
+                }
+            }
+            return;
+        }
+
+        let mut offsets = TopicPartitionList::new();
+        for (partition, (_, offset)) in ranges {
+            if let Err(e) = offsets.add_partition_offset(topic, partition, Offset::Offset(offset)) {
+                tracing::error!("{e}");
+            }
+        }
+        if let Err(e) = consumer.commit(&offsets, CommitMode::Async) {
+            tracing::error!("Unable to commit kafka consumer offsets: {e}");
+        }
+    }
+}
+
+/// 延迟主题的转发服务：消息到期后转发到原来的主题，转发时去掉延迟相关的消息头
+///
+/// 分区中最早的消息没有到期时，暂停该分区并回到这条消息，到期后恢复，因此分区内的消息按顺序转发。
+/// 延迟时间相差很大的消息会互相阻塞，这时可以为不同的延迟使用不同的延迟主题。
+/// 消息转发成功后才记录 offset，重启后没有转发的消息会再次转发。
+pub struct KafkaDelayRelay {
+    client_options: HashMap<String, String>,
+    delay_topic: String,
+    producer: Arc<dyn MessageQueueProducer>,
+    shutdown: CancellationToken,
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for KafkaDelayRelay {
+    async fn run(&self) {
+        let stream_consumer = match create_stream_consumer(
+            &self.client_options,
+            &[&self.delay_topic],
+            "kafka_delay_relay",
+        ) {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("{e}");
+                return;
+            }
+        };
+        let mut stream = stream_consumer.stream();
+        // The partitions waiting for their first message to be due.
+        let mut paused = HashMap::<i32, Instant>::new();
+        tracing::info!("Kafka delay relay starting");
+        loop {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => break,
+                _ = wait_deadline(paused.values().min().copied()) => {
+                    let now = Instant::now();
+                    let due = paused
+                        .iter()
+                        .filter(|(_, resume_at)| **resume_at <= now)
+                        .map(|(partition, _)| *partition)
+                        .collect::<Vec<_>>();
+                    for partition in due {
+                        paused.remove(&partition);
+                        self.resume(&stream_consumer, partition);
+                    }
+                }
+                message = stream.next() => match message {
+                    // Messages fetched before pausing are fetched again after resuming.
+                    Some(Ok(borrowed_message))
+                        if !paused.contains_key(&borrowed_message.partition()) =>
+                    {
+                        self.relay(&stream_consumer, &borrowed_message, &mut paused).await;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(kafka_error)) => match kafka_error {
+                        KafkaError::PartitionEOF(partition) => {
+                            tracing::info!("at end of partition {partition:?}");
+                        }
+                        _ => tracing::error!("errors from kafka, {kafka_error}"),
+                    },
+                    None => {}
+                },
+            }
+        }
+        commit_on_shutdown(&stream_consumer);
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl KafkaDelayRelay {
+    /// `client_options` 为消费者的配置，需要包含 `group.id`；到期的消息由 `producer` 发送
     pub fn new(
-        topics: &[String],
-        client_options: HashMap<String, String>,
-        service_provider: Arc<SP>,
-        fn_mapper: Vec<ConsumerFn<SP>>,
+        client_options: &HashMap<String, String>,
+        producer: Arc<dyn MessageQueueProducer>,
     ) -> Self {
-        let mut new_topics = HashSet::new();
-        for topic in topics {
-            new_topics.insert(topic.to_string());
-        }
         Self {
-            topics: new_topics,
-            client_options,
-            service_provider,
-            fn_mapper,
+            client_options: client_options.clone(),
+            delay_topic: DEFAULT_DELAY_TOPIC.to_string(),
+            producer,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// 转发的延迟主题，默认为 [`DEFAULT_DELAY_TOPIC`]
+    pub fn delay_topic(mut self, delay_topic: &str) -> Self {
+        self.delay_topic = delay_topic.to_string();
+        self
+    }
+
+    async fn relay(
+        &self,
+        consumer: &MetricsStreamConsumer,
+        borrowed_message: &BorrowedMessage<'_>,
+        paused: &mut HashMap<i32, Instant>,
+    ) {
+        let mut message = to_message(borrowed_message);
+        let Some(topic) = message.headers.remove(ORIGINAL_TOPIC_HEADER) else {
+            tracing::error!("Delayed message without {ORIGINAL_TOPIC_HEADER} header is dropped");
+            store_offset(consumer, borrowed_message);
+            return;
+        };
+        let deliver_at = message
+            .headers
+            .remove(DELIVER_AT_HEADER)
+            .and_then(|deliver_at| deliver_at.parse::<u64>().ok())
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+        if let Some(Ok(delay)) = deliver_at.map(|at| at.duration_since(SystemTime::now())) {
+            if !delay.is_zero() {
+                self.pause(consumer, borrowed_message, Instant::now() + delay, paused);
+                return;
+            }
+        }
+        match self
+            .producer
+            .send_with_headers(&message.payload, &topic, &message.headers)
+            .await
+        {
+            Ok(()) => store_offset(consumer, borrowed_message),
+            Err(e) => {
+                tracing::error!("Unable to relay delayed message to {topic}, retrying: {e}");
+                let retry_at = Instant::now() + Duration::from_secs(1);
+                self.pause(consumer, borrowed_message, retry_at, paused);
+            }
+        }
+    }
+
+    fn pause(
+        &self,
+        consumer: &MetricsStreamConsumer,
+        borrowed_message: &BorrowedMessage<'_>,
+        resume_at: Instant,
+        paused: &mut HashMap<i32, Instant>,
+    ) {
+        let partition = borrowed_message.partition();
+        match pause_at_message(consumer, borrowed_message) {
+            Ok(()) => {
+                paused.insert(partition, resume_at);
+            }
+            Err(e) => tracing::error!("Unable to pause delay topic partition {partition}: {e}"),
+        }
+    }
+
+    fn resume(&self, consumer: &MetricsStreamConsumer, partition: i32) {
+        if let Err(e) = resume_partition(consumer, &self.delay_topic, partition) {
+            tracing::error!("Unable to resume delay topic partition {partition}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::mocking::MockCluster;
+
+    use super::*;
+    use crate::message_queue::RateLimit;
+
+    fn client_options(cluster: &MockCluster<'_, impl ClientContext>) -> HashMap<String, String> {
+        HashMap::from([("bootstrap.servers".to_string(), cluster.bootstrap_servers())])
+    }
+
+    #[tokio::test]
+    async fn flush_delivers_buffered_messages() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 1, 1).unwrap();
+        let producer = KafkaMessageQueueProducer::new(&client_options(&cluster)).unwrap();
+        let delivery = producer
+            .producer
+            .send_result(FutureRecord::<str, str>::to("orders").payload("created"))
+            .unwrap();
+        producer.flush().await.unwrap();
+        assert_eq!(producer.producer.in_flight_count(), 0);
+        assert!(delivery.await.unwrap().is_ok());
+        producer.close().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drop_flushes_buffered_messages() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 1, 1).unwrap();
+        let mut client_options = client_options(&cluster);
+        // Keeps the message buffered until the producer is dropped.
+        client_options.insert("linger.ms".to_string(), "60000".to_string());
+        let producer = KafkaMessageQueueProducer::new(&client_options).unwrap();
+        let delivery = producer
+            .producer
+            .send_result(FutureRecord::<str, str>::to("orders").payload("created"))
+            .unwrap();
+        drop(producer);
+        assert!(delivery.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn transactions_need_transactional_id() {
+        let cluster = MockCluster::new(1).unwrap();
+        let producer = KafkaMessageQueueProducer::idempotent(&client_options(&cluster)).unwrap();
+        let e = producer.begin_transaction().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<KafkaProducerError>(),
+            Some(KafkaProducerError::NotTransactional)
+        ));
+    }
+
+    #[derive(Default)]
+    struct Received(std::sync::Mutex<Vec<String>>);
+
+    impl Received {
+        fn contents(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        /// Waits until `count` messages are received.
+        async fn wait_for(&self, count: usize) -> Vec<String> {
+            self.wait_until(|contents| contents.len() >= count).await
         }
+
+        async fn wait_until(&self, done: impl Fn(&[String]) -> bool) -> Vec<String> {
+            tokio::time::timeout(Duration::from_secs(30), async {
+                while !done(&self.contents()) {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .expect("messages weren't received");
+            self.contents()
+        }
+    }
+
+    fn record(content: &str, sp: Arc<Received>) -> super::super::ConsumerReturn<'_> {
+        sp.0.lock().unwrap().push(content.to_string());
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    /// Fails the first batch it receives.
+    fn record_batch(messages: &[Message], sp: Arc<Received>) -> super::super::ConsumerReturn<'_> {
+        let mut received = sp.0.lock().unwrap();
+        let first = received.is_empty();
+        received.extend(messages.iter().map(|m| String::from_utf8_lossy(&m.payload).into_owned()));
+        let result = match first {
+            true => Err(anyhow::anyhow!("unavailable")),
+            false => Ok(()),
+        };
+        Box::pin(std::future::ready(result))
+    }
+
+    fn with_options<C: MessageQueueConsumer>(
+        cluster: &MockCluster<'_, impl ClientContext>,
+        consumer: C,
+    ) -> C {
+        client_options(cluster)
+            .into_iter()
+            .fold(consumer, |consumer, (key, value)| {
+                consumer.add_option(&key, &value)
+            })
+            .add_option("group.id", "workers")
+            .add_option("auto.offset.reset", "earliest")
+            // The restarted consumer joins the group quickly.
+            .add_option("session.timeout.ms", "6000")
+            .add_option("heartbeat.interval.ms", "500")
+    }
+
+    fn consumer(
+        cluster: &MockCluster<'_, impl ClientContext>,
+        topic: &str,
+        received: Arc<Received>,
+    ) -> Arc<KafkaMultiTopicMessageQueueConsumer<Received>> {
+        let consumer = KafkaMultiTopicMessageQueueConsumer::new(received)
+            .add_topic(topic, record as ConsumerFn<Received>);
+        Arc::new(with_options(cluster, consumer))
+    }
+
+    async fn produce(cluster: &MockCluster<'_, impl ClientContext>, topic: &str, content: &str) {
+        let producer = KafkaMessageQueueProducer::new(&client_options(cluster)).unwrap();
+        producer.send(content, topic).await.unwrap();
+        producer.close().await.unwrap();
+    }
+
+    /// Subscribes to `orders`, which is paused and has a message, and to `events`, which has a
+    /// message produced after it.
+    ///
+    /// Receiving the event shows that the consumer is fetching, and holds the order.
+    async fn paused_orders_consumer(
+        cluster: &MockCluster<'_, impl ClientContext>,
+        received: Arc<Received>,
+    ) -> Arc<KafkaMultiTopicMessageQueueConsumer<Received>> {
+        for (topic, content) in [("orders", "created"), ("events", "fetching")] {
+            cluster.create_topic(topic, 1, 1).unwrap();
+            produce(cluster, topic, content).await;
+        }
+        let consumer = KafkaMultiTopicMessageQueueConsumer::new(received)
+            .add_topic("orders", record as ConsumerFn<Received>)
+            .add_topic("events", record as ConsumerFn<Received>);
+        let consumer = with_options(cluster, consumer);
+        consumer.control().pause("orders");
+        Arc::new(consumer)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn held_messages_are_delivered_after_resume() {
+        let cluster = MockCluster::new(1).unwrap();
+        let received = Arc::new(Received::default());
+        let consumer = paused_orders_consumer(&cluster, received.clone()).await;
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+
+        assert_eq!(received.wait_for(1).await, ["fetching"]);
+        consumer.control().resume("orders");
+        assert_eq!(received.wait_for(2).await, ["fetching", "created"]);
+        consumer.stop().await;
+        running.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn held_messages_are_not_committed() {
+        let cluster = MockCluster::new(1).unwrap();
+        let received = Arc::new(Received::default());
+        let paused = paused_orders_consumer(&cluster, received.clone()).await;
+        let running = tokio::spawn({
+            let paused = paused.clone();
+            async move { paused.run().await }
+        });
+        received.wait_for(1).await;
+        // Commits on shutdown while the message is held.
+        paused.stop().await;
+        running.await.unwrap();
+        assert_eq!(received.contents(), ["fetching"]);
+
+        let restarted = consumer(&cluster, "orders", received.clone());
+        let running = tokio::spawn({
+            let restarted = restarted.clone();
+            async move { restarted.run().await }
+        });
+        assert_eq!(received.wait_for(2).await, ["fetching", "created"]);
+        restarted.stop().await;
+        running.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn patterns_subscribe_to_matching_topics() {
+        let cluster = MockCluster::new(1).unwrap();
+        for topic in ["order.created", "order.item.created", "user.created"] {
+            cluster.create_topic(topic, 1, 1).unwrap();
+            produce(&cluster, topic, topic).await;
+        }
+        let received = Arc::new(Received::default());
+        let consumer = consumer(&cluster, "order.#", received.clone());
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+
+        let mut contents = received.wait_for(2).await;
+        contents.sort();
+        assert_eq!(contents, ["order.created", "order.item.created"]);
+        consumer.stop().await;
+        running.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_batches_are_consumed_again() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 1, 1).unwrap();
+        produce(&cluster, "orders", "created").await;
+        let received = Arc::new(Received::default());
+        let options = BatchOptions::new(10, Duration::from_millis(100));
+        let consumer = KafkaBatchMessageQueueConsumer::new(received.clone(), options)
+            .add_topic("orders", record_batch as BatchConsumerFn<Received>);
+        let consumer = Arc::new(with_options(&cluster, consumer));
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+
+        // Without a dead letter queue the failed batch isn't committed past.
+        assert_eq!(received.wait_for(2).await, ["created", "created"]);
+        consumer.stop().await;
+        running.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn throttled_consumers_stop_promptly() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 1, 1).unwrap();
+        produce(&cluster, "orders", "created").await;
+        produce(&cluster, "orders", "paid").await;
+        let received = Arc::new(Received::default());
+        let consumer = consumer(&cluster, "orders", received.clone());
+        // One message now, the next one in about 15 minutes.
+        consumer.control().set_rate_limit("orders", Some(RateLimit::new(0.001, 1)));
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+
+        assert_eq!(received.wait_for(1).await, ["created"]);
+        consumer.stop().await;
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("consumer waited for the rate limit")
+            .unwrap();
+        assert_eq!(received.contents(), ["created"]);
+    }
+
+    /// Runs the transactions with a transactional producer, then consumes them with a
+    /// `read_committed` consumer until `done`.
+    async fn consume_transactions(
+        transactions: &[(&str, bool)],
+        done: impl Fn(&[String]) -> bool,
+    ) -> Vec<String> {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("orders", 1, 1).unwrap();
+        let producer =
+            KafkaMessageQueueProducer::transactional(&client_options(&cluster), "orders-tx")
+                .unwrap();
+        for (content, commit) in transactions {
+            producer.begin_transaction().await.unwrap();
+            producer.send(content, "orders").await.unwrap();
+            match commit {
+                true => producer.commit_transaction().await.unwrap(),
+                false => producer.abort_transaction().await.unwrap(),
+            }
+        }
+        producer.close().await.unwrap();
+
+        let received = Arc::new(Received::default());
+        let consumer = KafkaMultiTopicMessageQueueConsumer::new(received.clone())
+            .add_topic("orders", record as ConsumerFn<Received>);
+        let consumer = Arc::new(
+            with_options(&cluster, consumer).add_option("isolation.level", "read_committed"),
+        );
+        let running = tokio::spawn({
+            let consumer = consumer.clone();
+            async move { consumer.run().await }
+        });
+        let contents = received.wait_until(done).await;
+        consumer.stop().await;
+        running.await.unwrap();
+        contents
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn committed_transactions_are_visible() {
+        let transactions = [("created", true), ("paid", true)];
+        let contents = consume_transactions(&transactions, |c| c.len() >= 2).await;
+        assert_eq!(contents, ["created", "paid"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn aborted_transactions_are_followed_by_new_ones() {
+        let transactions = [("created", true), ("cancelled", false), ("paid", true)];
+        // The mock cluster doesn't report aborted transactions to consumers, so unlike a real
+        // broker it doesn't hide "cancelled" from them. It only shows the producer recovers.
+        let contents =
+            consume_transactions(&transactions, |c| c.last().is_some_and(|m| m == "paid")).await;
+        assert_eq!(contents.first().map(String::as_str), Some("created"));
     }
 }
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use opentelemetry::metrics::{Counter, Histogram, Meter, ObservableGauge, Unit};
+use opentelemetry::{global, KeyValue};
+
+/// Returns the depth of every channel of an internal queue, or `None` after the queue is dropped.
+type QueueDepthSource = Box<dyn Fn() -> Option<Vec<(String, usize)>> + Send + Sync>;
+
+/// The latest lag of every (consumer, topic, partition).
+type ConsumerLags = Arc<Mutex<HashMap<(&'static str, String, i32), i64>>>;
+
+/// 消息队列的指标
+///
+/// 指标在第一次使用时从全局的 MeterProvider 创建，需要在此之前通过 `init_telemetry` 启用指标导出。
+pub(crate) struct MessageQueueMetrics {
+    consumed: Counter<u64>,
+    produced: Counter<u64>,
+    errors: Counter<u64>,
+    handler_duration: Histogram<f64>,
+    #[cfg_attr(not(feature = "kafka-mq"), allow(dead_code))]
+    consumer_lags: ConsumerLags,
+    #[cfg_attr(not(feature = "flume-mq"), allow(dead_code))]
+    queue_depth_sources: Arc<Mutex<Vec<QueueDepthSource>>>,
+    _consumer_lag: ObservableGauge<i64>,
+    _queue_depth: ObservableGauge<u64>,
+}
+
+static METRICS: OnceLock<MessageQueueMetrics> = OnceLock::new();
+
+pub(crate) fn metrics() -> &'static MessageQueueMetrics {
+    METRICS.get_or_init(|| MessageQueueMetrics::with_meter(global::meter("alice-infrastructure")))
+}
+
+impl MessageQueueMetrics {
+    fn with_meter(meter: Meter) -> Self {
+        let consumer_lags: ConsumerLags = Arc::default();
+        let queue_depth_sources: Arc<Mutex<Vec<QueueDepthSource>>> = Arc::default();
+
+        let lags = consumer_lags.clone();
+        let consumer_lag = meter
+            .i64_observable_gauge("messaging.consumer.lag")
+            .with_description("Messages not consumed yet in each partition")
+            .with_callback(move |observer| {
+                for ((consumer, topic, partition), lag) in lock(&lags).iter() {
+                    observer.observe(
+                        *lag,
+                        &[
+                            KeyValue::new("consumer", *consumer),
+                            KeyValue::new("topic", topic.clone()),
+                            KeyValue::new("partition", i64::from(*partition)),
+                        ],
+                    );
+                }
+            })
+            .init();
+
+        let sources = queue_depth_sources.clone();
+        let queue_depth = meter
+            .u64_observable_gauge("messaging.internal.queue_depth")
+            .with_description("Messages waiting in each subscriber group of the internal queue")
+            .with_callback(move |observer| {
+                lock(&sources).retain(|source| match source() {
+                    Some(depths) => {
+                        for (group, depth) in depths {
+                            observer.observe(depth as u64, &[KeyValue::new("group", group)]);
+                        }
+                        true
+                    }
+                    None => false,
+                });
+            })
+            .init();
+
+        Self {
+            consumed: meter
+                .u64_counter("messaging.consumer.messages")
+                .with_description("Messages received by consumers")
+                .init(),
+            produced: meter
+                .u64_counter("messaging.producer.messages")
+                .with_description("Messages sent by producers")
+                .init(),
+            errors: meter
+                .u64_counter("messaging.errors")
+                .with_description("Messages failed to be sent or handled")
+                .init(),
+            handler_duration: meter
+                .f64_histogram("messaging.handler.duration")
+                .with_description("Time spent handling a message or a batch, including retries")
+                .with_unit(Unit::new("s"))
+                .init(),
+            consumer_lags,
+            queue_depth_sources,
+            _consumer_lag: consumer_lag,
+            _queue_depth: queue_depth,
+        }
+    }
+
+    pub fn consumed(&self, consumer: &'static str, topic: &str, count: u64) {
+        self.consumed.add(
+            count,
+            &[
+                KeyValue::new("consumer", consumer),
+                KeyValue::new("topic", topic.to_string()),
+            ],
+        );
+    }
+
+    pub fn produced(&self, producer: &'static str, topic: &str) {
+        self.produced.add(
+            1,
+            &[
+                KeyValue::new("producer", producer),
+                KeyValue::new("topic", topic.to_string()),
+            ],
+        );
+    }
+
+    /// `component` 为出错的生产者或消费者，`error_type` 为 `send`、`decode`、`handler` 等
+    pub fn error(&self, component: &'static str, topic: &str, error_type: &'static str) {
+        self.errors.add(
+            1,
+            &[
+                KeyValue::new("component", component),
+                KeyValue::new("topic", topic.to_string()),
+                KeyValue::new("error.type", error_type),
+            ],
+        );
+    }
+
+    pub fn handled(&self, consumer: &'static str, topic: &str, duration: Duration, success: bool) {
+        self.handler_duration.record(
+            duration.as_secs_f64(),
+            &[
+                KeyValue::new("consumer", consumer),
+                KeyValue::new("topic", topic.to_string()),
+                KeyValue::new("outcome", if success { "success" } else { "failure" }),
+            ],
+        );
+    }
+
+    #[cfg_attr(not(feature = "kafka-mq"), allow(dead_code))]
+    pub fn consumer_lag(&self, consumer: &'static str, topic: &str, partition: i32, lag: i64) {
+        lock(&self.consumer_lags).insert((consumer, topic.to_string(), partition), lag);
+    }
+
+    #[cfg_attr(not(feature = "flume-mq"), allow(dead_code))]
+    pub fn register_queue_depth<F>(&self, source: F)
+    where
+        F: Fn() -> Option<Vec<(String, usize)>> + Send + Sync + 'static,
+    {
+        lock(&self.queue_depth_sources).push(Box::new(source));
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Weak;
+
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_sdk::{
+        metrics::{
+            data::{Gauge, ResourceMetrics, Sum, Temporality},
+            reader::{AggregationSelector, MetricReader, TemporalitySelector},
+            Aggregation, InstrumentKind, ManualReader, MeterProvider, Pipeline,
+        },
+        Resource,
+    };
+
+    use super::*;
+
+    /// Lets the test keep a handle on the reader it gives to the provider.
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl TemporalitySelector for SharedReader {
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl AggregationSelector for SharedReader {
+        fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+            self.0.aggregation(kind)
+        }
+    }
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+            self.0.shutdown()
+        }
+    }
+
+    fn setup() -> (MeterProvider, SharedReader, MessageQueueMetrics) {
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let provider = MeterProvider::builder().with_reader(reader.clone()).build();
+        let metrics = MessageQueueMetrics::with_meter(provider.meter("test"));
+        (provider, reader, metrics)
+    }
+
+    fn collect(reader: &SharedReader) -> ResourceMetrics {
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics).unwrap();
+        metrics
+    }
+
+    /// The value of `name` for the data point with exactly `attributes`.
+    fn value<T: Copy + 'static>(
+        metrics: &ResourceMetrics,
+        name: &str,
+        attributes: &[KeyValue],
+    ) -> Option<T> {
+        let data = metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+            .find(|metric| metric.name == name)?
+            .data
+            .as_any();
+        let points = match data.downcast_ref::<Sum<T>>() {
+            Some(sum) => &sum.data_points,
+            None => &data.downcast_ref::<Gauge<T>>()?.data_points,
+        };
+        points
+            .iter()
+            .find(|point| {
+                point.attributes.len() == attributes.len()
+                    && point.attributes.iter().all(|(key, value)| {
+                        attributes.iter().any(|kv| kv.key == *key && kv.value == *value)
+                    })
+            })
+            .map(|point| point.value)
+    }
+
+    #[test]
+    fn counters_are_labelled_by_component_and_topic() {
+        let (_provider, reader, metrics) = setup();
+        metrics.consumed("kafka", "orders", 3);
+        metrics.consumed("kafka", "orders", 2);
+        metrics.error("kafka", "orders", "decode");
+
+        let collected = collect(&reader);
+        let consumed = [
+            KeyValue::new("consumer", "kafka"),
+            KeyValue::new("topic", "orders"),
+        ];
+        assert_eq!(
+            value::<u64>(&collected, "messaging.consumer.messages", &consumed),
+            Some(5)
+        );
+        let error = [
+            KeyValue::new("component", "kafka"),
+            KeyValue::new("topic", "orders"),
+            KeyValue::new("error.type", "decode"),
+        ];
+        assert_eq!(
+            value::<u64>(&collected, "messaging.errors", &error),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn gauges_report_latest_lag_and_live_queue_depths() {
+        let (_provider, reader, metrics) = setup();
+        metrics.consumer_lag("kafka", "orders", 0, 10);
+        metrics.consumer_lag("kafka", "orders", 0, 4);
+        let alive = Arc::new(());
+        let queue = Arc::downgrade(&alive);
+        metrics.register_queue_depth(move || {
+            queue.upgrade().map(|_| vec![(String::from("default"), 2)])
+        });
+
+        let collected = collect(&reader);
+        let lag = [
+            KeyValue::new("consumer", "kafka"),
+            KeyValue::new("topic", "orders"),
+            KeyValue::new("partition", 0_i64),
+        ];
+        assert_eq!(
+            value::<i64>(&collected, "messaging.consumer.lag", &lag),
+            Some(4)
+        );
+        let depth = [KeyValue::new("group", "default")];
+        assert_eq!(
+            value::<u64>(&collected, "messaging.internal.queue_depth", &depth),
+            Some(2)
+        );
+
+        // A dropped queue is no longer observed.
+        drop(alive);
+        collect(&reader);
+        assert!(lock(&metrics.queue_depth_sources).is_empty());
+    }
+}
@@ -1,8 +1,31 @@
+pub mod batch;
+pub mod codec;
+pub mod control;
+pub mod deduplication;
+pub mod handler;
 #[cfg(feature = "flume-mq")]
 pub mod internal_message_queue_producer;
 #[cfg(feature = "kafka-mq")]
+pub mod kafka_admin;
+#[cfg(feature = "kafka-mq")]
 pub mod kafka_message_queue_producer;
+mod metrics;
+pub mod request_reply;
+pub mod topic;
+#[cfg(feature = "flume-mq")]
+pub mod write_ahead_log;
+pub use self::batch::*;
+pub use self::codec::*;
+pub use self::control::*;
+pub use self::deduplication::*;
+pub use self::handler::*;
 #[cfg(feature = "flume-mq")]
 pub use self::internal_message_queue_producer::*;
 #[cfg(feature = "kafka-mq")]
+pub use self::kafka_admin::*;
+#[cfg(feature = "kafka-mq")]
 pub use self::kafka_message_queue_producer::*;
+pub use self::request_reply::*;
+pub use self::topic::*;
+#[cfg(feature = "flume-mq")]
+pub use self::write_ahead_log::Receipt;
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::future::{ready, Future};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use alice_architecture::message_queue::producer::MessageQueueProducer;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::oneshot;
+
+use super::{ConsumerReturn, Message, MessageHandler};
+
+/// 请求的关联 id，回复消息原样带回
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+/// 请求方接收回复的主题
+pub const REPLY_TO_HEADER: &str = "reply-to";
+
+tokio::task_local! {
+    static CONSUMER_CONTEXT: ConsumerContext;
+}
+
+/// 正在处理的消息的上下文，在处理函数中通过 [`ConsumerContext::current`] 获取
+///
+/// 批处理函数没有上下文。
+#[derive(Debug, Clone)]
+pub struct ConsumerContext {
+    pub topic: String,
+    pub headers: HashMap<String, String>,
+}
+
+impl ConsumerContext {
+    pub(crate) fn scope<F: Future>(
+        message: &Message,
+        future: F,
+    ) -> impl Future<Output = F::Output> {
+        let context = Self {
+            topic: message.topic.clone(),
+            headers: message.headers.clone(),
+        };
+        CONSUMER_CONTEXT.scope(context, future)
+    }
+
+    /// 当前处理的消息的上下文，不在处理函数中时返回 `None`
+    pub fn current() -> Option<Self> {
+        CONSUMER_CONTEXT.try_with(Clone::clone).ok()
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.header(CORRELATION_ID_HEADER)
+    }
+
+    pub fn reply_to(&self) -> Option<&str> {
+        self.header(REPLY_TO_HEADER)
+    }
+
+    /// 回复请求方，消息不是请求时返回错误
+    pub async fn reply(
+        &self,
+        producer: &dyn MessageQueueProducer,
+        content: &[u8],
+    ) -> anyhow::Result<()> {
+        let (reply_to, correlation_id) = match (self.reply_to(), self.correlation_id()) {
+            (Some(reply_to), Some(correlation_id)) => (reply_to, correlation_id),
+            _ => anyhow::bail!("Message from {} is not a request", self.topic),
+        };
+        let headers = HashMap::from([(
+            CORRELATION_ID_HEADER.to_string(),
+            correlation_id.to_string(),
+        )]);
+        producer.send_with_headers(content, reply_to, &headers).await
+    }
+
+    pub async fn reply_object<T>(
+        &self,
+        producer: &dyn MessageQueueProducer,
+        content: &T,
+    ) -> anyhow::Result<()>
+    where
+        T: Serialize,
+    {
+        self.reply(producer, &serde_json::to_vec(content)?).await
+    }
+}
+
+/// 等待回复超时
+#[derive(Debug, thiserror::Error)]
+#[error("Request to {topic} timed out after {timeout:?}")]
+pub struct RequestTimeout {
+    pub topic: String,
+    pub timeout: Duration,
+}
+
+type PendingReplies = Arc<Mutex<HashMap<String, oneshot::Sender<Message>>>>;
+
+/// 基于消息队列的请求/回复客户端
+///
+/// 请求带上关联 id 与回复主题发送；回复主题上的消费者需要注册 [`RequestReplyClient::reply_handler`]，
+/// 收到回复后唤醒对应的请求。使用 Kafka 时每个实例需要使用各自的回复主题。
+#[derive(Clone)]
+pub struct RequestReplyClient {
+    producer: Arc<dyn MessageQueueProducer>,
+    reply_topic: String,
+    pending: PendingReplies,
+}
+
+impl RequestReplyClient {
+    pub fn new(producer: Arc<dyn MessageQueueProducer>, reply_topic: &str) -> Self {
+        Self {
+            producer,
+            reply_topic: reply_topic.to_string(),
+            pending: Arc::default(),
+        }
+    }
+
+    pub fn reply_topic(&self) -> &str {
+        &self.reply_topic
+    }
+
+    /// 发送请求并等待回复
+    pub async fn request(
+        &self,
+        topic: &str,
+        content: &[u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Message> {
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let (sender, receiver) = oneshot::channel();
+        lock(&self.pending).insert(correlation_id.clone(), sender);
+        let _pending = PendingGuard {
+            pending: &self.pending,
+            correlation_id: &correlation_id,
+        };
+
+        let headers = HashMap::from([
+            (CORRELATION_ID_HEADER.to_string(), correlation_id.clone()),
+            (REPLY_TO_HEADER.to_string(), self.reply_topic.clone()),
+        ]);
+        self.producer.send_with_headers(content, topic, &headers).await?;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => anyhow::bail!("Request to {topic} was dropped"),
+            Err(_) => Err(RequestTimeout {
+                topic: topic.to_string(),
+                timeout,
+            }
+            .into()),
+        }
+    }
+
+    /// 以 JSON 发送请求，并把回复反序列化为 `R`
+    pub async fn request_object<T, R>(
+        &self,
+        topic: &str,
+        content: &T,
+        timeout: Duration,
+    ) -> anyhow::Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let reply = self.request(topic, &serde_json::to_vec(content)?, timeout).await?;
+        Ok(serde_json::from_slice(&reply.payload)?)
+    }
+
+    /// 把回复交给等待中的请求，没有对应的请求时返回 `false`
+    pub fn complete(&self, message: &Message) -> bool {
+        complete(&self.pending, message)
+    }
+
+    /// 回复主题的处理函数
+    pub fn reply_handler<SP>(&self) -> MessageHandler<SP>
+    where
+        SP: Send + Sync + 'static,
+    {
+        let pending = self.pending.clone();
+        Arc::new(move |message: &Message, _| -> ConsumerReturn {
+            if !complete(&pending, message) {
+                tracing::debug!("No pending request for reply from {}", message.topic);
+            }
+            Box::pin(ready(Ok(())))
+        })
+    }
+}
+
+fn complete(pending: &PendingReplies, message: &Message) -> bool {
+    let sender = match message.header(CORRELATION_ID_HEADER) {
+        Some(correlation_id) => lock(pending).remove(correlation_id),
+        None => None,
+    };
+    match sender {
+        Some(sender) => sender.send(message.clone()).is_ok(),
+        None => false,
+    }
+}
+
+fn lock(pending: &PendingReplies) -> MutexGuard<'_, HashMap<String, oneshot::Sender<Message>>> {
+    pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// 请求完成、超时或者被取消时移除等待中的请求
+struct PendingGuard<'a> {
+    pending: &'a PendingReplies,
+    correlation_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        lock(self.pending).remove(self.correlation_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 记录发送的消息，不实际发送
+    #[derive(Default)]
+    struct RecordingProducer(Mutex<Vec<Message>>);
+
+    impl RecordingProducer {
+        fn sent(&self) -> Vec<Message> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MessageQueueProducer for RecordingProducer {
+        async fn send_with_headers(
+            &self,
+            content: &[u8],
+            topic: &str,
+            headers: &HashMap<String, String>,
+        ) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(Message {
+                topic: topic.to_string(),
+                payload: content.to_vec(),
+                headers: headers.clone(),
+            });
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn request_completes_with_matching_reply() {
+        let producer = Arc::new(RecordingProducer::default());
+        let client = RequestReplyClient::new(producer.clone(), "replies");
+        let responder = async {
+            let request = loop {
+                match producer.sent().pop() {
+                    Some(request) => break request,
+                    None => tokio::task::yield_now().await,
+                }
+            };
+            assert_eq!(request.topic, "pings");
+            let context = ConsumerContext {
+                topic: request.topic,
+                headers: request.headers,
+            };
+            context.reply(producer.as_ref(), b"pong").await.unwrap();
+            assert!(client.complete(&producer.sent().pop().unwrap()));
+        };
+        let (reply, ()) = tokio::join!(
+            client.request("pings", b"ping", Duration::from_secs(5)),
+            responder
+        );
+        let reply = reply.unwrap();
+        assert_eq!(reply.topic, "replies");
+        assert_eq!(reply.payload, b"pong");
+        // 完成后请求不再处于等待中
+        assert!(!client.complete(&reply));
+    }
+
+    #[tokio::test]
+    async fn request_times_out_without_reply() {
+        let client = RequestReplyClient::new(Arc::new(RecordingProducer::default()), "replies");
+        let e = client.request("pings", b"ping", Duration::from_millis(10)).await.unwrap_err();
+        assert!(e.is::<RequestTimeout>());
+        assert!(lock(&client.pending).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reply_needs_request_headers() {
+        let context = ConsumerContext {
+            topic: String::from("events"),
+            headers: HashMap::new(),
+        };
+        assert!(context.reply(&RecordingProducer::default(), b"pong").await.is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::cmp::Reverse;
+
+/// 判断主题是否匹配订阅的模式
+///
+/// 主题以 `.` 分隔为多段，`*` 匹配一段，`#` 匹配零段或多段，例如 `order.*` 匹配 `order.created`，
+/// `order.#` 匹配 `order` 与 `order.item.created`。Kafka 消费者按 [`topic_regex`] 订阅模式。
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let pattern = pattern.split('.').collect::<Vec<_>>();
+    let topic = topic.split('.').collect::<Vec<_>>();
+    segments_match(&pattern, &topic)
+}
+
+/// 模式中是否包含通配符
+pub fn is_topic_pattern(pattern: &str) -> bool {
+    pattern.split('.').any(|segment| segment == "*" || segment == "#")
+}
+
+/// 多个模式匹配同一主题时选择最具体的模式：字面段最多，其次 `#` 最少，再次 `*` 最少，仍然相同时按字典序
+pub fn most_specific_pattern<'a>(
+    patterns: impl IntoIterator<Item = &'a str>,
+    topic: &str,
+) -> Option<&'a str> {
+    patterns
+        .into_iter()
+        .filter(|pattern| topic_matches(pattern, topic))
+        .min_by_key(|pattern| {
+            let count =
+                |wildcard| pattern.split('.').filter(|segment| *segment == wildcard).count();
+            let literals = pattern.split('.').count() - count("*") - count("#");
+            (Reverse(literals), count("#"), count("*"), *pattern)
+        })
+}
+
+/// 模式对应的正则表达式，以 `^` 开头，用于 Kafka 的正则订阅
+pub fn topic_regex(pattern: &str) -> String {
+    let mut segments = pattern.split('.').collect::<Vec<_>>();
+    // Consecutive `#` match the same topics as one.
+    segments.dedup_by(|a, b| *a == "#" && *b == "#");
+    if segments == ["#"] {
+        return String::from("^.*$");
+    }
+    let mut regex = String::from("^");
+    let mut first = true;
+    for segment in segments {
+        match segment {
+            // A leading `#` takes the separator of the next segment.
+            "#" if first => regex.push_str("([^.]+\\.)*"),
+            "#" => regex.push_str("(\\.[^.]+)*"),
+            _ => {
+                if !first {
+                    regex.push_str("\\.");
+                }
+                first = false;
+                if segment == "*" {
+                    regex.push_str("[^.]+");
+                } else {
+                    for c in segment.chars() {
+                        if "\\^$.|?*+()[]{}".contains(c) {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                }
+            }
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn segments_match(pattern: &[&str], topic: &[&str]) -> bool {
+    match (pattern.split_first(), topic.split_first()) {
+        (None, None) => true,
+        (Some((&"#", rest)), _) => {
+            segments_match(rest, topic)
+                || (!topic.is_empty() && segments_match(pattern, &topic[1..]))
+        }
+        (Some((&"*", rest)), Some((_, topic_rest))) => segments_match(rest, topic_rest),
+        (Some((segment, rest)), Some((topic_segment, topic_rest))) => {
+            segment == topic_segment && segments_match(rest, topic_rest)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_exactly_one_segment() {
+        assert!(topic_matches("order.*", "order.created"));
+        assert!(topic_matches("*.created", "order.created"));
+        assert!(!topic_matches("order.*", "order"));
+        assert!(!topic_matches("order.*", "order.item.created"));
+    }
+
+    #[test]
+    fn hash_matches_any_number_of_segments() {
+        assert!(topic_matches("order.#", "order"));
+        assert!(topic_matches("order.#", "order.created"));
+        assert!(topic_matches("order.#", "order.item.created"));
+        assert!(topic_matches("#", "order.item.created"));
+        assert!(topic_matches("order.#.created", "order.created"));
+        assert!(topic_matches("order.#.created", "order.item.line.created"));
+        assert!(!topic_matches("order.#.created", "order.item.deleted"));
+        assert!(!topic_matches("order.#", "orders.created"));
+    }
+
+    #[test]
+    fn plain_segments_match_whole_segments() {
+        assert!(topic_matches("order.created", "order.created"));
+        assert!(!topic_matches("order.created", "order.created.v2"));
+        assert!(!topic_matches("order", "orders"));
+        assert!(!topic_matches("order.cre*", "order.created"));
+    }
+
+    #[test]
+    fn most_specific_pattern_wins() {
+        let patterns = ["#", "order.#", "order.*", "*.created", "order.created.#"];
+        let pick = |topic| most_specific_pattern(patterns, topic);
+        assert_eq!(pick("order.created"), Some("order.created.#"));
+        assert_eq!(pick("order.deleted"), Some("order.*"));
+        assert_eq!(pick("order.item.deleted"), Some("order.#"));
+        assert_eq!(pick("user.created"), Some("*.created"));
+        assert_eq!(pick("user.deleted"), Some("#"));
+        // Equally specific patterns are chosen by name, whatever their order.
+        assert_eq!(
+            most_specific_pattern(["order.*", "*.created"], "order.created"),
+            Some("*.created")
+        );
+        assert_eq!(
+            most_specific_pattern(["*.created", "order.*"], "order.created"),
+            Some("*.created")
+        );
+        assert_eq!(most_specific_pattern(["order.*"], "user.created"), None);
+    }
+
+    #[test]
+    fn patterns_translate_to_regexes() {
+        assert_eq!(topic_regex("order.*"), "^order\\.[^.]+$");
+        assert_eq!(topic_regex("order.#"), "^order(\\.[^.]+)*$");
+        assert_eq!(topic_regex("#.created"), "^([^.]+\\.)*created$");
+        assert_eq!(
+            topic_regex("order.#.created"),
+            "^order(\\.[^.]+)*\\.created$"
+        );
+        assert_eq!(topic_regex("#.#"), "^.*$");
+        assert_eq!(topic_regex("order+v2.*"), "^order\\+v2\\.[^.]+$");
+    }
+
+    #[test]
+    fn detects_patterns() {
+        assert!(is_topic_pattern("order.*"));
+        assert!(is_topic_pattern("#"));
+        assert!(!is_topic_pattern("order.created"));
+        assert!(!is_topic_pattern("order.cre*"));
+    }
+}
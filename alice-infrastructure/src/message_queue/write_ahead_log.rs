@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::WalSyncPolicy;
+
+use super::{InternalMessage, DEFAULT_GROUP};
+
+/// Number of acknowledged messages after which the log is rewritten.
+const COMPACT_THRESHOLD: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord {
+    Put {
+        sequence: u64,
+        target: String,
+        body: Vec<u8>,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        /// Logs written before subscriber groups existed only delivered to the default group.
+        #[serde(default)]
+        groups: BTreeSet<String>,
+    },
+    Ack {
+        sequence: u64,
+        /// Acks without a group acknowledge every group.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+}
+
+struct PendingMessage {
+    target: String,
+    body: Vec<u8>,
+    headers: HashMap<String, String>,
+    /// The subscriber groups that haven't acknowledged the message yet.
+    groups: BTreeSet<String>,
+}
+
+impl PendingMessage {
+    fn to_record(&self, sequence: u64) -> WalRecord {
+        WalRecord::Put {
+            sequence,
+            target: self.target.clone(),
+            body: self.body.clone(),
+            headers: self.headers.clone(),
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+struct WalState {
+    file: File,
+    next_sequence: u64,
+    pending: BTreeMap<u64, PendingMessage>,
+    acked_since_compaction: usize,
+}
+
+/// 内部消息队列的写前日志
+///
+/// 每条消息入队前以 JSON 行追加到日志中，并记录接收该消息的订阅组；每个订阅组处理完成后追加确认记录。
+/// 重新打开日志时，没有确认的消息按原来的顺序重新投递到没有确认的订阅组。
+/// 确认记录不同步到磁盘，丢失时消息会被再次投递。
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    sync: WalSyncPolicy,
+    state: Mutex<WalState>,
+}
+
+/// 需要重新投递的消息，按订阅组分开
+pub(crate) type ReplayedMessages = HashMap<String, Vec<InternalMessage>>;
+
+impl WriteAheadLog {
+    /// 打开日志，返回日志与需要重新投递的消息
+    pub fn open(
+        path: impl AsRef<Path>,
+        sync: WalSyncPolicy,
+    ) -> anyhow::Result<(Arc<Self>, ReplayedMessages)> {
+        let path = path.as_ref().to_path_buf();
+        let pending = Self::replay(&path)?;
+        let next_sequence = pending.keys().next_back().map_or(0, |sequence| sequence + 1);
+        let file = Self::rewrite(&path, &pending)?;
+        let write_ahead_log = Arc::new(Self {
+            path,
+            sync,
+            state: Mutex::new(WalState {
+                file,
+                next_sequence,
+                pending: BTreeMap::new(),
+                acked_since_compaction: 0,
+            }),
+        });
+
+        let mut replayed = ReplayedMessages::new();
+        for (sequence, message) in &pending {
+            for group in &message.groups {
+                replayed
+                    .entry(group.clone())
+                    .or_default()
+                    .push(write_ahead_log.internal_message(*sequence, message, group));
+            }
+        }
+        write_ahead_log.lock().pending = pending;
+        for (group, messages) in &replayed {
+            tracing::info!(
+                "Replaying {} messages of group {group} from write ahead log {}",
+                messages.len(),
+                write_ahead_log.path.display()
+            );
+        }
+        Ok((write_ahead_log, replayed))
+    }
+
+    /// 写入一条投递给 `groups` 的消息，按 `groups` 的顺序返回每个组带有确认回执的消息
+    pub fn append(
+        self: &Arc<Self>,
+        target: &str,
+        body: Vec<u8>,
+        headers: HashMap<String, String>,
+        groups: &[&str],
+    ) -> anyhow::Result<Vec<InternalMessage>> {
+        // Nobody receives the message, so it needn't survive a restart.
+        if groups.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut state = self.lock();
+        let sequence = state.next_sequence;
+        let message = PendingMessage {
+            target: target.to_string(),
+            body,
+            headers,
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        };
+        write_record(&mut state.file, &message.to_record(sequence))?;
+        if self.sync == WalSyncPolicy::Always {
+            state.file.sync_data()?;
+        }
+        state.next_sequence += 1;
+        let internal_messages = groups
+            .iter()
+            .map(|group| self.internal_message(sequence, &message, group))
+            .collect();
+        state.pending.insert(sequence, message);
+        Ok(internal_messages)
+    }
+
+    fn ack(&self, sequence: u64, group: &str) {
+        let mut state = self.lock();
+        let Some(message) = state.pending.get_mut(&sequence) else {
+            return;
+        };
+        if !message.groups.remove(group) {
+            return;
+        }
+        if message.groups.is_empty() {
+            state.pending.remove(&sequence);
+        }
+        let ack = WalRecord::Ack {
+            sequence,
+            group: Some(group.to_string()),
+        };
+        if let Err(e) = write_record(&mut state.file, &ack) {
+            tracing::error!("Unable to write ack to {}: {e}", self.path.display());
+            return;
+        }
+        state.acked_since_compaction += 1;
+        if state.acked_since_compaction >= COMPACT_THRESHOLD {
+            match Self::rewrite(&self.path, &state.pending) {
+                Ok(file) => {
+                    state.file = file;
+                    state.acked_since_compaction = 0;
+                }
+                Err(e) => tracing::error!("Unable to compact {}: {e}", self.path.display()),
+            }
+        }
+    }
+
+    fn internal_message(
+        self: &Arc<Self>,
+        sequence: u64,
+        message: &PendingMessage,
+        group: &str,
+    ) -> InternalMessage {
+        InternalMessage {
+            target: message.target.clone(),
+            body: message.body.clone(),
+            headers: message.headers.clone(),
+            receipt: Some(Receipt {
+                write_ahead_log: self.clone(),
+                sequence,
+                group: Arc::from(group),
+            }),
+        }
+    }
+
+    fn replay(path: &Path) -> anyhow::Result<BTreeMap<u64, PendingMessage>> {
+        let mut pending = BTreeMap::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(pending),
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            // The last line may be partially written if the process crashed while appending.
+            match serde_json::from_str::<WalRecord>(&line) {
+                Ok(WalRecord::Put {
+                    sequence,
+                    target,
+                    body,
+                    headers,
+                    mut groups,
+                }) => {
+                    if groups.is_empty() {
+                        groups.insert(DEFAULT_GROUP.to_string());
+                    }
+                    pending.insert(
+                        sequence,
+                        PendingMessage {
+                            target,
+                            body,
+                            headers,
+                            groups,
+                        },
+                    );
+                }
+                Ok(WalRecord::Ack {
+                    sequence,
+                    group: Some(group),
+                }) => {
+                    if let Some(message) = pending.get_mut(&sequence) {
+                        message.groups.remove(&group);
+                        if message.groups.is_empty() {
+                            pending.remove(&sequence);
+                        }
+                    }
+                }
+                Ok(WalRecord::Ack {
+                    sequence,
+                    group: None,
+                }) => {
+                    pending.remove(&sequence);
+                }
+                Err(e) => tracing::warn!("Skipping corrupted record in {}: {e}", path.display()),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Rewrites the log with only the pending messages, and returns it opened for appending.
+    fn rewrite(path: &Path, pending: &BTreeMap<u64, PendingMessage>) -> anyhow::Result<File> {
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            for (sequence, message) in pending {
+                write_record(&mut file, &message.to_record(*sequence))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, path)?;
+        Ok(OpenOptions::new().append(true).open(path)?)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WalState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn write_record(file: &mut File, record: &WalRecord) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// 写前日志中一条消息在一个订阅组中的回执，该组处理完成或丢弃消息后确认
+///
+/// 消息投递给多个订阅组时每个组各有一个回执，所有订阅组都确认后才从日志中移除；重复确认没有影响。
+#[derive(Clone)]
+pub struct Receipt {
+    write_ahead_log: Arc<WriteAheadLog>,
+    sequence: u64,
+    group: Arc<str>,
+}
+
+impl Receipt {
+    pub fn ack(&self) {
+        self.write_ahead_log.ack(self.sequence, &self.group);
+    }
+}
+
+impl fmt::Debug for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receipt")
+            .field("sequence", &self.sequence)
+            .field("group", &self.group)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(write_ahead_log: &Arc<WriteAheadLog>, body: &str) -> Vec<InternalMessage> {
+        let headers = HashMap::from([("message-id".to_string(), body.to_string())]);
+        write_ahead_log
+            .append(
+                "orders",
+                body.as_bytes().to_vec(),
+                headers,
+                &["audit", "billing"],
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn replays_unacked_messages_into_their_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.wal");
+        {
+            let (write_ahead_log, replayed) =
+                WriteAheadLog::open(&path, WalSyncPolicy::Always).unwrap();
+            assert!(replayed.is_empty());
+            let first = append(&write_ahead_log, "first");
+            let second = append(&write_ahead_log, "second");
+            first[0].ack();
+            first[1].ack();
+            second[0].ack();
+            // Acking twice has no effect.
+            second[0].ack();
+        }
+
+        let (write_ahead_log, mut replayed) =
+            WriteAheadLog::open(&path, WalSyncPolicy::Always).unwrap();
+        assert_eq!(replayed.keys().collect::<Vec<_>>(), ["billing"]);
+        let billing = replayed.remove("billing").unwrap();
+        assert_eq!(billing.len(), 1);
+        assert_eq!(billing[0].target, "orders");
+        assert_eq!(billing[0].body, b"second");
+        assert_eq!(billing[0].headers["message-id"], "second");
+
+        // New messages continue after the replayed sequence.
+        let third = append(&write_ahead_log, "third");
+        billing[0].ack();
+        drop((write_ahead_log, third));
+
+        let (_, replayed) = WriteAheadLog::open(&path, WalSyncPolicy::Never).unwrap();
+        let bodies = |group: &str| {
+            replayed[group].iter().map(|message| message.body.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(bodies("audit"), [b"third"]);
+        assert_eq!(bodies("billing"), [b"third"]);
+    }
+
+    #[test]
+    fn replays_records_without_groups_into_default_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.wal");
+        fs::write(
+            &path,
+            "{\"op\":\"put\",\"sequence\":0,\"target\":\"orders\",\"body\":[49]}\n\
+             {\"op\":\"put\",\"sequence\":1,\"target\":\"orders\",\"body\":[50]}\n\
+             {\"op\":\"ack\",\"sequence\":0}\n\
+             {\"op\":\"put\",\"seq",
+        )
+        .unwrap();
+        let (_, replayed) = WriteAheadLog::open(&path, WalSyncPolicy::Always).unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[DEFAULT_GROUP].len(), 1);
+        assert_eq!(replayed[DEFAULT_GROUP][0].body, b"2");
+    }
+}
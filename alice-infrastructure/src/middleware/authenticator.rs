@@ -0,0 +1,621 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[cfg(feature = "tls-openssl")]
+use actix_tls::accept::openssl::TlsStream as OpensslStream;
+#[cfg(feature = "tls-rustls")]
+use actix_tls::accept::rustls_0_21::TlsStream as RustlsStream;
+use actix_web::dev::{Extensions, ServiceRequest};
+#[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+use actix_web::rt::net::TcpStream;
+use alice_architecture::jwt_payload::Payload;
+use serde_json::{json, Map};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::{authorization::GrantedAccess, claims::Claims};
+use crate::{
+    config::{ClaimsMappingConfig, JwtValidationConfig},
+    error::{AliceCommonError, AliceError},
+};
+
+/// 通过认证的身份
+#[derive(Clone)]
+pub struct Identity {
+    pub payload: Payload,
+    pub granted: GrantedAccess,
+    pub claims: Claims,
+}
+
+impl Identity {
+    /// 按声明映射配置由令牌的声明生成
+    pub fn from_claims(claims: Claims, mapping: &ClaimsMappingConfig) -> Result<Self, AliceError> {
+        Ok(Self {
+            payload: claims.to_payload(mapping)?,
+            granted: claims.granted_access(mapping),
+            claims,
+        })
+    }
+
+    /// 由客户端身份生成，角色按 `client:role` 写入 `resource_access`
+    pub fn from_client(client: &ClientIdentity) -> Self {
+        let mut resource_access = HashMap::<String, Vec<&str>>::new();
+        for role in &client.roles {
+            if let Some((client_id, role)) = role.split_once(':') {
+                resource_access.entry(client_id.to_string()).or_default().push(role);
+            }
+        }
+        let resource_access = resource_access
+            .into_iter()
+            .map(|(client_id, roles)| (client_id, json!({ "roles": roles })))
+            .collect::<HashMap<_, _>>();
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), json!(client.subject));
+        claims.insert("preferred_username".to_string(), json!(client.name));
+        claims.insert("resource_access".to_string(), json!(resource_access));
+        Self {
+            payload: Payload {
+                iss: String::new(),
+                sub: client.subject,
+                preferred_username: client.name.clone(),
+                resource_access,
+                scope: String::new(),
+            },
+            granted: GrantedAccess {
+                roles: client.roles.clone(),
+                scopes: HashSet::new(),
+            },
+            claims: Claims(claims),
+        }
+    }
+}
+
+/// JWT 以外的认证方式，由 `JwtValidationMiddleware` 在请求没有 JWT 时依次调用
+#[async_trait::async_trait(?Send)]
+pub trait Authenticator: Send + Sync {
+    /// 请求中没有该方式的凭据时返回 `Ok(None)`，凭据无效时返回错误
+    ///
+    /// `config` 为中间件（或其作用域）的验证配置，令牌类的凭据应与 JWT 一样检查 `aud` 与 `iss`
+    async fn authenticate(
+        &self,
+        req: &ServiceRequest,
+        config: &JwtValidationConfig,
+    ) -> Result<Option<Identity>, AliceError>;
+}
+
+/// `Authorization: Bearer <token>` 中的令牌
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let mut parts = authorization.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("Bearer"), Some(token)) => Some(token),
+        _ => None,
+    }
+}
+
+/// 能解析出 JWT 头部的令牌，这些令牌由 `JwtValidationMiddleware` 验证
+pub fn is_jwt(token: &str) -> bool {
+    jsonwebtoken::decode_header(token).is_ok()
+}
+
+/// API key 或者客户端证书对应的客户端
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub subject: Uuid,
+    pub name: String,
+    /// 格式为 `client:role`，与令牌的角色一样经过访问策略检查
+    pub roles: HashSet<String>,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// 按凭据的 SHA-256 摘要（十六进制）查找客户端，API key 与证书都不保存原文
+#[async_trait::async_trait]
+pub trait CredentialRepository: Send + Sync {
+    async fn find(&self, fingerprint: &str) -> anyhow::Result<Option<ClientIdentity>>;
+}
+
+/// 凭据的 SHA-256 摘要（十六进制）
+///
+/// 摘要不加盐，API key 必须是足够长的随机值（例如 32 字节的随机数），否则可以由摘要穷举出原文
+pub fn fingerprint(credential: &[u8]) -> String {
+    hex::encode(Sha256::digest(credential))
+}
+
+/// 内存中的凭据，用于静态配置的 API key 与测试
+#[derive(Default)]
+pub struct MemoryCredentialRepository {
+    credentials: Mutex<HashMap<String, ClientIdentity>>,
+}
+
+impl MemoryCredentialRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加已经计算好摘要的凭据
+    pub fn insert(&self, fingerprint: &str, identity: ClientIdentity) {
+        let mut credentials = self.credentials.lock().unwrap_or_else(|e| e.into_inner());
+        credentials.insert(fingerprint.to_ascii_lowercase(), identity);
+    }
+
+    pub fn api_key(self, api_key: &str, identity: ClientIdentity) -> Self {
+        self.insert(&fingerprint(api_key.as_bytes()), identity);
+        self
+    }
+
+    /// 添加 DER 编码的客户端证书
+    pub fn certificate(self, der: &[u8], identity: ClientIdentity) -> Self {
+        self.insert(&fingerprint(der), identity);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl CredentialRepository for MemoryCredentialRepository {
+    async fn find(&self, fingerprint: &str) -> anyhow::Result<Option<ClientIdentity>> {
+        let credentials = self.credentials.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(credentials.get(fingerprint).cloned())
+    }
+}
+
+async fn find_client(
+    repository: &dyn CredentialRepository,
+    fingerprint: &str,
+    kind: &str,
+) -> Result<Identity, AliceError> {
+    let invalid = |error_description: String| {
+        AliceError::new(AliceCommonError::InvalidToken { error_description })
+    };
+    let client = repository
+        .find(fingerprint)
+        .await
+        .map_err(|e| AliceError::new(AliceCommonError::InternalError { source: e }))?
+        .ok_or_else(|| invalid(format!("Invalid {kind}.")))?;
+    if client.expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+        return Err(invalid(format!(
+            "The {kind} of {} is expired.",
+            client.name
+        )));
+    }
+    Ok(Identity::from_client(&client))
+}
+
+/// API key 认证，key 放在 `X-Api-Key` 请求头或者 `Authorization: ApiKey <key>` 中
+///
+/// key 按 [`fingerprint`] 查找，只能使用随机生成的高熵 key，不能使用口令
+pub struct ApiKeyAuthenticator {
+    repository: Arc<dyn CredentialRepository>,
+    header: String,
+}
+
+impl ApiKeyAuthenticator {
+    pub fn new(repository: Arc<dyn CredentialRepository>) -> Self {
+        Self {
+            repository,
+            header: String::from("X-Api-Key"),
+        }
+    }
+
+    /// 放置 API key 的请求头，默认为 `X-Api-Key`
+    pub fn header(mut self, header: &str) -> Self {
+        self.header = header.to_string();
+        self
+    }
+
+    fn api_key<'a>(&self, req: &'a ServiceRequest) -> Option<&'a str> {
+        if let Some(api_key) = req.headers().get(&self.header) {
+            return api_key.to_str().ok();
+        }
+        let authorization = req.headers().get("Authorization")?.to_str().ok()?;
+        let (scheme, api_key) = authorization.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("ApiKey").then(|| api_key.trim())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Authenticator for ApiKeyAuthenticator {
+    async fn authenticate(
+        &self,
+        req: &ServiceRequest,
+        _: &JwtValidationConfig,
+    ) -> Result<Option<Identity>, AliceError> {
+        let Some(api_key) = self.api_key(req) else {
+            return Ok(None);
+        };
+        let fingerprint = fingerprint(api_key.as_bytes());
+        find_client(self.repository.as_ref(), &fingerprint, "API key").await.map(Some)
+    }
+}
+
+/// TLS 连接上客户端证书的摘要
+///
+/// 在 `HttpServer::on_connect` 中通过 [`ClientCertificate::on_connect`] 从 TLS 连接取得证书放入连接数据，
+/// [`ClientCertificateAuthenticator`] 从中读取。
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub fingerprint: String,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Self {
+        Self {
+            fingerprint: fingerprint(der),
+        }
+    }
+
+    /// 取得客户端在 TLS 连接上出示的证书
+    ///
+    /// 支持 `tls-rustls` 与 `tls-openssl` feature 对应的连接，其他连接或者客户端没有出示证书时返回 `None`
+    #[allow(unused_variables)]
+    pub fn from_connection(connection: &dyn Any) -> Option<Self> {
+        #[cfg(feature = "tls-rustls")]
+        if let Some(stream) = connection.downcast_ref::<RustlsStream<TcpStream>>() {
+            let (_, session) = stream.get_ref();
+            return session.peer_certificates()?.first().map(|der| Self::from_der(&der.0));
+        }
+        #[cfg(feature = "tls-openssl")]
+        if let Some(stream) = connection.downcast_ref::<OpensslStream<TcpStream>>() {
+            let der = stream.ssl().peer_certificate()?.to_der().ok()?;
+            return Some(Self::from_der(&der));
+        }
+        None
+    }
+
+    /// `HttpServer::on_connect` 的回调，把客户端证书放入连接数据
+    ///
+    /// ```ignore
+    /// HttpServer::new(app).on_connect(ClientCertificate::on_connect)
+    /// ```
+    pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+        if let Some(certificate) = Self::from_connection(connection) {
+            data.insert(certificate);
+        }
+    }
+}
+
+/// 客户端证书认证，按证书的摘要查找客户端
+pub struct ClientCertificateAuthenticator {
+    repository: Arc<dyn CredentialRepository>,
+}
+
+impl ClientCertificateAuthenticator {
+    pub fn new(repository: Arc<dyn CredentialRepository>) -> Self {
+        Self { repository }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Authenticator for ClientCertificateAuthenticator {
+    async fn authenticate(
+        &self,
+        req: &ServiceRequest,
+        _: &JwtValidationConfig,
+    ) -> Result<Option<Identity>, AliceError> {
+        let Some(certificate) = req.conn_data::<ClientCertificate>() else {
+            return Ok(None);
+        };
+        find_client(
+            self.repository.as_ref(),
+            &certificate.fingerprint,
+            "client certificate",
+        )
+        .await
+        .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{test::TestRequest, web, App, HttpResponse, HttpServer};
+    use reqwest::Client as ReqwestClient;
+
+    use super::*;
+    use crate::access_policy::{AccessPolicy, AccessRule};
+    use crate::middleware::authorization::{
+        AliceScopedConfig, JwtValidationMiddleware, MemoryKeyStorage,
+    };
+    use crate::middleware::principal_resolver::MemoryPrincipalResolver;
+
+    fn client(name: &str, expires_at: Option<SystemTime>) -> ClientIdentity {
+        ClientIdentity {
+            subject: Uuid::from_u128(1),
+            name: name.to_string(),
+            roles: HashSet::from([
+                String::from("fe:user"),
+                String::from("fe:admin"),
+                String::from("device:hpc"),
+            ]),
+            expires_at,
+        }
+    }
+
+    fn repository() -> Arc<MemoryCredentialRepository> {
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        Arc::new(
+            MemoryCredentialRepository::new()
+                .api_key("secret", client("ci", None))
+                .api_key("stale", client("old-ci", Some(expired)))
+                .certificate(b"agent certificate", client("agent", None)),
+        )
+    }
+
+    async fn authenticate(
+        authenticator: &ApiKeyAuthenticator,
+        header: (&str, &str),
+    ) -> Result<Option<Identity>, AliceError> {
+        let req = TestRequest::default().insert_header(header).to_srv_request();
+        authenticator.authenticate(&req, &JwtValidationConfig::default()).await
+    }
+
+    #[actix_web::test]
+    async fn api_keys_are_read_from_headers() {
+        let authenticator = ApiKeyAuthenticator::new(repository());
+        for header in [
+            ("X-Api-Key", "secret"),
+            ("Authorization", "ApiKey secret"),
+            ("Authorization", "apikey  secret "),
+        ] {
+            let identity = authenticate(&authenticator, header).await.unwrap().unwrap();
+            assert_eq!(identity.payload.preferred_username, "ci", "{header:?}");
+        }
+        // Other credentials are left to the other authenticators.
+        assert!(
+            authenticate(&authenticator, ("Authorization", "Bearer secret"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let req = TestRequest::default().to_srv_request();
+        let config = JwtValidationConfig::default();
+        assert!(authenticator.authenticate(&req, &config).await.unwrap().is_none());
+
+        let authenticator = ApiKeyAuthenticator::new(repository()).header("X-Key");
+        assert!(authenticate(&authenticator, ("X-Key", "secret")).await.unwrap().is_some());
+        assert!(authenticate(&authenticator, ("X-Api-Key", "secret")).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn unknown_and_expired_api_keys_are_rejected() {
+        let authenticator = ApiKeyAuthenticator::new(repository());
+        let e = authenticate(&authenticator, ("X-Api-Key", "guess")).await.err().unwrap();
+        assert!(e.to_string().contains("Invalid API key."), "{e}");
+        let e = authenticate(&authenticator, ("X-Api-Key", "stale")).await.err().unwrap();
+        assert!(
+            e.to_string().contains("The API key of old-ci is expired."),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn client_roles_are_granted_and_written_to_resource_access() {
+        let identity = Identity::from_client(&client("ci", None));
+        assert_eq!(identity.payload.sub, Uuid::from_u128(1));
+        assert_eq!(identity.granted.roles, client("ci", None).roles);
+        let mut fe_roles = identity.payload.resource_access["fe"]["roles"].clone();
+        fe_roles.as_array_mut().unwrap().sort_by_key(|role| role.to_string());
+        assert_eq!(fe_roles, json!(["admin", "user"]));
+        assert_eq!(
+            identity.claims.get("resource_access.device.roles"),
+            Some(&json!(["hpc"]))
+        );
+        // The claims map back to the same roles.
+        let granted = identity.claims.granted_access(&ClaimsMappingConfig::default());
+        assert_eq!(granted.roles, identity.granted.roles);
+    }
+
+    /// Serves the middleware with a client certificate authenticator over connections presenting
+    /// `der`, returning the server's URL.
+    fn serve_with_certificate(der: &'static [u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            let key_storage = MemoryKeyStorage::new(Arc::new(ReqwestClient::new()));
+            let middleware = JwtValidationMiddleware::new(
+                Arc::new(key_storage),
+                JwtValidationConfig::default(),
+                AccessPolicy::default().rule(AccessRule::allow("/**")),
+                Arc::new(MemoryPrincipalResolver::new()),
+            )
+            .authenticator(Arc::new(ClientCertificateAuthenticator::new(repository())));
+            App::new().wrap(middleware).route(
+                "/whoami",
+                web::get().to(|scoped: AliceScopedConfig| async move {
+                    let device = scoped.device_info.unwrap();
+                    HttpResponse::Ok().body(device.preferred_username)
+                }),
+            )
+        })
+        .on_connect(move |_: &dyn Any, data: &mut Extensions| {
+            data.insert(ClientCertificate::from_der(der));
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap();
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    #[actix_web::test]
+    async fn client_certificates_identify_the_connection() {
+        let url = serve_with_certificate(b"agent certificate");
+        let res = reqwest::get(format!("{url}/whoami")).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "agent");
+
+        let url = serve_with_certificate(b"unknown certificate");
+        let res = reqwest::get(format!("{url}/whoami")).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Requests without a certificate aren't authenticated by it.
+        let authenticator = ClientCertificateAuthenticator::new(repository());
+        let req = TestRequest::default().to_srv_request();
+        let config = JwtValidationConfig::default();
+        assert!(authenticator.authenticate(&req, &config).await.unwrap().is_none());
+    }
+
+    /// A CA and the DER encoded server and client certificates it signed, with their keys.
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    struct Certificates {
+        ca: Vec<u8>,
+        server: Vec<u8>,
+        server_key: Vec<u8>,
+        client: Vec<u8>,
+        client_identity: reqwest::Identity,
+    }
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    fn certificates() -> Certificates {
+        use base64::Engine;
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        // The default subject is shared by all certificates, which would make them look self-signed.
+        params.distinguished_name.push(DnType::CommonName, "alice CA");
+        let ca = Certificate::from_params(params).unwrap();
+        let server =
+            Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+        let client =
+            Certificate::from_params(CertificateParams::new(vec!["agent".to_string()])).unwrap();
+        // Each signature is different, so the client certificate is serialized only once.
+        let client_der = client.serialize_der_with_signer(&ca).unwrap();
+        let client_pem = base64::engine::general_purpose::STANDARD.encode(&client_der);
+        let client_pem = client_pem.as_bytes().chunks(64).map(String::from_utf8_lossy);
+        let client_pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            client_pem.collect::<Vec<_>>().join("\n")
+        );
+        let client_identity = reqwest::Identity::from_pem(
+            format!("{client_pem}{}", client.serialize_private_key_pem()).as_bytes(),
+        )
+        .unwrap();
+        Certificates {
+            ca: ca.serialize_der().unwrap(),
+            server: server.serialize_der_with_signer(&ca).unwrap(),
+            server_key: server.serialize_private_key_der(),
+            client: client_der,
+            client_identity,
+        }
+    }
+
+    /// Returns the fingerprint the server read from the client certificate of a request to `url`,
+    /// presenting `identity` if given.
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    async fn presented_fingerprint(
+        url: &str,
+        ca: &[u8],
+        identity: Option<reqwest::Identity>,
+    ) -> String {
+        let mut client = ReqwestClient::builder()
+            .use_rustls_tls()
+            .add_root_certificate(reqwest::Certificate::from_der(ca).unwrap());
+        if let Some(identity) = identity {
+            client = client.identity(identity);
+        }
+        let res = client.build().unwrap().get(url).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        res.text().await.unwrap()
+    }
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-openssl"))]
+    fn fingerprint_app() -> App<
+        impl actix_web::dev::ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new().route(
+            "/",
+            web::get().to(|req: actix_web::HttpRequest| async move {
+                let certificate = req.conn_data::<ClientCertificate>();
+                certificate
+                    .map(|certificate| certificate.fingerprint.clone())
+                    .unwrap_or_default()
+            }),
+        )
+    }
+
+    #[cfg(feature = "tls-rustls")]
+    #[actix_web::test]
+    async fn client_certificates_are_read_from_rustls_connections() {
+        use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+        use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+
+        let certificates = certificates();
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(certificates.ca.clone())).unwrap();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(
+                vec![Certificate(certificates.server.clone())],
+                PrivateKey(certificates.server_key.clone()),
+            )
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "https://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let server = HttpServer::new(fingerprint_app)
+            .on_connect(ClientCertificate::on_connect)
+            .workers(1)
+            .listen_rustls_0_21(listener, config)
+            .unwrap();
+        actix_web::rt::spawn(server.run());
+
+        let presented =
+            presented_fingerprint(&url, &certificates.ca, Some(certificates.client_identity)).await;
+        assert_eq!(presented, fingerprint(&certificates.client));
+        assert_eq!(
+            presented_fingerprint(&url, &certificates.ca, None).await,
+            ""
+        );
+    }
+
+    #[cfg(feature = "tls-openssl")]
+    #[actix_web::test]
+    async fn client_certificates_are_read_from_openssl_connections() {
+        use openssl::pkey::PKey;
+        use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+        use openssl::x509::X509;
+
+        let certificates = certificates();
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&X509::from_der(&certificates.server).unwrap()).unwrap();
+        builder
+            .set_private_key(&PKey::private_key_from_der(&certificates.server_key).unwrap())
+            .unwrap();
+        builder
+            .cert_store_mut()
+            .add_cert(X509::from_der(&certificates.ca).unwrap())
+            .unwrap();
+        builder.set_verify(SslVerifyMode::PEER);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "https://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        let server = HttpServer::new(fingerprint_app)
+            .on_connect(ClientCertificate::on_connect)
+            .workers(1)
+            .listen_openssl(listener, builder)
+            .unwrap();
+        actix_web::rt::spawn(server.run());
+
+        let presented =
+            presented_fingerprint(&url, &certificates.ca, Some(certificates.client_identity)).await;
+        assert_eq!(presented, fingerprint(&certificates.client));
+        assert_eq!(
+            presented_fingerprint(&url, &certificates.ca, None).await,
+            ""
+        );
+    }
+}
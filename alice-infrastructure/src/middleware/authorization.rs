@@ -1,38 +1,44 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     future::{ready, Ready},
     pin::Pin,
     rc::Rc,
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use actix_http::body::{EitherBody, MessageBody};
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, FromRequest, HttpMessage,
+    Error, FromRequest, HttpMessage, HttpRequest,
 };
-use alice_architecture::jwt_payload::Payload;
-use anyhow::{anyhow, Context};
-use base64::{engine::general_purpose, Engine};
+use alice_architecture::{background_service::BackgroundService, jwt_payload::Payload};
+use anyhow::anyhow;
 use futures_util::{future::LocalBoxFuture, Future, TryFutureExt};
 use jsonwebtoken::{
     decode as jwt_decode,
-    jwk::{AlgorithmParameters, JwkSet},
+    jwk::{Jwk, JwkSet},
     Algorithm, DecodingKey, TokenData, Validation,
 };
 use reqwest::Client as ReqwestClient;
-use sea_orm::EntityTrait;
 use serde::Deserialize;
 use serde_json::Value;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
-    config::ResourceControlConfig,
-    data::Database,
+    access_policy::{token_roles, AccessPolicy, RoleExpression, RoleExpressionError},
+    config::{JwtValidationConfig, TrustedIssuer},
     error::{AliceCommonError, AliceError},
 };
 
+use super::{
+    authenticator::{bearer_token, is_jwt, Authenticator, Identity},
+    claims::Claims,
+    principal_resolver::PrincipalResolver,
+};
+
 #[derive(Default, Debug, Clone)]
 pub struct AliceScopedConfig {
     pub user_info: Option<UserInfo>,
@@ -111,34 +117,125 @@ impl From<Payload> for DeviceInfo {
     }
 }
 
+/// 令牌授予的角色与 scope，由 [`JwtValidationMiddleware`] 放入请求的扩展中
+#[derive(Debug, Clone, Default)]
+pub struct GrantedAccess {
+    /// 格式为 `client:role`
+    pub roles: HashSet<String>,
+    pub scopes: HashSet<String>,
+}
+
+impl GrantedAccess {
+    pub fn from_payload(payload: &Payload) -> Self {
+        Self {
+            roles: token_roles(&payload.resource_access),
+            scopes: payload.scope.split_whitespace().map(String::from).collect(),
+        }
+    }
+}
+
+/// 处理函数的访问要求，由 `#[authorize(roles = "...", scopes = "...")]` 生成
+pub struct AccessRequirement {
+    roles: Option<Result<RoleExpression, RoleExpressionError>>,
+    scopes: Vec<String>,
+}
+
+impl AccessRequirement {
+    /// `roles` 为角色表达式，`scopes` 为空格分隔的必须全部拥有的 scope
+    pub fn new(roles: Option<&str>, scopes: &str) -> Self {
+        Self {
+            roles: roles.map(str::parse),
+            scopes: scopes.split_whitespace().map(String::from).collect(),
+        }
+    }
+
+    pub fn check(&self, req: &HttpRequest) -> Result<(), AliceError> {
+        let extensions = req.extensions();
+        let granted = extensions.get::<GrantedAccess>().ok_or_else(|| {
+            AliceError::new(AliceCommonError::InvalidToken {
+                error_description: "Request isn't authenticated.".to_string(),
+            })
+        })?;
+        let insufficient = |error_description: String| {
+            AliceError::new(AliceCommonError::InsufficientScope { error_description })
+        };
+        match &self.roles {
+            Some(Ok(roles)) if !roles.evaluate(&granted.roles) => {
+                return Err(insufficient(format!(
+                    "Token doesn't have the roles to access {}",
+                    req.path()
+                )));
+            }
+            Some(Err(e)) => {
+                return Err(AliceError::new(AliceCommonError::InternalError {
+                    source: anyhow!(e.to_string()),
+                }));
+            }
+            _ => {}
+        }
+        match self.scopes.iter().find(|scope| !granted.scopes.contains(*scope)) {
+            Some(scope) => Err(insufficient(format!("Token doesn't have scope {scope}"))),
+            None => Ok(()),
+        }
+    }
+}
+
 pub struct JwtValidationMiddleware {
     key_storage: Arc<dyn KeyStorage>,
-    config: crate::config::JwtValidationConfig,
+    config: JwtValidationConfig,
     all_controllers: bool,
-    resources_config: ResourceControlConfig,
-    database: Arc<Database>,
+    access_policy: Arc<AccessPolicy>,
+    principal_resolver: Arc<dyn PrincipalResolver>,
+    authenticators: Vec<Arc<dyn Authenticator>>,
 }
 
 impl JwtValidationMiddleware {
     pub fn new(
         key_storage: Arc<dyn KeyStorage>,
-        config: crate::config::JwtValidationConfig,
-        resources_config: ResourceControlConfig,
-        database: Arc<Database>,
+        config: JwtValidationConfig,
+        access_policy: impl Into<AccessPolicy>,
+        principal_resolver: Arc<dyn PrincipalResolver>,
     ) -> Self {
         Self {
             key_storage,
             config,
             all_controllers: false,
-            resources_config,
-            database,
+            access_policy: Arc::new(access_policy.into()),
+            principal_resolver,
+            authenticators: vec![],
         }
     }
 
+    /// 添加 JWT 以外的认证方式，请求没有 JWT 时按添加的顺序尝试
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticators.push(authenticator);
+        self
+    }
+
+    /// 保护所有路径，忽略配置中的 `public_paths`
     pub fn all_controllers(mut self) -> Self {
         self.all_controllers = true;
         self
     }
+
+    /// 使用另一份验证配置的中间件，公钥存储、访问策略与数据库与原中间件共用
+    ///
+    /// 用于在不同的 actix scope 上使用不同的受众、签发者或公开路径。
+    pub fn with_config(&self, config: JwtValidationConfig) -> Self {
+        Self {
+            key_storage: self.key_storage.clone(),
+            config,
+            all_controllers: self.all_controllers,
+            access_policy: self.access_policy.clone(),
+            principal_resolver: self.principal_resolver.clone(),
+            authenticators: self.authenticators.clone(),
+        }
+    }
+
+    /// 使用配置中名为 `scope` 的作用域的中间件，见 [`JwtValidationConfig::for_scope`]
+    pub fn scope(&self, scope: &str) -> Self {
+        self.with_config(self.config.for_scope(scope))
+    }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for JwtValidationMiddleware
@@ -159,8 +256,9 @@ where
             key_storage: self.key_storage.clone(),
             config: self.config.clone(),
             all_controllers: self.all_controllers,
-            resources_config: self.resources_config.clone(),
-            database: self.database.clone(),
+            access_policy: self.access_policy.clone(),
+            principal_resolver: self.principal_resolver.clone(),
+            authenticators: self.authenticators.clone(),
         }))
     }
 }
@@ -168,10 +266,11 @@ where
 pub struct JwtValidationMiddlewareExcutor<S> {
     service: Rc<S>,
     key_storage: Arc<dyn KeyStorage>,
-    config: crate::config::JwtValidationConfig,
+    config: JwtValidationConfig,
     all_controllers: bool,
-    resources_config: ResourceControlConfig,
-    database: Arc<Database>,
+    access_policy: Arc<AccessPolicy>,
+    principal_resolver: Arc<dyn PrincipalResolver>,
+    authenticators: Vec<Arc<dyn Authenticator>>,
 }
 
 impl<S> Clone for JwtValidationMiddlewareExcutor<S> {
@@ -181,8 +280,9 @@ impl<S> Clone for JwtValidationMiddlewareExcutor<S> {
             key_storage: self.key_storage.clone(),
             config: self.config.clone(),
             all_controllers: self.all_controllers,
-            resources_config: self.resources_config.clone(),
-            database: self.database.clone(),
+            access_policy: self.access_policy.clone(),
+            principal_resolver: self.principal_resolver.clone(),
+            authenticators: self.authenticators.clone(),
         }
     }
 }
@@ -204,88 +304,65 @@ where
         let key_storage = self.key_storage.clone();
         let config = self.config.clone();
         let all_controllers = self.all_controllers;
-        let resources_config = self.resources_config.clone();
-        let database = self.database.clone();
+        let access_policy = self.access_policy.clone();
+        let principal_resolver = self.principal_resolver.clone();
+        let authenticators = self.authenticators.clone();
 
-        let protected_prefixs = [""];
         Box::pin(async move {
             let req_path = req.path();
-            let flag =
-                protected_prefixs.iter().any(|el| req_path.starts_with(el)) || all_controllers;
-            if !flag {
+            if !all_controllers && config.is_public_path(req_path) {
                 return Ok(service.call(req).await?.map_into_left_body());
             }
 
-            let header = match req.headers().get("Authorization").ok_or::<Error>(
-                AliceError::new(AliceCommonError::InvalidToken {
-                    error_description: "No Authorization header.".to_string(),
-                })
-                .into(),
-            ) {
-                Ok(h) => h,
-                Err(e) => {
-                    return Ok(ServiceResponse::from_err(e, req.request().to_owned())
-                        .map_into_right_body());
-                }
-            };
-
-            let header = match header.to_str().map_err(|e| {
-                AliceError::new(AliceCommonError::InvalidToken {
-                    error_description: e.to_string(),
-                })
-            }) {
-                Ok(h) => h,
+            let identity = match authenticate(&req, key_storage, &config, &authenticators).await {
+                Ok(identity) => identity,
                 Err(e) => {
                     return Ok(ServiceResponse::from_err(e, req.request().to_owned())
                         .map_into_right_body());
                 }
             };
+            let Identity {
+                payload,
+                granted,
+                claims,
+            } = identity;
 
-            let payload = match parse_jwt_token_payload(header, key_storage, config).await {
-                Ok(p) => p,
-                Err(e) => {
-                    return Ok(ServiceResponse::from_err(e, req.request().to_owned())
-                        .map_into_right_body());
-                }
-            };
-
-            let resource_access = &payload.resource_access;
-            let option = resources_config.examine_permission(resource_access);
-            let e_403 = AliceError::new(AliceCommonError::InsufficientScope {
-                error_description: format!("Token doesn't have permission to access {req_path}"),
-            });
-
-            if option.is_none() {
-                return Ok(ServiceResponse::from_err(e_403, req.request().to_owned())
-                    .map_into_right_body());
-            }
-            let (allowed_paths, is_hpc) = option.unwrap();
-            let has_permission = allowed_paths.iter().any(|prefix| req_path.starts_with(prefix));
-            if !has_permission {
+            if !access_policy.is_allowed(req.method().as_str(), req_path, &granted.roles) {
+                let e_403 = AliceError::new(AliceCommonError::InsufficientScope {
+                    error_description: format!(
+                        "Token doesn't have permission to access {req_path}"
+                    ),
+                });
                 return Ok(ServiceResponse::from_err(e_403, req.request().to_owned())
                     .map_into_right_body());
             }
+            let is_hpc = granted.roles.contains("device:hpc");
+            req.extensions_mut().insert(granted);
+            req.extensions_mut().insert(claims);
 
             let mut info_user_id = None;
             let mut info_device_name = None;
             let mut info_task_id = None;
 
             if is_hpc {
-                if let Some(task_id) = req.headers().get("TaskId") {
-                    let (user_id, task_id) = match get_user_and_task_id(database, task_id)
-                        .await
-                        .map_err(|e| AliceError::new(AliceCommonError::InternalError { source: e }))
-                    {
-                        Ok(id) => id,
-                        Err(e) => {
-                            return Ok(ServiceResponse::from_err(e, req.request().to_owned())
-                                .map_into_right_body());
-                        }
-                    };
-                    req.extensions_mut().insert(UserInfo::new(user_id));
-                    req.extensions_mut().insert(TaskInfo::new(task_id));
-                    info_user_id = Some(user_id);
-                    info_task_id = Some(task_id);
+                let principal = match principal_resolver
+                    .resolve(req.headers(), &payload)
+                    .await
+                    .map_err(|e| AliceError::new(AliceCommonError::InternalError { source: e }))
+                {
+                    Ok(principal) => principal,
+                    Err(e) => {
+                        return Ok(ServiceResponse::from_err(e, req.request().to_owned())
+                            .map_into_right_body());
+                    }
+                };
+                if let Some(user_info) = principal.user_info {
+                    info_user_id = Some(user_info.id);
+                    req.extensions_mut().insert(user_info);
+                }
+                if let Some(task_info) = principal.task_info {
+                    info_task_id = Some(task_info.id);
+                    req.extensions_mut().insert(task_info);
                 }
                 info_device_name = Some(payload.preferred_username.to_owned());
                 req.extensions_mut().insert(DeviceInfo::from(payload));
@@ -311,37 +388,37 @@ where
     }
 }
 
-async fn get_user_and_task_id(
-    database: Arc<Database>,
-    task_id: &actix_http::header::HeaderValue,
-) -> anyhow::Result<(Uuid, Uuid)> {
-    let task_id = task_id.to_str()?.parse::<Uuid>()?;
-    let con = database.get_connection();
-    let node_id = database_model::prelude::Task::find_by_id(task_id)
-        .one(con)
-        .await?
-        .with_context(|| format!("No such task: {task_id} in jwt validation.",))?
-        .node_instance_id;
-    let flow_id = database_model::prelude::NodeInstance::find_by_id(node_id)
-        .one(con)
-        .await?
-        .with_context(|| format!("No such node: {node_id} in jwt validation."))?
-        .flow_instance_id;
-    Ok((
-        database_model::prelude::FlowInstance::find_by_id(flow_id)
-            .one(con)
-            .await?
-            .with_context(|| format!("No such flow-instance: {flow_id} in jwt validation"))?
-            .user_id,
-        task_id,
-    ))
+/// Authenticates a JWT, or else the credentials accepted by the other authenticators.
+async fn authenticate(
+    req: &ServiceRequest,
+    key_storage: Arc<dyn KeyStorage>,
+    config: &JwtValidationConfig,
+    authenticators: &[Arc<dyn Authenticator>],
+) -> Result<Identity, AliceError> {
+    let authorization =
+        req.headers().get("Authorization").map(|header| header.to_str()).transpose()?;
+    // Opaque Bearer tokens are left to the authenticators, e.g. token introspection.
+    if !authorization.and_then(bearer_token).is_some_and(is_jwt) {
+        for authenticator in authenticators {
+            if let Some(identity) = authenticator.authenticate(req, config).await? {
+                return Ok(identity);
+            }
+        }
+    }
+    let Some(authorization) = authorization else {
+        return Err(AliceError::new(AliceCommonError::InvalidToken {
+            error_description: "No Authorization header.".to_string(),
+        }));
+    };
+    let claims = parse_jwt_token_payload(authorization, key_storage, config).await?;
+    Identity::from_claims(claims, &config.claims)
 }
 
 async fn parse_jwt_token_payload(
     authorization_str: &str,
     key_storage: Arc<dyn KeyStorage>,
-    config: crate::config::JwtValidationConfig,
-) -> Result<alice_architecture::jwt_payload::Payload, AliceError> {
+    config: &JwtValidationConfig,
+) -> Result<Claims, AliceError> {
     let parts = authorization_str.split_whitespace().collect::<Vec<&str>>();
     if parts.is_empty() {
         return Err(AliceError::new(AliceCommonError::InvalidToken {
@@ -372,79 +449,66 @@ async fn parse_jwt_token_payload(
     let mut insecure_validation = validation.clone();
     insecure_validation.insecure_disable_signature_validation();
 
-    let payload: TokenData<Payload> = jwt_decode(
+    let claims: TokenData<Claims> = jwt_decode(
         token,
         &DecodingKey::from_secret("secret".as_ref()),
         &insecure_validation,
     )?;
 
-    let header = payload.header;
-    let payload = payload.claims;
+    let header = claims.header;
+    let claims = claims.claims;
+    let iss = claims.issuer().unwrap_or_default();
 
     let kid = header.kid.ok_or(AliceError::new(AliceCommonError::InvalidToken {
         error_description: "No kid in token.".to_string(),
     }))?;
 
-    let jwk_set = match key_storage.get(&payload.iss).await {
-        Ok(x) => x,
-        Err(e) => {
-            tracing::warn!("Get jwk first try failed, take second try. - {e}");
-            key_storage.reload_keys(&payload.iss).await?
-        }
-    };
-    let jwk_set = MemoryKeyStorage::remove_unsupported_key(&jwk_set)?;
-    let jwk_set: JwkSet = serde_json::from_str(&jwk_set)?;
-    let key = match jwk_set.keys.iter().find(|x| match &x.common.key_id {
-        Some(id) => id.eq(&kid),
-        None => false,
-    }) {
+    if config.trusted_issuer(iss).is_none() {
+        return Err(AliceError::new(AliceCommonError::InvalidToken {
+            error_description: format!("Issuer {iss} isn't trusted."),
+        }));
+    }
+
+    let jwk_set = key_storage.get(iss).await?;
+    let key = match find_key(&jwk_set, &kid) {
         Some(k) => k.to_owned(),
         None => {
-            let jwk_set = key_storage.reload_keys(&payload.iss).await?;
-            let jwk_set = MemoryKeyStorage::remove_unsupported_key(&jwk_set)?;
-            let jwk_set: JwkSet = serde_json::from_str(&jwk_set)?;
-            jwk_set
-                .keys
-                .iter()
-                .find(|jwk| match &jwk.common.key_id {
-                    Some(id) => id.eq(&kid),
-                    None => false,
-                })
+            let jwk_set = key_storage.reload_keys(iss).await?;
+            find_key(&jwk_set, &kid)
                 .ok_or(anyhow::anyhow!("Public key isn't matched."))?
                 .to_owned()
         }
     };
 
-    let key = match key.algorithm {
-        AlgorithmParameters::RSA(ref params) => {
-            DecodingKey::from_rsa_components(&params.n, &params.e)?
-        }
-        AlgorithmParameters::EllipticCurve(ref params) => {
-            let x_cmp = general_purpose::STANDARD.decode(&params.x)?;
-            let y_cmp = general_purpose::STANDARD.decode(&params.y)?;
-
-            let mut public_key = Vec::with_capacity(1 + params.x.len() + params.y.len());
-            public_key.push(0x04);
-            public_key.extend_from_slice(&x_cmp);
-            public_key.extend_from_slice(&y_cmp);
-            DecodingKey::from_ec_der(&public_key)
-        }
-        AlgorithmParameters::OctetKeyPair(ref params) => {
-            let x_decoded = general_purpose::STANDARD.decode(&params.x)?;
-            DecodingKey::from_ed_der(&x_decoded)
-        }
-        AlgorithmParameters::OctetKey(ref params) => {
-            DecodingKey::from_base64_secret(&params.value)?
-        }
+    let algorithm = match key.common.key_algorithm {
+        Some(key_algorithm) => Algorithm::from_str(&key_algorithm.to_string()).map_err(|_| {
+            AliceError::new(AliceCommonError::InvalidToken {
+                error_description: format!("Key {kid} isn't a signing key: {key_algorithm}."),
+            })
+        })?,
+        // The key doesn't pin an algorithm, the decoding key still has to match its family.
+        None => header.alg,
     };
+    if !config.allowed_algorithms.contains(&format!("{algorithm:?}")) {
+        return Err(AliceError::new(AliceCommonError::InvalidToken {
+            error_description: format!("Algorithm {algorithm:?} isn't allowed."),
+        }));
+    }
+    validation.algorithms = vec![algorithm];
 
-    validation.algorithms = vec![Algorithm::RS256];
+    let key = DecodingKey::from_jwk(&key)?;
+    let verified = jwt_decode::<Claims>(token, &key, &validation)?;
 
-    let _ = jwt_decode::<Payload>(token, &key, &validation)?;
+    Ok(verified.claims)
+}
 
-    Ok(payload)
+fn find_key<'a>(jwk_set: &'a JwkSet, kid: &str) -> Option<&'a Jwk> {
+    jwk_set.keys.iter().find(|jwk| jwk.common.key_id.as_deref() == Some(kid))
 }
 
+/// How long an issuer without cached keys waits after a refresh, e.g. after its first fetch failed.
+const COLD_REFRESH_BACKOFF: Duration = Duration::from_secs(1);
+
 #[derive(serde::Deserialize)]
 pub struct WellKnownResponse {
     pub jwks_uri: String,
@@ -452,26 +516,85 @@ pub struct WellKnownResponse {
 
 #[async_trait::async_trait]
 pub trait KeyStorage: Send + Sync {
-    async fn get(&self, iss: &str) -> anyhow::Result<String>;
-    async fn reload_keys(&self, iss: &str) -> anyhow::Result<String>;
+    /// 签发者的公钥，可以使用缓存
+    async fn get(&self, iss: &str) -> anyhow::Result<Arc<JwkSet>>;
+
+    /// 重新获取签发者的公钥，令牌的 `kid` 不在缓存的公钥中时调用
+    async fn reload_keys(&self, iss: &str) -> anyhow::Result<Arc<JwkSet>>;
+}
+
+#[derive(Clone)]
+struct CachedKeys {
+    jwk_set: Arc<JwkSet>,
+    fetched_at: Instant,
+    refresh_at: Instant,
+    expires_at: Instant,
 }
 
+/// 内存中的公钥缓存，只从受信任的签发者获取公钥
+///
+/// 公钥的有效期取 JWKS 响应 `Cache-Control` 的 `max-age`，没有时使用 `default_ttl`。
+/// 同一个签发者同时只有一个请求在获取公钥。作为后台服务运行时会在公钥过期前提前刷新，
+/// 刷新失败时继续使用过期的公钥。
 pub struct MemoryKeyStorage {
-    storage: Arc<Mutex<HashMap<String, String>>>,
+    keys: Mutex<HashMap<String, CachedKeys>>,
+    reloads: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
     http_client: Arc<ReqwestClient>,
+    trusted_issuers: HashMap<String, TrustedIssuer>,
+    refresh_interval: Duration,
+    default_ttl: Duration,
+    max_ttl: Duration,
+    refreshed_at: Mutex<HashMap<String, Instant>>,
+    shutdown: CancellationToken,
 }
 
 impl MemoryKeyStorage {
-    pub fn new(
-        storage: Arc<Mutex<HashMap<String, String>>>,
-        http_client: Arc<ReqwestClient>,
-    ) -> Self {
+    pub fn new(http_client: Arc<ReqwestClient>) -> Self {
         Self {
-            storage,
+            keys: Mutex::default(),
+            reloads: Mutex::default(),
             http_client,
+            trusted_issuers: HashMap::new(),
+            refresh_interval: Duration::from_secs(30),
+            default_ttl: Duration::from_secs(300),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            refreshed_at: Mutex::default(),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// 受信任的签发者，其他签发者的公钥不会被获取
+    pub fn trusted_issuers(mut self, trusted_issuers: HashMap<String, TrustedIssuer>) -> Self {
+        self.trusted_issuers = trusted_issuers;
+        self
+    }
+
+    /// 同一个签发者两次刷新公钥的最短间隔，也是公钥的最短有效期，默认 30 秒
+    ///
+    /// 还没有获取到公钥的签发者只间隔 1 秒，避免一次获取失败导致整个间隔内的令牌都被拒绝。
+    pub fn refresh_interval(mut self, refresh_interval: Duration) -> Self {
+        self.refresh_interval = refresh_interval;
+        self
+    }
+
+    /// JWKS 响应没有 `Cache-Control` 时公钥的有效期，默认 5 分钟
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.default_ttl = default_ttl;
+        self
+    }
+
+    /// 公钥的最长有效期，默认 24 小时
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// 使用 JWT 验证配置中的受信任签发者（包括各个作用域的签发者）与刷新间隔
+    pub fn with_config(self, config: &JwtValidationConfig) -> Self {
+        self.trusted_issuers(config.issuer_registry())
+            .refresh_interval(Duration::from_secs(config.key_refresh_interval))
+    }
+
     fn remove_unsupported_key(jwk_set: &str) -> anyhow::Result<String> {
         let mut jwk_set: Value = serde_json::from_str(jwk_set)?;
         let jwk_set_obj = jwk_set.as_object_mut().ok_or(anyhow::anyhow!("JwkSet isn't object."))?;
@@ -482,13 +605,12 @@ impl MemoryKeyStorage {
             .ok_or(anyhow::anyhow!("Keys isn't array."))?;
         let mut new_keys_value = vec![];
         for key in keys_value.iter() {
-            let alg = key
-                .as_object()
-                .ok_or(anyhow!("Key isn't object."))?
-                .get("alg")
-                .ok_or(anyhow!("No alg in key."))?
-                .as_str()
-                .ok_or(anyhow!("Alg isn't string."))?;
+            let Some(alg) = key.as_object().ok_or(anyhow!("Key isn't object."))?.get("alg") else {
+                // `alg` is optional, the algorithm is then taken from the token.
+                new_keys_value.push(key.clone());
+                continue;
+            };
+            let alg = alg.as_str().ok_or(anyhow!("Alg isn't string."))?;
             // `HS256`, `HS384`, `HS512`, `ES256`, `ES384`, `RS256`, `RS384`, `RS512`, `PS256`, `PS384`, `PS512`, `EdDSA`
             match alg {
                 "HS256" | "HS384" | "HS512" | "ES256" | "ES384" | "RS256" | "RS384" | "RS512"
@@ -503,33 +625,578 @@ impl MemoryKeyStorage {
         Ok(jwk_set.to_string())
     }
 
-    async fn insert(&self, iss: &str, jwk_set: &str) -> anyhow::Result<()> {
-        let mut storage =
-            self.storage.lock().map_err(|_| anyhow::anyhow!("Unable to lock storage."))?;
-        storage.insert(iss.to_string(), jwk_set.to_string());
-        Ok(())
+    fn cached(&self, iss: &str) -> Option<CachedKeys> {
+        let keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        keys.get(iss).cloned()
+    }
+
+    /// The minimum time between two refreshes of an issuer.
+    ///
+    /// Only issuers with cached keys wait for the whole refresh interval, the others would reject
+    /// every token in the meantime.
+    fn refresh_backoff(&self, has_keys: bool) -> Duration {
+        if has_keys {
+            self.refresh_interval
+        } else {
+            COLD_REFRESH_BACKOFF.min(self.refresh_interval)
+        }
+    }
+
+    /// Records a refresh of the issuer, unless it was refreshed within `backoff`.
+    fn begin_refresh(&self, iss: &str, backoff: Duration) -> bool {
+        let mut refreshed_at = self.refreshed_at.lock().unwrap_or_else(|e| e.into_inner());
+        match refreshed_at.get(iss) {
+            Some(at) if at.elapsed() < backoff => false,
+            _ => {
+                refreshed_at.insert(iss.to_string(), Instant::now());
+                true
+            }
+        }
+    }
+
+    fn reload_lock(&self, iss: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut reloads = self.reloads.lock().unwrap_or_else(|e| e.into_inner());
+        reloads.entry(iss.to_string()).or_default().clone()
+    }
+
+    /// Fetches the keys unless another caller fetched them after `requested_at`.
+    async fn refresh(&self, iss: &str, requested_at: Instant) -> anyhow::Result<Arc<JwkSet>> {
+        let issuer = self
+            .trusted_issuers
+            .get(iss)
+            .ok_or_else(|| anyhow!("Issuer {iss} isn't trusted."))?;
+        let lock = self.reload_lock(iss);
+        let _reloading = lock.lock().await;
+        match self.cached(iss) {
+            Some(cached) if cached.fetched_at >= requested_at => return Ok(cached.jwk_set),
+            Some(cached) if !self.begin_refresh(iss, self.refresh_backoff(true)) => {
+                tracing::debug!("Keys of {iss} were refreshed recently, using the cached keys.");
+                return Ok(cached.jwk_set);
+            }
+            None if !self.begin_refresh(iss, self.refresh_backoff(false)) => {
+                anyhow::bail!("Key refresh of {iss} is rate limited.")
+            }
+            _ => {}
+        }
+
+        let (jwk_set, max_age) = self.fetch(issuer).await?;
+        let jwk_set = Arc::new(jwk_set);
+        let ttl = max_age.unwrap_or(self.default_ttl).max(self.refresh_interval).min(self.max_ttl);
+        let fetched_at = Instant::now();
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        keys.insert(
+            iss.to_string(),
+            CachedKeys {
+                jwk_set: jwk_set.clone(),
+                fetched_at,
+                // Refresh in the background before the keys expire.
+                refresh_at: fetched_at + ttl.mul_f64(0.8),
+                expires_at: fetched_at + ttl,
+            },
+        );
+        Ok(jwk_set)
+    }
+
+    /// The parsed keys and the `max-age` of the response.
+    async fn fetch(&self, issuer: &TrustedIssuer) -> anyhow::Result<(JwkSet, Option<Duration>)> {
+        let http_client = &self.http_client;
+        let jwks_uri = match issuer {
+            TrustedIssuer::Discovery(discovery_url) => {
+                let well_known: WellKnownResponse =
+                    http_client.get(discovery_url).send().await?.json().await?;
+                well_known.jwks_uri
+            }
+            TrustedIssuer::JwksUri(jwks_uri) => jwks_uri.clone(),
+            TrustedIssuer::Jwks(jwk_set) => {
+                let jwk_set = Self::remove_unsupported_key(&jwk_set.to_string())?;
+                return Ok((serde_json::from_str(&jwk_set)?, Some(self.max_ttl)));
+            }
+        };
+        let response = http_client.get(jwks_uri).send().await?.error_for_status()?;
+        let max_age = cache_max_age(response.headers());
+        let jwk_set = Self::remove_unsupported_key(&response.text().await?)?;
+        Ok((serde_json::from_str(&jwk_set)?, max_age))
     }
 }
 
+/// The lifetime allowed by the `Cache-Control` header, zero when the response mustn't be cached.
+fn cache_max_age(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let cache_control = headers.get(reqwest::header::CACHE_CONTROL)?.to_str().ok()?;
+    let mut max_age = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" => return Some(Duration::ZERO),
+            "max-age" => {
+                max_age = value.trim_matches('"').parse().ok().map(Duration::from_secs);
+            }
+            _ => {}
+        }
+    }
+    max_age
+}
+
 #[async_trait::async_trait]
 impl KeyStorage for MemoryKeyStorage {
-    async fn get(&self, iss: &str) -> anyhow::Result<String> {
-        let storage =
-            self.storage.lock().map_err(|_| anyhow::anyhow!("Unable to lock storage."))?;
-        match storage.get(iss) {
-            Some(x) => Ok(x.clone()),
-            None => anyhow::bail!("No such key."),
+    async fn get(&self, iss: &str) -> anyhow::Result<Arc<JwkSet>> {
+        let now = Instant::now();
+        let stale = match self.cached(iss) {
+            Some(cached) if cached.expires_at > now => return Ok(cached.jwk_set),
+            cached => cached.map(|cached| cached.jwk_set),
+        };
+        match (self.refresh(iss, now).await, stale) {
+            (Ok(jwk_set), _) => Ok(jwk_set),
+            (Err(e), Some(jwk_set)) => {
+                tracing::warn!("Unable to refresh keys of {iss}, using the expired keys. - {e}");
+                Ok(jwk_set)
+            }
+            (Err(e), None) => Err(e),
         }
     }
 
-    async fn reload_keys(&self, iss: &str) -> anyhow::Result<String> {
-        let iss_well_known_url =
-            url::Url::from_str(&format!("{iss}/"))?.join(".well-known/openid-configuration")?;
-        let http_client = &self.http_client;
-        let well_known: WellKnownResponse =
-            http_client.get(iss_well_known_url).send().await?.json().await?;
-        let jwk_set = http_client.get(well_known.jwks_uri).send().await?.text().await?;
-        self.insert(iss, jwk_set.as_str()).await?;
-        Ok(jwk_set)
+    async fn reload_keys(&self, iss: &str) -> anyhow::Result<Arc<JwkSet>> {
+        self.refresh(iss, Instant::now()).await
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for MemoryKeyStorage {
+    async fn run(&self) {
+        loop {
+            let now = Instant::now();
+            let mut next_refresh = None::<Instant>;
+            for iss in self.trusted_issuers.keys() {
+                let due = self.cached(iss).map_or(true, |cached| cached.refresh_at <= now);
+                if due {
+                    if let Err(e) = self.refresh(iss, now).await {
+                        tracing::warn!("Unable to refresh keys of {iss}. - {e}");
+                    }
+                }
+                // Retry failed and rate limited refreshes after the backoff.
+                let cached = self.cached(iss);
+                let refresh_at = cached
+                    .as_ref()
+                    .map(|cached| cached.refresh_at)
+                    .filter(|refresh_at| *refresh_at > now)
+                    .unwrap_or(now + self.refresh_backoff(cached.is_some()));
+                next_refresh = Some(next_refresh.map_or(refresh_at, |next| next.min(refresh_at)));
+            }
+            let Some(next_refresh) = next_refresh else {
+                self.shutdown.cancelled().await;
+                break;
+            };
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = tokio::time::sleep_until(next_refresh.into()) => {}
+            }
+        }
+    }
+
+    async fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::{http::StatusCode, test::TestRequest, web, App, HttpResponse, HttpServer};
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use rsa::{pkcs1::EncodeRsaPrivateKey, traits::PublicKeyParts, RsaPrivateKey};
+    use serde_json::json;
+
+    use super::*;
+    use crate::access_policy::AccessRule;
+    use crate::config::JwtScopeConfig;
+    use crate::middleware::principal_resolver::{MemoryPrincipalResolver, TASK_ID_HEADER};
+
+    const ISSUER: &str = "https://issuer.example";
+
+    /// A locally generated signing key and its public JWK without `kid` and `alg`.
+    #[derive(Clone)]
+    struct TestKey {
+        encoding_key: EncodingKey,
+        jwk: Value,
+    }
+
+    impl TestKey {
+        /// Generating RSA keys is slow, the tests share one.
+        fn rsa() -> Self {
+            static RSA: std::sync::OnceLock<TestKey> = std::sync::OnceLock::new();
+            let key = RSA.get_or_init(|| {
+                let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+                TestKey {
+                    encoding_key: EncodingKey::from_rsa_der(key.to_pkcs1_der().unwrap().as_bytes()),
+                    jwk: json!({
+                        "kty": "RSA",
+                        "n": b64(&key.n().to_bytes_be()),
+                        "e": b64(&key.e().to_bytes_be()),
+                    }),
+                }
+            });
+            key.clone()
+        }
+
+        fn ec() -> Self {
+            let rng = SystemRandom::new();
+            let algorithm = &ECDSA_P256_SHA256_FIXED_SIGNING;
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(algorithm, &rng).unwrap();
+            let key_pair = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8.as_ref(), &rng).unwrap();
+            // An uncompressed point: 0x04, x and y.
+            let point = key_pair.public_key().as_ref();
+            Self {
+                encoding_key: EncodingKey::from_ec_der(pkcs8.as_ref()),
+                jwk: json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "x": b64(&point[1..33]),
+                    "y": b64(&point[33..]),
+                }),
+            }
+        }
+
+        fn okp() -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            Self {
+                encoding_key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+                jwk: json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": b64(key_pair.public_key().as_ref()),
+                }),
+            }
+        }
+
+        fn oct() -> Self {
+            let secret: [u8; 32] = rand::random();
+            Self {
+                encoding_key: EncodingKey::from_secret(&secret),
+                jwk: json!({ "kty": "oct", "k": b64(&secret) }),
+            }
+        }
+
+        fn jwk(&self, kid: &str, alg: Option<&str>) -> Value {
+            let mut jwk = self.jwk.clone();
+            jwk["kid"] = json!(kid);
+            if let Some(alg) = alg {
+                jwk["alg"] = json!(alg);
+            }
+            jwk
+        }
+
+        fn sign(&self, kid: &str, alg: Algorithm) -> String {
+            sign(&self.encoding_key, kid, alg, ISSUER)
+        }
+    }
+
+    fn b64(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn sign(encoding_key: &EncodingKey, kid: &str, alg: Algorithm, iss: &str) -> String {
+        let claims = json!({ "iss": iss, "sub": Uuid::nil() });
+        sign_claims(encoding_key, kid, alg, claims)
+    }
+
+    /// Signs `claims` expiring in five minutes.
+    fn sign_claims(
+        encoding_key: &EncodingKey,
+        kid: &str,
+        alg: Algorithm,
+        mut claims: Value,
+    ) -> String {
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 300;
+        claims["exp"] = json!(exp);
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(alg)
+        };
+        encode(&header, &claims, encoding_key).unwrap()
+    }
+
+    /// A configuration trusting [`ISSUER`] with the static `keys`.
+    fn config(keys: Vec<Value>) -> JwtValidationConfig {
+        JwtValidationConfig {
+            trusted_issuers: HashMap::from([(
+                ISSUER.to_string(),
+                TrustedIssuer::Jwks(json!({ "keys": keys })),
+            )]),
+            ..Default::default()
+        }
+    }
+
+    async fn verify(config: &JwtValidationConfig, token: &str) -> Result<Claims, AliceError> {
+        let key_storage = MemoryKeyStorage::new(Arc::new(ReqwestClient::new())).with_config(config);
+        parse_jwt_token_payload(&format!("Bearer {token}"), Arc::new(key_storage), config).await
+    }
+
+    #[tokio::test]
+    async fn verifies_every_key_type() {
+        let keys = [
+            (TestKey::rsa(), Algorithm::RS256),
+            (TestKey::rsa(), Algorithm::PS384),
+            (TestKey::ec(), Algorithm::ES256),
+            (TestKey::okp(), Algorithm::EdDSA),
+            (TestKey::oct(), Algorithm::HS256),
+        ];
+        let mut config = config(
+            keys.iter()
+                .enumerate()
+                .map(|(kid, (key, alg))| key.jwk(&kid.to_string(), Some(&format!("{alg:?}"))))
+                .collect(),
+        );
+        config.allowed_algorithms.insert(String::from("HS256"));
+
+        for (kid, (key, alg)) in keys.iter().enumerate() {
+            let token = key.sign(&kid.to_string(), *alg);
+            let claims = verify(&config, &token).await.unwrap();
+            assert_eq!(claims.issuer(), Some(ISSUER), "{alg:?}");
+        }
+        // A key only verifies its own signatures.
+        let token = keys[2].0.sign("3", Algorithm::ES256);
+        assert!(verify(&config, &token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keys_without_alg_use_the_token_algorithm() {
+        let ec = TestKey::ec();
+        let rsa = TestKey::rsa();
+        let mut config = config(vec![ec.jwk("ec", None), rsa.jwk("rsa", None)]);
+        verify(&config, &ec.sign("ec", Algorithm::ES256)).await.unwrap();
+
+        // The RSA public key used as an HMAC secret doesn't verify as the RSA key.
+        config.allowed_algorithms.insert(String::from("HS256"));
+        let n = URL_SAFE_NO_PAD.decode(rsa.jwk["n"].as_str().unwrap()).unwrap();
+        let forged = sign(
+            &EncodingKey::from_secret(&n),
+            "rsa",
+            Algorithm::HS256,
+            ISSUER,
+        );
+        assert!(verify(&config, &forged).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_algorithms_not_allowed() {
+        let oct = TestKey::oct();
+        let ec = TestKey::ec();
+        let config = config(vec![oct.jwk("oct", Some("HS256")), ec.jwk("ec", None)]);
+
+        let e = verify(&config, &oct.sign("oct", Algorithm::HS256)).await.unwrap_err();
+        assert!(
+            e.to_string().contains("Algorithm HS256 isn't allowed."),
+            "{e}"
+        );
+        let mut config = config;
+        config.allowed_algorithms.remove("ES256");
+        let e = verify(&config, &ec.sign("ec", Algorithm::ES256)).await.unwrap_err();
+        assert!(
+            e.to_string().contains("Algorithm ES256 isn't allowed."),
+            "{e}"
+        );
+    }
+
+    /// Serves the responses built for the server's URL in order, repeating the last one, and counts
+    /// the requests.
+    fn serve(responses: impl FnOnce(&str) -> Vec<(u16, Value)>) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let responses = Arc::new(responses(&url));
+        let server = HttpServer::new(move || {
+            let requests = counter.clone();
+            let responses = responses.clone();
+            App::new().default_service(web::to(move || {
+                let n = requests.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = responses[n.min(responses.len() - 1)].clone();
+                async move { HttpResponse::build(StatusCode::from_u16(status).unwrap()).json(body) }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap();
+        actix_web::rt::spawn(server.run());
+        (url, requests)
+    }
+
+    #[actix_web::test]
+    async fn cold_cache_retries_after_short_backoff() {
+        let key = TestKey::ec();
+        let jwks = json!({ "keys": [key.jwk("ec", Some("ES256"))] });
+        let (url, requests) = serve(|_| vec![(500, json!({})), (200, jwks)]);
+        let key_storage = MemoryKeyStorage::new(Arc::new(ReqwestClient::new()))
+            .trusted_issuers(HashMap::from([(
+                ISSUER.to_string(),
+                TrustedIssuer::JwksUri(url),
+            )]))
+            .refresh_interval(Duration::from_secs(60));
+
+        assert!(key_storage.get(ISSUER).await.is_err());
+        let e = key_storage.get(ISSUER).await.unwrap_err();
+        assert!(e.to_string().contains("rate limited"), "{e}");
+        tokio::time::sleep(COLD_REFRESH_BACKOFF).await;
+        assert_eq!(key_storage.get(ISSUER).await.unwrap().keys.len(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Issuers with cached keys wait for the whole refresh interval.
+        key_storage.reload_keys(ISSUER).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn scope_only_issuers_are_trusted() {
+        let key = TestKey::ec();
+        let jwks = json!({ "keys": [key.jwk("ec", Some("ES256"))] });
+        // The issuer is discovered from its own URL.
+        let (issuer, _) = serve(|issuer| {
+            let discovery = json!({ "jwks_uri": format!("{issuer}/jwks") });
+            vec![(200, discovery), (200, jwks)]
+        });
+        let scope = JwtScopeConfig {
+            iss: Some(HashSet::from([issuer.clone()])),
+            ..Default::default()
+        };
+        let config = JwtValidationConfig {
+            iss: Some(HashSet::from([ISSUER.to_string()])),
+            scopes: HashMap::from([(String::from("partner"), scope)]),
+            ..Default::default()
+        };
+        let key_storage: Arc<dyn KeyStorage> =
+            Arc::new(MemoryKeyStorage::new(Arc::new(ReqwestClient::new())).with_config(&config));
+        let authorization = format!(
+            "Bearer {}",
+            sign(&key.encoding_key, "ec", Algorithm::ES256, &issuer)
+        );
+
+        let claims = parse_jwt_token_payload(
+            &authorization,
+            key_storage.clone(),
+            &config.for_scope("partner"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(claims.issuer(), Some(issuer.as_str()));
+        // Outside the scope the issuer isn't accepted.
+        assert!(parse_jwt_token_payload(&authorization, key_storage, &config).await.is_err());
+    }
+
+    /// Calls a service protected by the middleware with a device token and the `TaskId` header,
+    /// returning the status and the resolved principal.
+    async fn call_as_device(
+        resolver: MemoryPrincipalResolver,
+        task_id: Option<Uuid>,
+    ) -> (StatusCode, Value) {
+        let key = TestKey::ec();
+        let config = config(vec![key.jwk("ec", Some("ES256"))]);
+        let key_storage =
+            MemoryKeyStorage::new(Arc::new(ReqwestClient::new())).with_config(&config);
+        let middleware = JwtValidationMiddleware::new(
+            Arc::new(key_storage),
+            config,
+            AccessPolicy::default().rule(AccessRule::allow("/**")),
+            Arc::new(resolver),
+        );
+        let app = actix_web::test::init_service(App::new().wrap(middleware).route(
+            "/whoami",
+            web::get().to(|scoped: AliceScopedConfig| async move {
+                HttpResponse::Ok().json(json!({
+                    "user": scoped.user_info.map(|user| user.id),
+                    "task": scoped.task_info.map(|task| task.id),
+                    "device": scoped.device_info.map(|device| device.preferred_username),
+                }))
+            }),
+        ))
+        .await;
+
+        let claims = json!({
+            "iss": ISSUER,
+            "sub": Uuid::nil(),
+            "preferred_username": "hpc-1",
+            "resource_access": { "device": { "roles": ["hpc"] } },
+        });
+        let token = sign_claims(&key.encoding_key, "ec", Algorithm::ES256, claims);
+        let mut req = TestRequest::get()
+            .uri("/whoami")
+            .insert_header(("Authorization", format!("Bearer {token}")));
+        if let Some(task_id) = task_id {
+            req = req.insert_header((TASK_ID_HEADER, task_id.to_string()));
+        }
+        let res = actix_web::test::call_service(&app, req.to_request()).await;
+        let status = res.status();
+        if !status.is_success() {
+            return (status, Value::Null);
+        }
+        (status, actix_web::test::read_body_json(res).await)
+    }
+
+    #[actix_web::test]
+    async fn device_tokens_act_for_the_task_owner() {
+        let task_id = Uuid::from_u128(1);
+        let user_id = Uuid::from_u128(2);
+        let resolver = MemoryPrincipalResolver::new();
+        resolver.insert(task_id, user_id);
+
+        let (status, principal) = call_as_device(resolver, Some(task_id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            principal,
+            json!({ "user": user_id, "task": task_id, "device": "hpc-1" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn device_tokens_without_a_task_only_identify_the_device() {
+        let (status, principal) = call_as_device(MemoryPrincipalResolver::new(), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            principal,
+            json!({ "user": null, "task": null, "device": "hpc-1" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn unresolved_tasks_are_rejected() {
+        let resolver = MemoryPrincipalResolver::new();
+        resolver.insert(Uuid::from_u128(1), Uuid::from_u128(2));
+
+        let (status, _) = call_as_device(resolver, Some(Uuid::from_u128(3))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn max_age(cache_control: Option<&str>) -> Option<Duration> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(cache_control) = cache_control {
+            headers.insert(
+                reqwest::header::CACHE_CONTROL,
+                cache_control.parse().unwrap(),
+            );
+        }
+        cache_max_age(&headers)
+    }
+
+    #[test]
+    fn cache_control_sets_key_lifetime() {
+        let seconds = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(max_age(None), None);
+        assert_eq!(max_age(Some("max-age=120")), seconds(120));
+        assert_eq!(
+            max_age(Some("public, Max-Age=\"60\", must-revalidate")),
+            seconds(60)
+        );
+        assert_eq!(max_age(Some("public")), None);
+        assert_eq!(max_age(Some("max-age=soon")), None);
+        // Responses that mustn't be cached win over max-age.
+        assert_eq!(max_age(Some("max-age=120, no-cache")), seconds(0));
+        assert_eq!(max_age(Some("NO-STORE")), seconds(0));
     }
 }
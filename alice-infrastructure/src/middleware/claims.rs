@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use alice_architecture::jwt_payload::Payload;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::authorization::GrantedAccess;
+use crate::{
+    config::{ClaimsMappingConfig, RoleSource},
+    error::{AliceCommonError, AliceError},
+};
+
+/// 令牌的全部声明，由 `JwtValidationMiddleware` 放入请求的扩展中
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct Claims(pub Map<String, Value>);
+
+impl Claims {
+    /// 读取声明，路径以 `.` 分隔；名称本身包含 `.` 的声明（例如 URL）也可以直接读取
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        if let Some(value) = self.0.get(path) {
+            return Some(value);
+        }
+        let mut segments = path.split('.');
+        let mut value = self.0.get(segments.next()?)?;
+        for segment in segments {
+            value = value.get(segment)?;
+        }
+        Some(value)
+    }
+
+    pub fn get_str(&self, path: &str) -> Option<&str> {
+        self.get(path)?.as_str()
+    }
+
+    pub fn issuer(&self) -> Option<&str> {
+        self.get_str("iss")
+    }
+
+    /// 按映射配置转换为 [`Payload`]，用户 id 不存在或者不是 uuid 时令牌无效
+    pub fn to_payload(&self, mapping: &ClaimsMappingConfig) -> Result<Payload, AliceError> {
+        let invalid = |error_description: String| {
+            AliceError::new(AliceCommonError::InvalidToken { error_description })
+        };
+        let subject = self
+            .get_str(&mapping.subject)
+            .ok_or_else(|| invalid(format!("No {} in token.", mapping.subject)))?;
+        Ok(Payload {
+            iss: self.issuer().unwrap_or_default().to_string(),
+            sub: subject
+                .parse()
+                .map_err(|_| invalid(format!("{} of token isn't uuid.", mapping.subject)))?,
+            preferred_username: self.get_str(&mapping.username).unwrap_or_default().to_string(),
+            resource_access: self
+                .get("resource_access")
+                .and_then(Value::as_object)
+                .map(|access| access.clone().into_iter().collect())
+                .unwrap_or_default(),
+            scope: self.get_str(&mapping.scope).unwrap_or_default().to_string(),
+        })
+    }
+
+    /// 按映射配置读取令牌授予的角色与 scope
+    pub fn granted_access(&self, mapping: &ClaimsMappingConfig) -> GrantedAccess {
+        let mut roles = HashSet::new();
+        for source in &mapping.roles {
+            match source {
+                RoleSource::ResourceAccess => {
+                    let Some(resource_access) =
+                        self.get("resource_access").and_then(Value::as_object)
+                    else {
+                        continue;
+                    };
+                    for (client, access) in resource_access {
+                        roles.extend(prefixed(client, access.get("roles")));
+                    }
+                }
+                RoleSource::RealmAccess { client } => {
+                    roles.extend(prefixed(client, self.get("realm_access.roles")));
+                }
+                RoleSource::Scope { client } => {
+                    roles.extend(prefixed(client, self.get(&mapping.scope)));
+                }
+                RoleSource::Claim { path, client } => {
+                    roles.extend(prefixed(client, self.get(path)));
+                }
+            }
+        }
+        GrantedAccess {
+            roles,
+            scopes: values(self.get(&mapping.scope)).into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+/// The strings of an array, or the words of a space separated string.
+fn values(value: Option<&Value>) -> Vec<&str> {
+    match value {
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+        Some(Value::String(value)) => value.split_whitespace().collect(),
+        _ => vec![],
+    }
+}
+
+fn prefixed<'a>(client: &'a str, value: Option<&'a Value>) -> impl Iterator<Item = String> + 'a {
+    values(value).into_iter().map(move |role| format!("{client}:{role}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn claims(value: Value) -> Claims {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn roles(access: &GrantedAccess) -> Vec<&str> {
+        let mut roles: Vec<_> = access.roles.iter().map(String::as_str).collect();
+        roles.sort();
+        roles
+    }
+
+    #[test]
+    fn paths_read_nested_and_dotted_claims() {
+        let claims = claims(json!({
+            "user": { "profile": { "id": "u-1" } },
+            "https://example.com/tenant": "acme",
+            "list": ["a"],
+        }));
+        assert_eq!(claims.get_str("user.profile.id"), Some("u-1"));
+        assert_eq!(claims.get_str("https://example.com/tenant"), Some("acme"));
+        assert_eq!(claims.get("list"), Some(&json!(["a"])));
+        assert_eq!(claims.get("user.profile.name"), None);
+        assert_eq!(claims.get("list.0.name"), None);
+        assert_eq!(claims.get_str("user.profile"), None);
+    }
+
+    #[test]
+    fn payload_follows_the_mapping() {
+        let id = Uuid::from_u128(7);
+        let claims = claims(json!({
+            "iss": "https://issuer.example",
+            "sub": "not-a-uuid",
+            "ext": { "uid": id, "login": "alice", "scp": "file:read file:write" },
+            "resource_access": { "fe": { "roles": ["user"] } },
+        }));
+        let mapping = ClaimsMappingConfig {
+            subject: String::from("ext.uid"),
+            username: String::from("ext.login"),
+            scope: String::from("ext.scp"),
+            ..Default::default()
+        };
+
+        let payload = claims.to_payload(&mapping).unwrap();
+        assert_eq!(payload.iss, "https://issuer.example");
+        assert_eq!(payload.sub, id);
+        assert_eq!(payload.preferred_username, "alice");
+        assert_eq!(payload.scope, "file:read file:write");
+        assert_eq!(payload.resource_access["fe"], json!({ "roles": ["user"] }));
+
+        let e = claims.to_payload(&ClaimsMappingConfig::default()).err().unwrap();
+        assert!(e.to_string().contains("sub of token isn't uuid."), "{e}");
+        let mapping = ClaimsMappingConfig {
+            subject: String::from("ext.id"),
+            ..Default::default()
+        };
+        let e = claims.to_payload(&mapping).err().unwrap();
+        assert!(e.to_string().contains("No ext.id in token."), "{e}");
+    }
+
+    #[test]
+    fn roles_are_merged_from_every_source() {
+        let claims = claims(json!({
+            "resource_access": { "fe": { "roles": ["user", "admin"] }, "device": { "roles": ["hpc"] } },
+            "realm_access": { "roles": ["offline_access"] },
+            "scope": "openid file:read",
+            "groups": ["ops"],
+            "org": { "teams": "red blue" },
+        }));
+        let mapping = ClaimsMappingConfig {
+            roles: vec![
+                RoleSource::ResourceAccess,
+                RoleSource::RealmAccess {
+                    client: String::from("realm"),
+                },
+                RoleSource::Scope {
+                    client: String::from("scope"),
+                },
+                RoleSource::Claim {
+                    path: String::from("groups"),
+                    client: String::from("group"),
+                },
+                RoleSource::Claim {
+                    path: String::from("org.teams"),
+                    client: String::from("team"),
+                },
+                RoleSource::Claim {
+                    path: String::from("missing"),
+                    client: String::from("none"),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let access = claims.granted_access(&mapping);
+        assert_eq!(
+            roles(&access),
+            [
+                "device:hpc",
+                "fe:admin",
+                "fe:user",
+                "group:ops",
+                "realm:offline_access",
+                "scope:file:read",
+                "scope:openid",
+                "team:blue",
+                "team:red",
+            ]
+        );
+        assert_eq!(
+            access.scopes,
+            HashSet::from([String::from("openid"), String::from("file:read")])
+        );
+
+        // By default only `resource_access` grants roles.
+        let access = claims.granted_access(&ClaimsMappingConfig::default());
+        assert_eq!(roles(&access), ["device:hpc", "fe:admin", "fe:user"]);
+    }
+}
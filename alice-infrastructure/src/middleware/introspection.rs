@@ -0,0 +1,362 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::ServiceRequest;
+use reqwest::Client as ReqwestClient;
+use serde_json::Value;
+
+use super::{
+    authenticator::{bearer_token, fingerprint, is_jwt, Authenticator, Identity},
+    claims::Claims,
+};
+use crate::{
+    config::{IntrospectionConfig, JwtValidationConfig},
+    error::{AliceCommonError, AliceError},
+};
+
+/// OAuth2 令牌内省（RFC 7662）认证，验证 `Authorization: Bearer` 中不是 JWT 的令牌
+///
+/// 结果按令牌的摘要缓存：有效令牌缓存 `cache_ttl` 且不超过令牌的 `exp`，无效令牌缓存
+/// `negative_cache_ttl`，最多缓存 `cache_capacity` 个结果。内省结果通过 `claims` 映射为
+/// [`Identity`]，与 JWT 的声明一样处理，并按中间件的 `aud` 与 `iss` 配置检查；内省结果没有 `aud`
+/// 时以 `client_id` 作为受众。
+pub struct IntrospectionAuthenticator {
+    config: IntrospectionConfig,
+    http_client: Arc<ReqwestClient>,
+    cache: Mutex<IntrospectionCache>,
+}
+
+/// Introspection results by token fingerprint, and the fingerprints ordered by expiry.
+#[derive(Default)]
+struct IntrospectionCache {
+    entries: HashMap<String, (Instant, Option<Claims>)>,
+    expirations: BTreeSet<(Instant, String)>,
+}
+
+impl IntrospectionCache {
+    fn remove(&mut self, key: &str) {
+        if let Some((expires_at, _)) = self.entries.remove(key) {
+            self.expirations.remove(&(expires_at, key.to_string()));
+        }
+    }
+
+    /// Removes the expired results, then the ones expiring first until there is room for one more.
+    fn make_room(&mut self, capacity: usize, now: Instant) {
+        while let Some((expires_at, key)) = self.expirations.first() {
+            if *expires_at > now && self.entries.len() < capacity.max(1) {
+                break;
+            }
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+}
+
+impl IntrospectionAuthenticator {
+    pub fn new(config: IntrospectionConfig, http_client: Arc<ReqwestClient>) -> Self {
+        Self {
+            config,
+            http_client,
+            cache: Mutex::default(),
+        }
+    }
+
+    fn cached(&self, key: &str) -> Option<Option<Claims>> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.entries.get(key) {
+            Some((expires_at, claims)) if *expires_at > Instant::now() => Some(claims.clone()),
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn cache(&self, key: String, claims: Option<Claims>) {
+        let ttl = match &claims {
+            Some(claims) => {
+                let ttl = Duration::from_secs(self.config.cache_ttl);
+                match claims.get("exp").and_then(Value::as_u64) {
+                    Some(exp) => ttl.min(
+                        (UNIX_EPOCH + Duration::from_secs(exp))
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default(),
+                    ),
+                    None => ttl,
+                }
+            }
+            None => Duration::from_secs(self.config.negative_cache_ttl),
+        };
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.remove(&key);
+        cache.make_room(self.config.cache_capacity, now);
+        cache.expirations.insert((now + ttl, key.clone()));
+        cache.entries.insert(key, (now + ttl, claims));
+    }
+
+    /// The claims of an active token, `None` for an inactive one.
+    async fn introspect(&self, token: &str) -> anyhow::Result<Option<Claims>> {
+        let response: Claims = self
+            .http_client
+            .post(&self.config.endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let active = response.get("active").and_then(Value::as_bool).unwrap_or(false);
+        Ok(active.then_some(response))
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Authenticator for IntrospectionAuthenticator {
+    async fn authenticate(
+        &self,
+        req: &ServiceRequest,
+        config: &JwtValidationConfig,
+    ) -> Result<Option<Identity>, AliceError> {
+        let Some(token) = req
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(bearer_token)
+            .filter(|token| !is_jwt(token))
+        else {
+            return Ok(None);
+        };
+        let key = fingerprint(token.as_bytes());
+        let claims = match self.cached(&key) {
+            Some(claims) => claims,
+            None => {
+                let claims = self
+                    .introspect(token)
+                    .await
+                    .map_err(|e| AliceError::new(AliceCommonError::InternalError { source: e }))?;
+                self.cache(key, claims.clone());
+                claims
+            }
+        };
+        let claims = claims.ok_or_else(|| {
+            AliceError::new(AliceCommonError::InvalidToken {
+                error_description: "Token isn't active.".to_string(),
+            })
+        })?;
+        validate(&claims, config)?;
+        Identity::from_claims(claims, &self.config.claims).map(Some)
+    }
+}
+
+/// Checks the audience and the issuer of an active token like `Validation` checks a JWT's.
+fn validate(claims: &Claims, config: &JwtValidationConfig) -> Result<(), AliceError> {
+    let invalid = |error_description: String| {
+        AliceError::new(AliceCommonError::InvalidToken { error_description })
+    };
+    if let Some(allowed) = &config.aud {
+        let audiences = match claims.get("aud").or_else(|| claims.get("client_id")) {
+            Some(Value::String(aud)) => vec![aud.as_str()],
+            Some(Value::Array(aud)) => aud.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !audiences.iter().any(|aud| allowed.contains(*aud)) {
+            return Err(invalid(format!("Audience {audiences:?} isn't allowed.")));
+        }
+    }
+    if let Some(allowed) = &config.iss {
+        let iss = claims.issuer().unwrap_or_default();
+        if !allowed.contains(iss) {
+            return Err(invalid(format!("Issuer {iss} isn't allowed.")));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_web::{test::TestRequest, web, App, HttpResponse, HttpServer};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    const ISSUER: &str = "https://issuer.example";
+
+    /// Serves an introspection endpoint counting its requests. Tokens named `inactive` are
+    /// inactive, tokens named `short` expire in a second, tokens named `foreign` are issued for
+    /// another audience and tokens named `client` have only a `client_id`.
+    fn serve() -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/introspect", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = HttpServer::new(move || {
+            let requests = counter.clone();
+            App::new().default_service(web::to(move |form: web::Form<HashMap<String, String>>| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let token = form["token"].clone();
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let exp = if token == "short" { now + 1 } else { now + 300 };
+                let mut response = json!({
+                    "active": token != "inactive",
+                    "sub": Uuid::from_u128(1),
+                    "username": token,
+                    "exp": exp,
+                    "iss": ISSUER,
+                    "client_id": "fe",
+                });
+                match token.as_str() {
+                    "foreign" => response["aud"] = json!(["other"]),
+                    "client" => {}
+                    _ => response["aud"] = json!(["alice", "account"]),
+                }
+                async move { HttpResponse::Ok().json(response) }
+            }))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap();
+        actix_web::rt::spawn(server.run());
+        (url, requests)
+    }
+
+    fn authenticator(endpoint: &str, cache_capacity: usize) -> IntrospectionAuthenticator {
+        let config = serde_json::from_value(json!({
+            "endpoint": endpoint,
+            "client_id": "alice",
+            "client_secret": "secret",
+            "cache_capacity": cache_capacity,
+        }))
+        .unwrap();
+        IntrospectionAuthenticator::new(config, Arc::new(ReqwestClient::new()))
+    }
+
+    async fn authenticate_with(
+        authenticator: &IntrospectionAuthenticator,
+        token: &str,
+        config: &JwtValidationConfig,
+    ) -> Result<Option<Identity>, AliceError> {
+        let req = TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_srv_request();
+        authenticator.authenticate(&req, config).await
+    }
+
+    async fn authenticate(
+        authenticator: &IntrospectionAuthenticator,
+        token: &str,
+    ) -> Result<Option<Identity>, AliceError> {
+        authenticate_with(authenticator, token, &JwtValidationConfig::default()).await
+    }
+
+    #[actix_web::test]
+    async fn active_tokens_are_cached() {
+        let (url, requests) = serve();
+        let authenticator = authenticator(&url, 10);
+        for _ in 0..2 {
+            let identity = authenticate(&authenticator, "opaque").await.unwrap().unwrap();
+            assert_eq!(identity.payload.sub, Uuid::from_u128(1));
+            assert_eq!(identity.payload.preferred_username, "opaque");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // JWTs are left to the middleware, opaque tokens are introspected even with dots.
+        let jwt = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &json!({}),
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(authenticate(&authenticator, &jwt).await.unwrap().is_none());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        authenticate(&authenticator, "a.b.c").await.unwrap().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn audiences_and_issuers_are_validated() {
+        let (url, _) = serve();
+        let authenticator = authenticator(&url, 10);
+        let config: JwtValidationConfig = serde_json::from_value(json!({
+            "aud": ["alice"],
+            "iss": [ISSUER],
+        }))
+        .unwrap();
+        authenticate_with(&authenticator, "opaque", &config).await.unwrap().unwrap();
+
+        let e = authenticate_with(&authenticator, "foreign", &config).await.err().unwrap();
+        assert!(
+            e.to_string().contains("Audience [\"other\"] isn't allowed."),
+            "{e}"
+        );
+
+        // Without `aud` the token is meant for its client.
+        let e = authenticate_with(&authenticator, "client", &config).await.err().unwrap();
+        assert!(
+            e.to_string().contains("Audience [\"fe\"] isn't allowed."),
+            "{e}"
+        );
+        let fe = JwtValidationConfig {
+            aud: Some(HashSet::from([String::from("fe")])),
+            ..config.clone()
+        };
+        authenticate_with(&authenticator, "client", &fe).await.unwrap().unwrap();
+
+        let other_issuer = JwtValidationConfig {
+            iss: Some(HashSet::from([String::from("https://other.example")])),
+            ..config
+        };
+        let e = authenticate_with(&authenticator, "opaque", &other_issuer).await.err().unwrap();
+        assert!(
+            e.to_string().contains(&format!("Issuer {ISSUER} isn't allowed.")),
+            "{e}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn cached_results_expire_with_the_token() {
+        let (url, requests) = serve();
+        let authenticator = authenticator(&url, 10);
+        authenticate(&authenticator, "short").await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        authenticate(&authenticator, "short").await.unwrap().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn inactive_tokens_are_unauthenticated() {
+        let (url, requests) = serve();
+        let authenticator = authenticator(&url, 10);
+        for _ in 0..2 {
+            let e = authenticate(&authenticator, "inactive").await.err().unwrap();
+            assert!(e.to_string().contains("Token isn't active."), "{e}");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn cache_evicts_results_expiring_first() {
+        let (url, requests) = serve();
+        let authenticator = authenticator(&url, 2);
+        // The inactive result expires after `negative_cache_ttl`, before the active ones.
+        authenticate(&authenticator, "inactive").await.err().unwrap();
+        authenticate(&authenticator, "first").await.unwrap().unwrap();
+        authenticate(&authenticator, "second").await.unwrap().unwrap();
+        assert_eq!(authenticator.cache.lock().unwrap().entries.len(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        authenticate(&authenticator, "first").await.unwrap().unwrap();
+        authenticate(&authenticator, "second").await.unwrap().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        authenticate(&authenticator, "inactive").await.err().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert_eq!(authenticator.cache.lock().unwrap().expirations.len(), 2);
+    }
+}
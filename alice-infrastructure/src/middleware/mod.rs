@@ -1,2 +1,6 @@
+pub mod authenticator;
 pub mod authorization;
+pub mod claims;
 pub mod error_msg_i18n;
+pub mod introspection;
+pub mod principal_resolver;
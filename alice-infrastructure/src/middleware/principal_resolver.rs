@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_http::header::HeaderMap;
+use alice_architecture::jwt_payload::Payload;
+use uuid::Uuid;
+
+use super::authorization::{TaskInfo, UserInfo};
+
+/// 设备令牌代表的用户与任务
+#[derive(Debug, Clone, Default)]
+pub struct ResolvedPrincipal {
+    pub user_info: Option<UserInfo>,
+    pub task_info: Option<TaskInfo>,
+}
+
+/// 为设备令牌解析其代表的用户与任务，由 `JwtValidationMiddleware` 在设备令牌通过验证后调用
+#[async_trait::async_trait]
+pub trait PrincipalResolver: Send + Sync {
+    async fn resolve(
+        &self,
+        headers: &HeaderMap,
+        payload: &Payload,
+    ) -> anyhow::Result<ResolvedPrincipal>;
+}
+
+/// 请求头中任务 id 的名称
+pub const TASK_ID_HEADER: &str = "TaskId";
+
+fn task_id(headers: &HeaderMap) -> anyhow::Result<Option<Uuid>> {
+    match headers.get(TASK_ID_HEADER) {
+        Some(task_id) => Ok(Some(task_id.to_str()?.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// 内存中的任务与用户对应关系，请求头 `TaskId` 中的任务不存在时返回错误
+#[derive(Default)]
+pub struct MemoryPrincipalResolver {
+    tasks: Mutex<HashMap<Uuid, Uuid>>,
+}
+
+impl MemoryPrincipalResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录任务所属的用户
+    pub fn insert(&self, task_id: Uuid, user_id: Uuid) {
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.insert(task_id, user_id);
+    }
+}
+
+#[async_trait::async_trait]
+impl PrincipalResolver for MemoryPrincipalResolver {
+    async fn resolve(
+        &self,
+        headers: &HeaderMap,
+        _payload: &Payload,
+    ) -> anyhow::Result<ResolvedPrincipal> {
+        let Some(task_id) = task_id(headers)? else {
+            return Ok(ResolvedPrincipal::default());
+        };
+        let tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        let user_id =
+            *tasks.get(&task_id).ok_or_else(|| anyhow::anyhow!("No such task: {task_id}."))?;
+        Ok(ResolvedPrincipal {
+            user_info: Some(UserInfo::new(user_id)),
+            task_info: Some(TaskInfo::new(task_id)),
+        })
+    }
+}
+
+#[cfg(feature = "task-principal-resolver")]
+pub use self::task_resolver::*;
+
+#[cfg(feature = "task-principal-resolver")]
+mod task_resolver {
+    use std::sync::Arc;
+
+    use actix_http::header::HeaderMap;
+    use alice_architecture::jwt_payload::Payload;
+    use anyhow::Context;
+    use sea_orm::EntityTrait;
+    use uuid::Uuid;
+
+    use super::{task_id, PrincipalResolver, ResolvedPrincipal};
+    use crate::data::Database;
+    use crate::middleware::authorization::{TaskInfo, UserInfo};
+
+    /// 通过任务、节点实例与流程实例表查找请求头 `TaskId` 中的任务所属的用户
+    pub struct TaskPrincipalResolver {
+        database: Arc<Database>,
+    }
+
+    impl TaskPrincipalResolver {
+        pub fn new(database: Arc<Database>) -> Self {
+            Self { database }
+        }
+
+        async fn user_id(&self, task_id: Uuid) -> anyhow::Result<Uuid> {
+            let con = self.database.get_connection();
+            let node_id = database_model::prelude::Task::find_by_id(task_id)
+                .one(con)
+                .await?
+                .with_context(|| format!("No such task: {task_id} in jwt validation.",))?
+                .node_instance_id;
+            let flow_id = database_model::prelude::NodeInstance::find_by_id(node_id)
+                .one(con)
+                .await?
+                .with_context(|| format!("No such node: {node_id} in jwt validation."))?
+                .flow_instance_id;
+            Ok(database_model::prelude::FlowInstance::find_by_id(flow_id)
+                .one(con)
+                .await?
+                .with_context(|| format!("No such flow-instance: {flow_id} in jwt validation"))?
+                .user_id)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl PrincipalResolver for TaskPrincipalResolver {
+        async fn resolve(
+            &self,
+            headers: &HeaderMap,
+            _payload: &Payload,
+        ) -> anyhow::Result<ResolvedPrincipal> {
+            let Some(task_id) = task_id(headers)? else {
+                return Ok(ResolvedPrincipal::default());
+            };
+            Ok(ResolvedPrincipal {
+                user_info: Some(UserInfo::new(self.user_id(task_id).await?)),
+                task_info: Some(TaskInfo::new(task_id)),
+            })
+        }
+    }
+}
@@ -19,6 +19,10 @@ pub struct TelemetryConfig {
     /// 文件输出设置
     #[serde(default)]
     pub file: FileConfig,
+
+    /// 指标导出设置
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 /// 控制台输出配置
@@ -75,6 +79,22 @@ pub struct RemoteConfig {
     pub collector_endpoint: String,
 }
 
+/// 指标导出配置
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    /// 启用指标导出
+    #[serde(default)]
+    pub enable: bool,
+
+    /// 远程收集器地址
+    #[serde(default)]
+    pub collector_endpoint: String,
+
+    /// 导出间隔（秒）
+    #[serde(default = "MetricsConfig::default_export_interval")]
+    pub export_interval: u64,
+}
+
 #[derive(Debug, Default, Clone, Copy, Deserialize)]
 #[serde(from = "String")]
 pub enum LogRotation {
@@ -130,6 +150,23 @@ impl Default for TelemetryConfig {
             console: Default::default(),
             remote: Default::default(),
             file: Default::default(),
+            metrics: Default::default(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    fn default_export_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enable: Default::default(),
+            collector_endpoint: Default::default(),
+            export_interval: Self::default_export_interval(),
         }
     }
 }
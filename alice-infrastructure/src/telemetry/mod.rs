@@ -1,5 +1,7 @@
 pub mod config;
 
+use std::time::Duration;
+
 use opentelemetry::trace::TraceResult;
 use opentelemetry_otlp::WithExportConfig;
 use tracing_appender::rolling::RollingFileAppender;
@@ -76,6 +78,20 @@ pub fn init_telemetry(config: &TelemetryConfig) -> anyhow::Result<()> {
         })
         .transpose()?;
 
+    let metrics = &config.metrics;
+    if metrics.enable {
+        let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+        if !metrics.collector_endpoint.is_empty() {
+            exporter = exporter.with_endpoint(&metrics.collector_endpoint);
+        }
+        // The built meter provider is installed as the global one.
+        opentelemetry_otlp::new_pipeline()
+            .metrics(opentelemetry_sdk::runtime::Tokio)
+            .with_exporter(exporter)
+            .with_period(Duration::from_secs(metrics.export_interval))
+            .build()?;
+    }
+
     Registry::default().with(console).with(file).with(remote).try_init()?;
     Ok(())
 }
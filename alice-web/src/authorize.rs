@@ -1,35 +1,164 @@
-use syn::{parse2, ItemFn};
+use alice_architecture::role_expression::RoleExpression;
+use syn::{
+    parse::Parser, parse2, punctuated::Punctuated, Expr, ExprLit, FnArg, ItemFn, Lit, LitStr,
+    MetaNameValue, ReturnType, Token, Type,
+};
 
+/// `#[authorize(roles = "fe:user || device:hpc", scopes = "file:read file:write")]`
+///
+/// Checks the roles and scopes granted by `JwtValidationMiddleware` before running the handler.
+/// The handler has to return a `Result` whose error converts from `AliceError`.
 pub fn internal_authorize(
-    _attr: proc_macro2::TokenStream,
+    attr: proc_macro2::TokenStream,
     body: proc_macro2::TokenStream,
 ) -> proc_macro2::TokenStream {
-    let body: ItemFn = match parse2(body) {
+    let (roles, scopes) = match parse_requirement(attr) {
         Ok(x) => x,
         Err(e) => return e.into_compile_error(),
     };
+    let mut body: ItemFn = match parse2(body) {
+        Ok(x) => x,
+        Err(e) => return e.into_compile_error(),
+    };
+    if let Err(e) = check_returns_result(&body) {
+        return e.into_compile_error();
+    }
+    let request_input: FnArg = match parse2(quote::quote! {
+        __alice_authorize_request: actix_web::HttpRequest
+    }) {
+        Ok(x) => x,
+        Err(e) => return e.into_compile_error(),
+    };
+    body.sig.inputs.push(request_input);
+    let roles = match roles {
+        Some(roles) => quote::quote! { Some(#roles) },
+        None => quote::quote! { None },
+    };
+    let scopes = scopes.unwrap_or_else(|| LitStr::new("", proc_macro2::Span::call_site()));
     let (visibility, attrs, block, sig) = (&body.vis, &body.attrs, &body.block.stmts, &body.sig);
     quote::quote! {
         #(#attrs)*
         #visibility #sig {
-            let user_info: Option<alice_architecture::authorization::UserInfo> = {
-                let extensions = actix_web::HttpMessage::extensions(&raw_req);
-                let user_info_ext: Option<&anyhow::Result<Option<alice_architecture::authorization::UserInfo>>> = extensions.get();
-                let user_info_ext = match user_info_ext {
-                    Some(u) => u,
-                    None => {
-                        return actix_web::web::Json(ResponseBase::err(400, "Unauthorized."));
-                    },
-                };
-                match user_info_ext {
-                    Ok(u) => u.to_owned(),
-                    Err(e) => {
-                        tracing::error!("{e}");
-                        return actix_web::web::Json(ResponseBase::err(400, &e.to_string()));
-                    }
+            {
+                static REQUIREMENT: std::sync::OnceLock<
+                    alice_infrastructure::middleware::authorization::AccessRequirement,
+                > = std::sync::OnceLock::new();
+                let requirement = REQUIREMENT.get_or_init(|| {
+                    alice_infrastructure::middleware::authorization::AccessRequirement::new(
+                        #roles, #scopes,
+                    )
+                });
+                if let Err(e) = requirement.check(&__alice_authorize_request) {
+                    return Err(e.into());
                 }
-            };
+            }
             #(#block)*
         }
     }
 }
+
+/// Rejected requests return early with `Err(e.into())`, which only compiles for results.
+fn check_returns_result(body: &ItemFn) -> syn::Result<()> {
+    let returns_result = match &body.sig.output {
+        ReturnType::Type(_, ty) => match ty.as_ref() {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident.to_string().ends_with("Result")),
+            _ => false,
+        },
+        ReturnType::Default => false,
+    };
+    if returns_result {
+        return Ok(());
+    }
+    let message =
+        "#[authorize] handlers must return a `Result` whose error converts from `AliceError`";
+    Err(match &body.sig.output {
+        ReturnType::Type(_, ty) => syn::Error::new_spanned(ty, message),
+        ReturnType::Default => syn::Error::new_spanned(&body.sig.ident, message),
+    })
+}
+
+fn parse_requirement(
+    attr: proc_macro2::TokenStream,
+) -> syn::Result<(Option<LitStr>, Option<LitStr>)> {
+    let args = Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse2(attr)?;
+    let (mut roles, mut scopes) = (None, None);
+    for arg in args {
+        let value = match &arg.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(value),
+                ..
+            }) => value.clone(),
+            value => return Err(syn::Error::new_spanned(value, "expected a string literal")),
+        };
+        if arg.path.is_ident("roles") {
+            // Typos fail the build instead of every request.
+            if let Err(e) = value.value().parse::<RoleExpression>() {
+                return Err(syn::Error::new(value.span(), e));
+            }
+            roles = Some(value);
+        } else if arg.path.is_ident("scopes") {
+            scopes = Some(value);
+        } else {
+            return Err(syn::Error::new_spanned(
+                &arg.path,
+                "expected `roles` or `scopes`",
+            ));
+        }
+    }
+    Ok((roles, scopes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(attr: proc_macro2::TokenStream) -> String {
+        internal_authorize(
+            attr,
+            quote::quote! {
+                async fn handle() -> AliceResult<()> {
+                    Ok(())
+                }
+            },
+        )
+        .to_string()
+    }
+
+    #[test]
+    fn valid_roles_expand_to_a_requirement() {
+        let expanded = expand(quote::quote! { roles = "fe:user || device:hpc", scopes = "a b" });
+        assert!(expanded.contains("AccessRequirement :: new"));
+        assert!(!expanded.contains("compile_error"));
+    }
+
+    #[test]
+    fn malformed_roles_fail_to_compile() {
+        let expanded = expand(quote::quote! { roles = "fe:user &&" });
+        assert!(expanded.contains("compile_error"));
+        assert!(expanded.contains("Invalid role expression"));
+        assert!(!expanded.contains("AccessRequirement"));
+    }
+
+    #[test]
+    fn handlers_without_results_fail_to_compile() {
+        for body in [
+            quote::quote! { async fn handle() {} },
+            quote::quote! { async fn handle() -> impl Responder { "" } },
+            quote::quote! { async fn handle() -> HttpResponse { HttpResponse::Ok().finish() } },
+        ] {
+            let expanded = internal_authorize(quote::quote! { scopes = "a" }, body).to_string();
+            assert!(expanded.contains("must return a `Result`"));
+            assert!(!expanded.contains("AccessRequirement"));
+        }
+        let expanded = internal_authorize(
+            quote::quote! { scopes = "a" },
+            quote::quote! { async fn handle() -> actix_web::Result<HttpResponse> { todo!() } },
+        )
+        .to_string();
+        assert!(!expanded.contains("compile_error"));
+    }
+}
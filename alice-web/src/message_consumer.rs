@@ -4,10 +4,26 @@ use syn::{
     ReturnType, Type,
 };
 
+/// The generated function is a `MessageHandler`, registered with
+/// `add_handler(topic, Arc::new(handle))`. Like `typed_handler`, the `#[serialize]` inputs are
+/// decoded from the raw payload with the codec named by the content-type header.
 pub fn internal_message_consumer(
-    _attr: proc_macro2::TokenStream,
+    attr: proc_macro2::TokenStream,
     body: proc_macro2::TokenStream,
 ) -> proc_macro2::TokenStream {
+    // `#[message_consumer(idempotent)]` skips the messages already handled, see
+    // `alice_infrastructure::message_queue::deduplicate`.
+    let idempotent = if attr.is_empty() {
+        false
+    } else {
+        match parse2::<Ident>(attr) {
+            Ok(x) if x == "idempotent" => true,
+            Ok(x) => {
+                return syn::Error::new(x.span(), "expected `idempotent`").into_compile_error()
+            }
+            Err(e) => return e.into_compile_error(),
+        }
+    };
     let body: ItemFn = match parse2(body) {
         Ok(x) => x,
         Err(e) => return e.into_compile_error(),
@@ -19,7 +35,9 @@ pub fn internal_message_consumer(
     let mut new_inputs = Punctuated::<FnArg, Comma>::new();
     let mut opt_inputs = Punctuated::<FnArg, Comma>::new();
     let mut serializable_inputs = Punctuated::<FnArg, Comma>::new();
-    let content_input: FnArg = match parse2(quote::quote! {content: &'async_fn str}) {
+    let content_input: FnArg = match parse2(
+        quote::quote! {__alice_message: &'async_fn alice_infrastructure::message_queue::Message},
+    ) {
         Ok(x) => x,
         Err(e) => return e.into_compile_error(),
     };
@@ -44,7 +62,7 @@ pub fn internal_message_consumer(
                             let mut x = x.clone();
                             let mut ty = ty.clone();
                             ty.lifetime = Some(lifetime.clone());
-                            x.ty = Box::new(Type::Reference(ty));
+                            *x.ty = Type::Reference(ty);
                             new_inputs.push(FnArg::Typed(x.clone()));
                         }
                         _ => {
@@ -62,15 +80,36 @@ pub fn internal_message_consumer(
     }
     sig.inputs = new_inputs;
     old_sig.inputs = opt_inputs;
-    let header = if serializable_inputs.len() == 1 {
-        let input = serializable_inputs.first();
+    // Decode failures are returned as `DecodeError`s like those of `typed_handler`, so that one
+    // malformed message neither panics the whole consumer nor gets retried.
+    let header = if serializable_inputs.is_empty() {
         quote::quote! {
-            let #input = serde_json::from_str(content).unwrap();
+            let _ = __alice_message;
         }
     } else {
-        let serializable_inputs = serializable_inputs.iter();
+        let (pats, tys): (Vec<_>, Vec<_>) = serializable_inputs
+            .iter()
+            .filter_map(|x| match x {
+                FnArg::Typed(x) => Some((&x.pat, &x.ty)),
+                _ => None,
+            })
+            .unzip();
+        let (pats, tys) = if pats.len() == 1 {
+            (quote::quote! { #(#pats)* }, quote::quote! { #(#tys)* })
+        } else {
+            (
+                quote::quote! { (#(#pats,)*) },
+                quote::quote! { (#(#tys,)*) },
+            )
+        };
         quote::quote! {
-            let (#(#serializable_inputs,)*) = serde_json::from_str(content).unwrap();
+            let #pats: #tys = match alice_infrastructure::message_queue::decode_message(__alice_message) {
+                Ok(x) => x,
+                Err(e) => {
+                    let e = alice_infrastructure::message_queue::DecodeError::new(&__alice_message.topic, e);
+                    return Box::pin(async move { Err(anyhow::Error::from(e)) });
+                }
+            };
         }
     };
     sig.asyncness = None;
@@ -101,13 +140,20 @@ pub fn internal_message_consumer(
             _ => None,
         })
         .collect::<Vec<Ident>>();
+    let call = if idempotent {
+        quote::quote! {
+            alice_infrastructure::message_queue::deduplicate(#ident(#(#param_idents,)*))
+        }
+    } else {
+        quote::quote! { #ident(#(#param_idents,)*) }
+    };
     quote::quote! {
         #(#attrs)*
         #visibility #sig {
             #header
             #old_sig
             #block
-            Box::pin(#ident(#(#param_idents,)*))
+            Box::pin(#call)
         }
     }
 }
@@ -0,0 +1,81 @@
+use std::fmt;
+
+use actix_web::dev::Service;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpMessage, HttpResponse, ResponseError};
+use alice_infrastructure::error::AliceError;
+use alice_infrastructure::middleware::authorization::GrantedAccess;
+use alice_web::authorize;
+
+/// Responds with the status of the error, like the error handlers of the services.
+#[derive(Debug)]
+struct StatusError(AliceError);
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<AliceError> for StatusError {
+    fn from(e: AliceError) -> Self {
+        Self(e)
+    }
+}
+
+impl ResponseError for StatusError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.0 .0.status()).unwrap()
+    }
+}
+
+#[authorize(roles = "fe:user || device:hpc", scopes = "file:read")]
+async fn download() -> Result<HttpResponse, StatusError> {
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Calls the handler as if the token granted `roles` and `scopes`, `None` for no token.
+async fn call(granted: Option<(&[&str], &[&str])>) -> StatusCode {
+    let granted = granted.map(|(roles, scopes)| GrantedAccess {
+        roles: roles.iter().map(ToString::to_string).collect(),
+        scopes: scopes.iter().map(ToString::to_string).collect(),
+    });
+    let app = test::init_service(
+        App::new()
+            .wrap_fn(move |req, srv| {
+                if let Some(granted) = granted.clone() {
+                    req.extensions_mut().insert(granted);
+                }
+                srv.call(req)
+            })
+            .route("/download", web::get().to(download)),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/download").to_request();
+    test::call_service(&app, req).await.status()
+}
+
+#[actix_web::test]
+async fn granted_roles_and_scopes_are_allowed() {
+    assert_eq!(
+        call(Some((&["fe:user"], &["file:read"]))).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        call(Some((&["device:hpc"], &["file:read", "file:write"]))).await,
+        StatusCode::OK
+    );
+}
+
+#[actix_web::test]
+async fn missing_roles_or_scopes_are_forbidden() {
+    assert_eq!(
+        call(Some((&["fe:admin"], &["file:read"]))).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        call(Some((&["fe:user"], &["file:write"]))).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(call(None).await, StatusCode::UNAUTHORIZED);
+}
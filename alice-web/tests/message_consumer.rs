@@ -0,0 +1,58 @@
+use std::sync::{Arc, Mutex};
+
+use alice_infrastructure::message_queue::{DecodeError, Message, MessageCodec};
+use alice_web::message_consumer;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Order {
+    id: u32,
+    item: String,
+}
+
+#[derive(Default)]
+struct Received(Mutex<Vec<(u32, String)>>);
+
+#[message_consumer]
+async fn handle(#[serialize] order: Order, sp: Arc<Received>) -> anyhow::Result<()> {
+    sp.0.lock().unwrap().push((order.id, order.item));
+    Ok(())
+}
+
+fn message(codec: MessageCodec, payload: Vec<u8>) -> Message {
+    Message {
+        topic: "orders".to_string(),
+        payload,
+        headers: codec.headers(),
+    }
+}
+
+#[tokio::test]
+async fn handlers_decode_with_the_message_codec() {
+    let codec = MessageCodec::MessagePack;
+    let order = Order {
+        id: 7,
+        item: "book".to_string(),
+    };
+    let received = Arc::new(Received::default());
+    handle(
+        &message(codec, codec.encode(&order).unwrap()),
+        received.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(*received.0.lock().unwrap(), [(7, "book".to_string())]);
+}
+
+#[tokio::test]
+async fn decode_failures_are_decode_errors() {
+    let received = Arc::new(Received::default());
+    let e = handle(
+        &message(MessageCodec::MessagePack, b"{}".to_vec()),
+        received.clone(),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(e.downcast_ref::<DecodeError>().unwrap().topic, "orders");
+    assert!(received.0.lock().unwrap().is_empty());
+}